@@ -0,0 +1,153 @@
+use anyhow::Context;
+use anyhow::Error;
+use anyhow::ensure;
+
+use futures::future::join_all;
+
+use sftp_protocol::Error as ProtocolError;
+use sftp_server::lock::LockKey;
+
+use super::scenario::Scenario;
+use super::scenario::Scratch;
+use super::scenario::add;
+use super::scenario::options;
+use super::scenario::pattern;
+use super::scenario::read_all;
+
+const BLOCK: usize = 4096;
+const PARALLEL_BLOCKS: &[usize] = &[2, 8, 32];
+const PARALLEL_CREATES: &[usize] = &[10, 50];
+const RACERS: usize = 8;
+
+pub(crate) fn scenarios(all: &mut Vec<Scenario>) /* {{{ */ {
+	for &blocks in PARALLEL_BLOCKS {
+		add(all, format!("concurrency/parallel-writes/{}-blocks", blocks), move |s| parallel_writes(s, blocks));
+		add(all, format!("concurrency/parallel-reads/{}-blocks", blocks), move |s| parallel_reads(s, blocks));
+	}
+	for &count in PARALLEL_CREATES {
+		add(all, format!("concurrency/parallel-creates/{}-files", count), move |s| parallel_creates(s, count));
+	}
+	add(all, "concurrency/two-handles-one-file", two_handles);
+	add(all, "concurrency/repeated-open-close", repeated_open_close);
+	add(all, "concurrency/racing-mkdir", racing_mkdir);
+	add(all, "concurrency/racing-create-new", racing_create_new);
+	add(all, "concurrency/lock-keys", lock_keys);
+} // }}}
+
+// Writes every block of a file at once, through one handle, the way pipelining clients upload
+async fn parallel_writes(s: Scratch, blocks: usize) -> Result<(), Error> /* {{{ */ {
+	let data = pattern(blocks * BLOCK);
+	let file = s.open("f", options("wct")).await.context("open failed")?;
+	let writes = data.chunks(BLOCK).enumerate().map(|(i, chunk)| file.write_at((i * BLOCK) as u64, chunk));
+	for (i, result) in join_all(writes).await.into_iter().enumerate() {
+		result.with_context(|| format!("write of block {} failed", i))?;
+	}
+	file.close().await.context("close failed")?;
+	ensure!(s.contents("f").await? == data, "file differs from what was written");
+	Ok(())
+} // }}}
+
+async fn parallel_reads(s: Scratch, blocks: usize) -> Result<(), Error> /* {{{ */ {
+	let data = pattern(blocks * BLOCK);
+	s.create("f", &data).await?;
+	let file = s.open("f", options("r")).await.context("open failed")?;
+	let reads = (0..blocks).map(|i| file.read_at((i * BLOCK) as u64, BLOCK as u32));
+	let results = join_all(reads).await;
+	file.close().await.context("close failed")?;
+	for (i, result) in results.into_iter().enumerate() {
+		let block = result.with_context(|| format!("read of block {} failed", i))?;
+		let expected = &data[i * BLOCK..(i + 1) * BLOCK];
+		// Short reads are allowed here too, but they must still be the right bytes
+		ensure!(!block.is_empty() && block[..] == expected[..block.len()], "read the wrong bytes for block {}", i);
+	}
+	Ok(())
+} // }}}
+
+async fn parallel_creates(s: Scratch, count: usize) -> Result<(), Error> /* {{{ */ {
+	let names: Vec<String> = (0..count).map(|i| format!("file{:03}", i)).collect();
+	let creates = names.iter().map(|name| s.create(name, name.as_bytes()));
+	for result in join_all(creates).await {
+		result?;
+	}
+	ensure!(s.names("").await? == names, "directory doesn't list exactly the files created");
+	for name in &names {
+		ensure!(s.contents(name).await? == name.as_bytes(), "{} has the wrong contents", name);
+	}
+	Ok(())
+} // }}}
+
+async fn two_handles(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"").await?;
+	let writer = s.open("f", options("w")).await.context("opening the writer failed")?;
+	let reader = s.open("f", options("r")).await.context("opening the reader failed")?;
+	writer.write_at(0, b"shared").await.context("write_at failed")?;
+	writer.flush().await.context("flush failed")?;
+	let data = read_all(&reader).await?;
+	ensure!(data == b"shared", "second handle reads {:?} after the first wrote", String::from_utf8_lossy(&data));
+	writer.close().await.context("closing the writer failed")?;
+	reader.close().await.context("closing the reader failed")?;
+	Ok(())
+} // }}}
+
+async fn repeated_open_close(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"stable").await?;
+	let s = &s;
+	for round in 0..5 {
+		let opens = (0..RACERS).map(|_| async move {
+			let file = s.open("f", options("r")).await?;
+			let data = file.read_at(0, 6).await?;
+			file.close().await?;
+			Ok::<_, ProtocolError>(data)
+		});
+		for result in join_all(opens).await {
+			let data = result.with_context(|| format!("round {}:  open, read or close failed", round))?;
+			ensure!(data == b"stable", "round {}:  read {:?}", round, String::from_utf8_lossy(&data));
+		}
+	}
+	Ok(())
+} // }}}
+
+async fn racing_mkdir(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let path = s.path("d");
+	let attempts = (0..RACERS).map(|_| s.backend.mkdir(&path, Default::default()));
+	let succeeded = join_all(attempts).await.into_iter().filter(|r| r.is_ok()).count();
+	ensure!(succeeded == 1, "{} of {} concurrent mkdirs of one name succeeded; expected exactly 1", succeeded, RACERS);
+	Ok(())
+} // }}}
+
+async fn racing_create_new(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let scratch = &s;
+	let attempts = (0..RACERS).map(|i| async move {
+		let file = scratch.open("f", options("wcx")).await?;
+		file.write_at(0, &[i as u8]).await?;
+		file.close().await
+	});
+	let results = join_all(attempts).await;
+	let succeeded = results.iter().filter(|r| r.is_ok()).count();
+	ensure!(succeeded == 1, "{} of {} concurrent exclusive creates of one name succeeded; expected exactly 1", succeeded, RACERS);
+	ensure!(s.contents("f").await?.len() == 1, "the winning create's write was lost");
+	Ok(())
+} // }}}
+
+// The server's locks only keep clients apart as well as the keys they're taken on tell files apart
+async fn lock_keys(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("a", b"x").await?;
+	s.create("b", b"x").await?;
+	let a = s.backend.lock_key(&s.path("a")).await.context("lock_key failed")?;
+	let b = s.backend.lock_key(&s.path("b")).await.context("lock_key failed")?;
+	ensure!(a != b, "two files share the lock key {:?}", a);
+	let again = s.backend.lock_key(&s.path("./a")).await.context("lock_key failed")?;
+	ensure!(again == a, "one file has lock keys {:?} and {:?}", a, again);
+	// Backends that identify files by more than their path should see through hard links
+	if let LockKey::File{..} = a {
+		match s.backend.hardlink(&s.path("a"), &s.path("c")).await {
+			Ok(_) => {
+				let c = s.backend.lock_key(&s.path("c")).await.context("lock_key failed")?;
+				ensure!(c == a, "a hard link has lock key {:?}, but the original has {:?}", c, a);
+			},
+			Err(ProtocolError::Unsupported) => (),
+			Err(e) => return Err(Error::new(e).context("hardlink failed"))
+		};
+	}
+	Ok(())
+} // }}}
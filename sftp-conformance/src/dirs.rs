@@ -0,0 +1,331 @@
+use anyhow::Context;
+use anyhow::Error;
+use anyhow::ensure;
+
+use sftp_protocol::Error as ProtocolError;
+use sftp_protocol::stream::packet::status::StatusType;
+
+use super::scenario::Scenario;
+use super::scenario::Scratch;
+use super::scenario::add;
+use super::scenario::expect_err;
+use super::scenario::expect_status;
+use super::scenario::options;
+
+// Names that backends mapping paths onto something else (object keys, URLs, ...) tend to mangle
+const AWKWARD_NAMES: &[&str] = &["with space", "ünïcödé", "semi;colon", "#hash", "trailing-dash-", "dots.in.name", "..leading-dots", "percent%20", "plus+sign", "quote'mark"];
+
+const LIST_COUNTS: &[usize] = &[1, 2, 10, 150];
+
+pub(crate) fn scenarios(all: &mut Vec<Scenario>) /* {{{ */ {
+	add(all, "dirs/mkdir", mkdir);
+	add(all, "dirs/mkdir-existing", mkdir_existing);
+	add(all, "dirs/mkdir-over-file", mkdir_over_file);
+	add(all, "dirs/mkdir-missing-parent", mkdir_missing_parent);
+	add(all, "dirs/mkdir-nested", mkdir_nested);
+	add(all, "dirs/list-empty", list_empty);
+	for &count in LIST_COUNTS {
+		add(all, format!("dirs/list-{}-files", count), move |s| list_files(s, count));
+	}
+	add(all, "dirs/list-includes-dotfiles", list_dotfiles);
+	add(all, "dirs/list-excludes-dot-entries", list_no_dot_entries);
+	add(all, "dirs/list-entry-metadata", list_metadata);
+	add(all, "dirs/list-missing", list_missing);
+	add(all, "dirs/list-file", list_file);
+	add(all, "dirs/rmdir", rmdir);
+	add(all, "dirs/rmdir-not-empty", rmdir_not_empty);
+	add(all, "dirs/rmdir-missing", rmdir_missing);
+	add(all, "dirs/rmdir-file", rmdir_file);
+	add(all, "dirs/delete-file", delete_file);
+	add(all, "dirs/delete-missing", delete_missing);
+	add(all, "dirs/delete-directory", delete_directory);
+	add(all, "dirs/rename-file", rename_file);
+	add(all, "dirs/rename-directory", rename_directory);
+	add(all, "dirs/rename-into-subdirectory", rename_into_subdirectory);
+	add(all, "dirs/rename-over-existing-file", rename_over_existing);
+	add(all, "dirs/rename-missing", rename_missing);
+	add(all, "dirs/rename-to-self", rename_to_self);
+	add(all, "dirs/posix-rename", posix_rename);
+	add(all, "dirs/posix-rename-over-existing-file", posix_rename_over_existing);
+	add(all, "dirs/hardlink", hardlink);
+	add(all, "dirs/hardlink-over-existing-file", hardlink_over_existing);
+	add(all, "dirs/realpath", realpath);
+	add(all, "dirs/realpath-missing", realpath_missing);
+	for &name in AWKWARD_NAMES {
+		add(all, format!("dirs/name/{}", name), move |s| awkward_name(s, name));
+	}
+} // }}}
+
+async fn mkdir(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("d").await?;
+	let metadata = s.backend.metadata(&s.path("d")).await.context("metadata failed")?;
+	ensure!(metadata.is_dir && !metadata.is_file, "new directory isn't reported as one");
+	Ok(())
+} // }}}
+
+async fn mkdir_existing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("d").await?;
+	s.create("d/f", b"x").await?;
+	expect_err(s.backend.mkdir(&s.path("d"), Default::default()).await, "creating a directory that exists")?;
+	ensure!(s.exists("d/f").await, "failed mkdir disturbed the existing directory");
+	Ok(())
+} // }}}
+
+async fn mkdir_over_file(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"x").await?;
+	expect_err(s.backend.mkdir(&s.path("f"), Default::default()).await, "creating a directory over a file")?;
+	ensure!(s.contents("f").await? == b"x", "failed mkdir disturbed the file");
+	Ok(())
+} // }}}
+
+async fn mkdir_missing_parent(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	expect_status(s.backend.mkdir(&s.path("missing/d"), Default::default()).await, StatusType::NoSuchFile, "creating a directory in a missing one")?;
+	ensure!(!s.exists("missing").await, "mkdir created missing parents");
+	Ok(())
+} // }}}
+
+async fn mkdir_nested(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let mut path = String::new();
+	for depth in 0..5 {
+		if(depth > 0) {
+			path.push('/');
+		}
+		path.push_str(&format!("level{}", depth));
+		s.mkdir(&path).await?;
+	}
+	s.create(&format!("{}/f", path), b"deep").await?;
+	ensure!(s.contents(&format!("{}/f", path)).await? == b"deep", "file in a nested directory didn't round-trip");
+	ensure!(s.names("level0").await? == vec!["level1"], "nested directory lists the wrong entries");
+	Ok(())
+} // }}}
+
+async fn list_empty(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("d").await?;
+	let names = s.names("d").await?;
+	ensure!(names.is_empty(), "empty directory lists {:?}", names);
+	Ok(())
+} // }}}
+
+async fn list_files(s: Scratch, count: usize) -> Result<(), Error> /* {{{ */ {
+	let mut expected = Vec::with_capacity(count);
+	for i in 0..count {
+		let name = format!("file{:03}", i);
+		s.create(&name, b"").await?;
+		expected.push(name);
+	}
+	expected.sort();
+	let names = s.names("").await?;
+	ensure!(names == expected, "directory lists {} entries; expected {}", names.len(), expected.len());
+	Ok(())
+} // }}}
+
+async fn list_dotfiles(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create(".hidden", b"").await?;
+	s.mkdir(".hidden-dir").await?;
+	s.create("visible", b"").await?;
+	let names = s.names("").await?;
+	ensure!(names == vec![".hidden", ".hidden-dir", "visible"], "directory lists {:?}", names);
+	Ok(())
+} // }}}
+
+async fn list_no_dot_entries(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"").await?;
+	let names = s.names("").await?;
+	ensure!(!names.iter().any(|n| n == "." || n == ".."), "list() includes . or ..:  {:?}", names);
+	Ok(())
+} // }}}
+
+async fn list_metadata(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"twelve bytes").await?;
+	s.mkdir("d").await?;
+	let entries = s.backend.list(&s.dir).await.context("list failed")?;
+	ensure!(entries.len() == 2, "directory lists {} entries; expected 2", entries.len());
+	for entry in entries {
+		match entry.path.as_str() {
+			"f" => {
+				ensure!(entry.is_file && !entry.is_dir, "file is listed as a directory");
+				ensure!(entry.size == 12, "file is listed as {} bytes; expected 12", entry.size);
+			},
+			"d" => ensure!(entry.is_dir && !entry.is_file, "directory is listed as a file"),
+			// Entries are named relative to the directory, not with their full path
+			other => return Err(Error::msg(format!("unexpected entry {:?}", other)))
+		};
+	}
+	Ok(())
+} // }}}
+
+async fn list_missing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	expect_status(s.backend.list(&s.path("missing")).await, StatusType::NoSuchFile, "listing a missing directory")
+} // }}}
+
+async fn list_file(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"x").await?;
+	expect_err(s.backend.list(&s.path("f")).await, "listing a file")
+} // }}}
+
+async fn rmdir(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("d").await?;
+	s.backend.rmdir(&s.path("d")).await.context("rmdir failed")?;
+	ensure!(!s.exists("d").await, "directory still exists after rmdir");
+	Ok(())
+} // }}}
+
+async fn rmdir_not_empty(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("d").await?;
+	s.create("d/f", b"x").await?;
+	expect_err(s.backend.rmdir(&s.path("d")).await, "removing a directory that isn't empty")?;
+	ensure!(s.contents("d/f").await? == b"x", "failed rmdir disturbed the directory's contents");
+	Ok(())
+} // }}}
+
+async fn rmdir_missing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	expect_status(s.backend.rmdir(&s.path("missing")).await, StatusType::NoSuchFile, "removing a missing directory")
+} // }}}
+
+async fn rmdir_file(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"x").await?;
+	expect_err(s.backend.rmdir(&s.path("f")).await, "rmdir on a file")?;
+	ensure!(s.exists("f").await, "rmdir removed a file");
+	Ok(())
+} // }}}
+
+async fn delete_file(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"x").await?;
+	s.backend.delete_file(&s.path("f")).await.context("delete_file failed")?;
+	ensure!(!s.exists("f").await, "file still exists after delete_file");
+	Ok(())
+} // }}}
+
+async fn delete_missing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	expect_status(s.backend.delete_file(&s.path("missing")).await, StatusType::NoSuchFile, "deleting a missing file")
+} // }}}
+
+async fn delete_directory(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("d").await?;
+	expect_err(s.backend.delete_file(&s.path("d")).await, "delete_file on a directory")?;
+	ensure!(s.exists("d").await, "delete_file removed a directory");
+	Ok(())
+} // }}}
+
+async fn rename_file(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("a", b"content").await?;
+	s.backend.rename(&s.path("a"), &s.path("b")).await.context("rename failed")?;
+	ensure!(!s.exists("a").await, "source still exists after rename");
+	ensure!(s.contents("b").await? == b"content", "renamed file has the wrong contents");
+	Ok(())
+} // }}}
+
+async fn rename_directory(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("a").await?;
+	s.create("a/f", b"inside").await?;
+	s.backend.rename(&s.path("a"), &s.path("b")).await.context("rename failed")?;
+	ensure!(!s.exists("a").await, "source directory still exists after rename");
+	ensure!(s.contents("b/f").await? == b"inside", "renamed directory lost its contents");
+	Ok(())
+} // }}}
+
+async fn rename_into_subdirectory(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("d").await?;
+	s.create("f", b"moving").await?;
+	s.backend.rename(&s.path("f"), &s.path("d/f")).await.context("rename failed")?;
+	ensure!(s.names("").await? == vec!["d"], "file wasn't moved out of its directory");
+	ensure!(s.contents("d/f").await? == b"moving", "moved file has the wrong contents");
+	Ok(())
+} // }}}
+
+async fn rename_over_existing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("a", b"new").await?;
+	s.create("b", b"old").await?;
+	// SSH_FXP_RENAME must not clobber; clients that want that use posix-rename
+	expect_err(s.backend.rename(&s.path("a"), &s.path("b")).await, "renaming over an existing file")?;
+	ensure!(s.contents("a").await? == b"new", "failed rename changed its source");
+	ensure!(s.contents("b").await? == b"old", "rename replaced the existing file");
+	Ok(())
+} // }}}
+
+async fn rename_missing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	expect_status(s.backend.rename(&s.path("missing"), &s.path("b")).await, StatusType::NoSuchFile, "renaming a missing file")?;
+	ensure!(!s.exists("b").await, "failed rename created its target");
+	Ok(())
+} // }}}
+
+async fn rename_to_self(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"same").await?;
+	s.backend.rename(&s.path("f"), &s.path("f")).await.context("renaming a file to itself failed")?;
+	ensure!(s.contents("f").await? == b"same", "renaming a file to itself changed it");
+	Ok(())
+} // }}}
+
+async fn posix_rename(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("a", b"content").await?;
+	match s.backend.posix_rename(&s.path("a"), &s.path("b")).await {
+		Ok(_) => (),
+		// The extension is optional
+		Err(ProtocolError::Unsupported) => return Ok(()),
+		Err(e) => return Err(Error::new(e).context("posix_rename failed"))
+	};
+	ensure!(!s.exists("a").await, "source still exists after posix_rename");
+	ensure!(s.contents("b").await? == b"content", "renamed file has the wrong contents");
+	Ok(())
+} // }}}
+
+async fn posix_rename_over_existing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("a", b"new").await?;
+	s.create("b", b"old").await?;
+	match s.backend.posix_rename(&s.path("a"), &s.path("b")).await {
+		Ok(_) => (),
+		Err(ProtocolError::Unsupported) => return Ok(()),
+		Err(e) => return Err(Error::new(e).context("posix_rename failed"))
+	};
+	ensure!(!s.exists("a").await, "source still exists after posix_rename");
+	ensure!(s.contents("b").await? == b"new", "posix_rename didn't replace the existing file");
+	Ok(())
+} // }}}
+
+async fn hardlink(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("a", b"shared").await?;
+	match s.backend.hardlink(&s.path("a"), &s.path("b")).await {
+		Ok(_) => (),
+		// The extension is optional
+		Err(ProtocolError::Unsupported) => return Ok(()),
+		Err(e) => return Err(Error::new(e).context("hardlink failed"))
+	};
+	ensure!(s.contents("a").await? == b"shared", "hardlink changed its source");
+	ensure!(s.contents("b").await? == b"shared", "new link has the wrong contents");
+	// Both names are the same file, so a change through one shows through the other
+	let file = s.open("b", options("w")).await.context("opening the new link failed")?;
+	file.write_at(0, b"SHARED").await.context("write_at failed")?;
+	file.close().await.context("close failed")?;
+	ensure!(s.contents("a").await? == b"SHARED", "writing through the new link didn't change the original");
+	Ok(())
+} // }}}
+
+async fn hardlink_over_existing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("a", b"new").await?;
+	s.create("b", b"old").await?;
+	expect_err(s.backend.hardlink(&s.path("a"), &s.path("b")).await, "hardlinking over an existing file")?;
+	ensure!(s.contents("b").await? == b"old", "failed hardlink replaced the existing file");
+	Ok(())
+} // }}}
+
+async fn realpath(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("d").await?;
+	s.create("d/f", b"").await?;
+	let resolved = s.backend.realpath(&s.path("d/../d/./f")).await.context("realpath failed")?;
+	ensure!(resolved == s.path("d/f"), "realpath resolved to {:?}; expected {:?}", resolved, s.path("d/f"));
+	Ok(())
+} // }}}
+
+async fn realpath_missing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	// The server treats this as "not created yet" when clients canonicalize upload targets
+	expect_status(s.backend.realpath(&s.path("missing")).await, StatusType::NoSuchFile, "resolving a missing path")
+} // }}}
+
+async fn awkward_name(s: Scratch, name: &'static str) -> Result<(), Error> /* {{{ */ {
+	s.create(name, name.as_bytes()).await?;
+	ensure!(s.names("").await? == vec![name], "file is listed under the wrong name");
+	ensure!(s.contents(name).await? == name.as_bytes(), "file has the wrong contents");
+	s.backend.delete_file(&s.path(name)).await.context("delete_file failed")?;
+	ensure!(!s.exists(name).await, "file still exists after delete_file");
+	Ok(())
+} // }}}
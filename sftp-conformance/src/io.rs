@@ -0,0 +1,185 @@
+use anyhow::Context;
+use anyhow::Error;
+use anyhow::ensure;
+
+use sftp_protocol::Error as ProtocolError;
+
+use super::scenario::Scenario;
+use super::scenario::Scratch;
+use super::scenario::add;
+use super::scenario::options;
+use super::scenario::pattern;
+
+const WRITE_SIZES: &[usize] = &[0, 1, 100, 4095, 4096, 4097, 65536, 65537, 300_000];
+const WRITE_OFFSETS: &[u64] = &[0, 1, 4096, 1 << 20];
+
+const READ_FILE_LEN: usize = 10_000;
+const READ_OFFSETS: &[u64] = &[0, 1, 5000, 9999, 10_000, 10_001, 1 << 32];
+const READ_LENS: &[u32] = &[1, 100, 9999, 10_000, 65536];
+
+const CHUNK_SIZES: &[usize] = &[1, 7, 4096, 32768];
+
+pub(crate) fn scenarios(all: &mut Vec<Scenario>) /* {{{ */ {
+	for &size in WRITE_SIZES {
+		for &offset in WRITE_OFFSETS {
+			add(all, format!("io/write/{}-bytes-at-{}", size, offset), move |s| write_at_offset(s, size, offset));
+		}
+	}
+	for &offset in READ_OFFSETS {
+		for &len in READ_LENS {
+			add(all, format!("io/read/{}-bytes-at-{}", len, offset), move |s| read_range(s, offset, len));
+		}
+	}
+	for &chunk in CHUNK_SIZES {
+		add(all, format!("io/sequential-writes/{}-byte-chunks", chunk), move |s| chunked_writes(s, chunk, false));
+		add(all, format!("io/reverse-writes/{}-byte-chunks", chunk), move |s| chunked_writes(s, chunk, true));
+	}
+	add(all, "io/overwrite-middle", overwrite_middle);
+	add(all, "io/len-tracks-writes", len_tracks_writes);
+	add(all, "io/flush-and-sync", flush_and_sync);
+	add(all, "io/abort-keeps-written-data", abort_keeps_data);
+	add(all, "io/handle-metadata-tracks-size", handle_metadata);
+	add(all, "io/read-while-writing-same-handle", read_own_writes);
+	add(all, "io/reopen-sees-closed-writes", reopen_sees_writes);
+} // }}}
+
+async fn write_at_offset(s: Scratch, size: usize, offset: u64) -> Result<(), Error> /* {{{ */ {
+	let data = pattern(size);
+	let file = s.open("f", options("rwct")).await.context("open failed")?;
+	file.write_at(offset, &data).await.context("write_at failed")?;
+	// A zero-length write doesn't extend the file, wherever it's aimed
+	let expected = match size {
+		0 => Vec::new(),
+		_ => {
+			let mut expected = vec![0u8; offset as usize];
+			expected.extend_from_slice(&data);
+			expected
+		}
+	};
+	let len = file.len().await.context("len failed")?;
+	ensure!(len == expected.len() as u64, "len() is {} after writing; expected {}", len, expected.len());
+	file.close().await.context("close failed")?;
+	let actual = s.contents("f").await?;
+	ensure!(actual.len() == expected.len(), "file is {} bytes; expected {}", actual.len(), expected.len());
+	if let Some(i) = actual.iter().zip(expected.iter()).position(|(a, b)| a != b) {
+		return Err(Error::msg(format!("file differs from what was written at byte {}", i)));
+	}
+	Ok(())
+} // }}}
+
+async fn read_range(s: Scratch, offset: u64, len: u32) -> Result<(), Error> /* {{{ */ {
+	let content = pattern(READ_FILE_LEN);
+	s.create("f", &content).await?;
+	let file = s.open("f", options("r")).await.context("open failed")?;
+	let data = file.read_at(offset, len).await.context("read_at failed")?;
+	file.close().await.context("close failed")?;
+	let start = (offset as usize).min(content.len());
+	let end = (offset as usize).saturating_add(len as usize).min(content.len());
+	let expected = &content[start..end];
+	if(expected.is_empty()) {
+		ensure!(data.is_empty(), "read {} bytes at or past the end of the file", data.len());
+		return Ok(());
+	}
+	// Short reads are allowed, as long as they make progress
+	ensure!(!data.is_empty(), "read nothing, but {} bytes are available", expected.len());
+	ensure!(data.len() <= expected.len(), "read {} bytes; only {} were asked for and available", data.len(), expected.len());
+	ensure!(data[..] == expected[..data.len()], "read the wrong bytes");
+	Ok(())
+} // }}}
+
+async fn chunked_writes(s: Scratch, chunk: usize, reverse: bool) -> Result<(), Error> /* {{{ */ {
+	let data = pattern(100_000);
+	let file = s.open("f", options("wct")).await.context("open failed")?;
+	let mut offsets: Vec<usize> = (0..data.len()).step_by(chunk).collect();
+	if(reverse) {
+		offsets.reverse();
+	}
+	for offset in offsets {
+		let end = (offset + chunk).min(data.len());
+		file.write_at(offset as u64, &data[offset..end]).await.with_context(|| format!("write_at {} failed", offset))?;
+	}
+	file.close().await.context("close failed")?;
+	ensure!(s.contents("f").await? == data, "file differs from what was written");
+	Ok(())
+} // }}}
+
+async fn overwrite_middle(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"0123456789").await?;
+	let file = s.open("f", options("w")).await.context("open failed")?;
+	file.write_at(3, b"abc").await.context("write_at failed")?;
+	file.close().await.context("close failed")?;
+	let data = s.contents("f").await?;
+	ensure!(data == b"012abc6789", "file contains {:?}", String::from_utf8_lossy(&data));
+	Ok(())
+} // }}}
+
+async fn len_tracks_writes(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let file = s.open("f", options("rwct")).await.context("open failed")?;
+	ensure!(file.len().await? == 0, "new file isn't empty");
+	file.write_at(0, b"abc").await?;
+	ensure!(file.len().await? == 3, "len() doesn't include the first write");
+	file.write_at(100, b"d").await?;
+	ensure!(file.len().await? == 101, "len() doesn't include a write past the end");
+	file.write_at(10, b"e").await?;
+	ensure!(file.len().await? == 101, "a write inside the file changed len()");
+	file.close().await?;
+	Ok(())
+} // }}}
+
+async fn flush_and_sync(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let file = s.open("f", options("wct")).await.context("open failed")?;
+	file.write_at(0, b"data").await?;
+	file.flush().await.context("flush failed")?;
+	file.sync().await.context("sync failed")?;
+	file.close().await?;
+	ensure!(s.contents("f").await? == b"data", "synced data didn't survive");
+	Ok(())
+} // }}}
+
+async fn abort_keeps_data(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let file = s.open("f", options("wct")).await.context("open failed")?;
+	file.write_at(0, b"partial").await?;
+	file.abort().await.context("abort failed")?;
+	drop(file);
+	ensure!(s.contents("f").await? == b"partial", "data written before the session ended was lost");
+	Ok(())
+} // }}}
+
+async fn handle_metadata(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let file = s.open("f", options("wct")).await.context("open failed")?;
+	file.write_at(0, &pattern(1234)).await?;
+	// Straight to the backend's file; OpenFile::metadata() would paper over Unsupported with what open() reported
+	match file.fd.metadata().await {
+		Ok(metadata) => {
+			ensure!(metadata.is_file, "handle metadata doesn't describe a file");
+			ensure!(metadata.size == 1234, "handle metadata reports {} bytes; expected 1234", metadata.size);
+		},
+		// Backends that can't stat an open handle must say so, rather than failing some other way
+		Err(ProtocolError::Unsupported) => (),
+		Err(e) => return Err(Error::new(e).context("metadata failed"))
+	};
+	file.close().await?;
+	Ok(())
+} // }}}
+
+async fn read_own_writes(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let file = s.open("f", options("rwct")).await.context("open failed")?;
+	file.write_at(0, b"first").await?;
+	let data = file.read_at(0, 5).await?;
+	ensure!(data == b"first", "handle doesn't read back its own write");
+	file.write_at(0, b"FIRST").await?;
+	let data = file.read_at(0, 5).await?;
+	ensure!(data == b"FIRST", "handle doesn't read back its own overwrite");
+	file.close().await?;
+	Ok(())
+} // }}}
+
+async fn reopen_sees_writes(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let contents: [&[u8]; 3] = [b"one", b"two, longer", b"3"];
+	for (i, content) in contents.iter().enumerate() {
+		s.create("f", content).await?;
+		let data = s.contents("f").await?;
+		ensure!(data == *content, "round {}:  reopened file contains {:?}", i, String::from_utf8_lossy(&data));
+	}
+	Ok(())
+} // }}}
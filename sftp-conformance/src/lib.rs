@@ -0,0 +1,129 @@
+#![allow(unused_parens)]
+//! Conformance suite for SFTP backends:  a few hundred scenarios covering what the server expects of `Backend` and
+//!    `OpenFile`, from open flags and error kinds to directories, metadata and concurrent access.  Add it to a
+//!    backend crate's tests with
+//!
+//! ```ignore
+//! sftp_conformance::backend_conformance!(MyBackend::new());
+//! ```
+//!
+//! Each scenario runs in its own directory under a fresh "/conformance-*" directory in the backend, which is removed
+//!    again afterwards.
+
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Error;
+
+use sftp_protocol::common::FileAttributes;
+use sftp_server::backend::DynBackend;
+
+mod scenario;
+pub use scenario::Scenario;
+pub use scenario::Scratch;
+pub use scenario::options;
+pub use scenario::read_all;
+
+mod concurrency;
+mod dirs;
+mod io;
+mod metadata;
+mod open;
+
+/// Generates a `#[test]` that runs every scenario against the given backend, failing with a list of the scenarios
+///    that didn't pass
+#[macro_export]
+macro_rules! backend_conformance {
+	($backend:expr) => {
+		#[test]
+		fn backend_conformance() {
+			$crate::run_blocking(::std::sync::Arc::new($backend));
+		}
+	};
+}
+
+/// Every scenario in the suite, in the order they're run
+pub fn scenarios() -> Vec<Scenario> /* {{{ */ {
+	let mut all = Vec::new();
+	open::scenarios(&mut all);
+	io::scenarios(&mut all);
+	dirs::scenarios(&mut all);
+	metadata::scenarios(&mut all);
+	concurrency::scenarios(&mut all);
+	all
+} // }}}
+
+#[derive(Debug, Default)]
+pub struct Report {
+	pub passed: usize,
+	// Names of the scenarios that failed, with why
+	pub failed: Vec<(String, String)>
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} scenario(s) passed, {} failed", self.passed, self.failed.len())?;
+		for (name, error) in &self.failed {
+			write!(f, "\n\t{}:  {}", name, error)?;
+		}
+		Ok(())
+	}
+}
+
+/// Runs every scenario against `backend`, one after another
+pub async fn run(backend: Arc<dyn DynBackend>) -> Result<Report, Error> /* {{{ */ {
+	let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	let root = PathBuf::from(format!("/conformance-{}-{}", std::process::id(), since_epoch.as_nanos()));
+	backend.mkdir(&root, FileAttributes::new()).await?;
+	let mut report = Report::default();
+	for (i, scenario) in scenarios().into_iter().enumerate() {
+		let dir = root.join(i.to_string());
+		let result = match backend.mkdir(&dir, FileAttributes::new()).await {
+			Ok(_) => scenario.run(Scratch::new(backend.clone(), dir)).await,
+			Err(e) => Err(Error::new(e).context("failed to create the scenario's directory"))
+		};
+		match result {
+			Ok(_) => report.passed += 1,
+			Err(e) => report.failed.push((scenario.name, format!("{:#}", e)))
+		};
+	}
+	remove_tree(&*backend, &root).await?;
+	Ok(report)
+} // }}}
+
+/// Runs the suite on a runtime of its own and panics if any scenario fails; this is what `backend_conformance!` calls
+pub fn run_blocking(backend: Arc<dyn DynBackend>) /* {{{ */ {
+	let mut runtime = tokio::runtime::Builder::new()
+		.threaded_scheduler()
+		.enable_all()
+		.build()
+		.expect("Failed to start a runtime for the conformance suite");
+	let report = runtime.block_on(run(backend)).expect("Conformance suite failed to run");
+	assert!(report.failed.is_empty(), "{}", report);
+} // }}}
+
+// Deletes a directory and everything under it, relying only on list(), delete_file() and rmdir()
+async fn remove_tree(backend: &dyn DynBackend, root: &Path) -> Result<(), Error> /* {{{ */ {
+	let mut dirs = vec![root.to_path_buf()];
+	let mut i = 0;
+	while(i < dirs.len()) {
+		let dir = dirs[i].clone();
+		for entry in backend.list(&dir).await? {
+			let path = dir.join(&entry.path);
+			if(entry.is_dir) {
+				dirs.push(path);
+			} else {
+				backend.delete_file(&path).await?;
+			}
+		}
+		i += 1;
+	}
+	for dir in dirs.iter().rev() {
+		backend.rmdir(dir).await?;
+	}
+	Ok(())
+} // }}}
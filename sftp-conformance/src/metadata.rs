@@ -0,0 +1,177 @@
+use anyhow::Context;
+use anyhow::Error;
+use anyhow::ensure;
+
+use sftp_protocol::Error as ProtocolError;
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::stream::packet::status::StatusType;
+use sftp_server::backend::OpenOptions;
+
+use super::scenario::Scenario;
+use super::scenario::Scratch;
+use super::scenario::add;
+use super::scenario::expect_status;
+use super::scenario::options;
+
+const FILE_MODES: &[u32] = &[0o600, 0o640, 0o644, 0o700, 0o755, 0o400];
+const DIR_MODES: &[u32] = &[0o700, 0o755, 0o750];
+const TIMES: &[u32] = &[1_000_000_000, 1_600_000_000, 2_000_000_000];
+
+// Modes requested at creation are still subject to the backend's umask, if it has one; these survive even 077
+const CREATE_FILE_MODES: &[u32] = &[0o600, 0o400];
+const CREATE_DIR_MODE: u32 = 0o700;
+
+pub(crate) fn scenarios(all: &mut Vec<Scenario>) /* {{{ */ {
+	add(all, "metadata/file", file_metadata);
+	add(all, "metadata/directory", dir_metadata);
+	add(all, "metadata/missing", missing_metadata);
+	for &mode in FILE_MODES {
+		add(all, format!("metadata/chmod/file-{:o}", mode), move |s| chmod(s, false, mode));
+	}
+	for &mode in DIR_MODES {
+		add(all, format!("metadata/chmod/directory-{:o}", mode), move |s| chmod(s, true, mode));
+	}
+	for &time in TIMES {
+		add(all, format!("metadata/times/file-{}", time), move |s| set_times(s, false, time));
+		add(all, format!("metadata/times/directory-{}", time), move |s| set_times(s, true, time));
+	}
+	add(all, "metadata/mode-and-times-together", mode_and_times);
+	add(all, "metadata/set-missing", set_missing);
+	add(all, "metadata/set-on-handle", set_on_handle);
+	for &mode in CREATE_FILE_MODES {
+		add(all, format!("metadata/create-with-mode/file-{:o}", mode), move |s| create_with_mode(s, mode));
+	}
+	add(all, format!("metadata/create-with-mode/directory-{:o}", CREATE_DIR_MODE), mkdir_with_mode);
+	add(all, "metadata/statvfs", statvfs);
+	add(all, "metadata/statvfs-on-renamed-handle", statvfs_on_handle);
+} // }}}
+
+async fn file_metadata(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"seventeen bytes!!").await?;
+	let metadata = s.backend.metadata(&s.path("f")).await.context("metadata failed")?;
+	ensure!(metadata.is_file && !metadata.is_dir, "file isn't reported as one");
+	ensure!(metadata.size == 17, "file is reported as {} bytes; expected 17", metadata.size);
+	Ok(())
+} // }}}
+
+async fn dir_metadata(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.mkdir("d").await?;
+	let metadata = s.backend.metadata(&s.path("d")).await.context("metadata failed")?;
+	ensure!(metadata.is_dir && !metadata.is_file, "directory isn't reported as one");
+	Ok(())
+} // }}}
+
+async fn missing_metadata(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	expect_status(s.backend.metadata(&s.path("missing")).await, StatusType::NoSuchFile, "metadata for a missing path")
+} // }}}
+
+// Creates the file or directory a scenario changes the metadata of
+async fn target(s: &Scratch, dir: bool) -> Result<&'static str, Error> /* {{{ */ {
+	match dir {
+		true => s.mkdir("d").await.map(|_| "d"),
+		false => s.create("f", b"x").await.map(|_| "f")
+	}
+} // }}}
+
+async fn chmod(s: Scratch, dir: bool, mode: u32) -> Result<(), Error> /* {{{ */ {
+	let name = target(&s, dir).await?;
+	s.backend.set_metadata(&s.path(name), None, Some(mode), None).await.context("set_metadata failed")?;
+	let metadata = s.backend.metadata(&s.path(name)).await.context("metadata failed")?;
+	ensure!(metadata.permissions & 0o7777 == mode, "permissions are {:o} after setting {:o}", metadata.permissions & 0o7777, mode);
+	// Put back something that lets the suite clean up after itself
+	s.backend.set_metadata(&s.path(name), None, Some(0o700), None).await.context("failed to restore permissions")?;
+	Ok(())
+} // }}}
+
+async fn set_times(s: Scratch, dir: bool, time: u32) -> Result<(), Error> /* {{{ */ {
+	let name = target(&s, dir).await?;
+	let atime = time + 12345;
+	s.backend.set_metadata(&s.path(name), None, None, Some((atime, time))).await.context("set_metadata failed")?;
+	let metadata = s.backend.metadata(&s.path(name)).await.context("metadata failed")?;
+	ensure!(metadata.mtime.timestamp() == time as i64, "mtime is {} after setting {}", metadata.mtime.timestamp(), time);
+	ensure!(metadata.atime.timestamp() == atime as i64, "atime is {} after setting {}", metadata.atime.timestamp(), atime);
+	Ok(())
+} // }}}
+
+async fn mode_and_times(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"x").await?;
+	s.backend.set_metadata(&s.path("f"), None, Some(0o640), Some((1_500_000_000, 1_400_000_000))).await.context("set_metadata failed")?;
+	let metadata = s.backend.metadata(&s.path("f")).await.context("metadata failed")?;
+	ensure!(metadata.permissions & 0o7777 == 0o640, "permissions are {:o}; expected 640", metadata.permissions & 0o7777);
+	ensure!(metadata.mtime.timestamp() == 1_400_000_000, "mtime is {}; expected 1400000000", metadata.mtime.timestamp());
+	ensure!(metadata.atime.timestamp() == 1_500_000_000, "atime is {}; expected 1500000000", metadata.atime.timestamp());
+	Ok(())
+} // }}}
+
+async fn set_missing(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	expect_status(s.backend.set_metadata(&s.path("missing"), None, Some(0o600), None).await, StatusType::NoSuchFile, "set_metadata on a missing path")?;
+	ensure!(!s.exists("missing").await, "set_metadata created the path");
+	Ok(())
+} // }}}
+
+async fn set_on_handle(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let file = s.open("f", options("wct")).await.context("open failed")?;
+	match file.set_metadata(None, Some(0o600), Some((1_000_000_000, 1_000_000_000))).await {
+		Ok(_) => {
+			file.close().await.context("close failed")?;
+			let metadata = s.backend.metadata(&s.path("f")).await.context("metadata failed")?;
+			ensure!(metadata.permissions & 0o7777 == 0o600, "permissions are {:o} after setting 600 through the handle", metadata.permissions & 0o7777);
+			ensure!(metadata.mtime.timestamp() == 1_000_000_000, "mtime is {} after setting it through the handle", metadata.mtime.timestamp());
+		},
+		// The server falls back to the path when a handle can't do this itself
+		Err(ProtocolError::Unsupported) => file.close().await.context("close failed")?,
+		Err(e) => return Err(Error::new(e).context("set_metadata on the handle failed"))
+	};
+	Ok(())
+} // }}}
+
+async fn create_with_mode(s: Scratch, mode: u32) -> Result<(), Error> /* {{{ */ {
+	let mut attrs = FileAttributes::new();
+	attrs.set_permissions(mode);
+	let file = s.open("f", OpenOptions{attrs: attrs, ..options("wct")}).await.context("open failed")?;
+	file.close().await.context("close failed")?;
+	let metadata = s.backend.metadata(&s.path("f")).await.context("metadata failed")?;
+	ensure!(metadata.permissions & 0o7777 == mode, "new file has permissions {:o}; asked for {:o}", metadata.permissions & 0o7777, mode);
+	Ok(())
+} // }}}
+
+async fn mkdir_with_mode(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	let mut attrs = FileAttributes::new();
+	attrs.set_permissions(CREATE_DIR_MODE);
+	s.backend.mkdir(&s.path("d"), attrs).await.context("mkdir failed")?;
+	let metadata = s.backend.metadata(&s.path("d")).await.context("metadata failed")?;
+	ensure!(metadata.permissions & 0o7777 == CREATE_DIR_MODE, "new directory has permissions {:o}; asked for {:o}", metadata.permissions & 0o7777, CREATE_DIR_MODE);
+	Ok(())
+} // }}}
+
+async fn statvfs(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"x").await?;
+	let stats = match s.backend.statvfs(&s.path("f")).await {
+		Ok(v) => v,
+		// Optional, like the extensions it serves
+		Err(ProtocolError::Unsupported) => return Ok(()),
+		Err(e) => return Err(Error::new(e).context("statvfs failed"))
+	};
+	ensure!(stats.block_size > 0, "block size is 0");
+	ensure!(stats.free_bytes <= stats.total_bytes, "{} bytes free out of {}", stats.free_bytes, stats.total_bytes);
+	ensure!(stats.available_bytes <= stats.free_bytes, "{} bytes available but only {} free", stats.available_bytes, stats.free_bytes);
+	ensure!(stats.free_inodes <= stats.total_inodes, "{} inodes free out of {}", stats.free_inodes, stats.total_inodes);
+	Ok(())
+} // }}}
+
+async fn statvfs_on_handle(s: Scratch) -> Result<(), Error> /* {{{ */ {
+	s.create("f", b"x").await?;
+	let file = s.open("f", options("r")).await.context("open failed")?;
+	// The handle has to keep working after its path is gone
+	s.backend.rename(&s.path("f"), &s.path("g")).await.context("rename failed")?;
+	let result = file.statvfs().await;
+	file.close().await.context("close failed")?;
+	let stats = match result {
+		Ok(v) => v,
+		Err(ProtocolError::Unsupported) => return Ok(()),
+		Err(e) => return Err(Error::new(e).context("statvfs on the handle failed"))
+	};
+	ensure!(stats.block_size > 0, "block size is 0");
+	ensure!(stats.free_bytes <= stats.total_bytes, "{} bytes free out of {}", stats.free_bytes, stats.total_bytes);
+	Ok(())
+} // }}}
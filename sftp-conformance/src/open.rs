@@ -0,0 +1,105 @@
+use anyhow::Context;
+use anyhow::Error;
+use anyhow::ensure;
+
+use sftp_protocol::stream::packet::status::StatusType;
+
+use super::scenario::Scenario;
+use super::scenario::Scratch;
+use super::scenario::add;
+use super::scenario::expect_err;
+use super::scenario::expect_status;
+use super::scenario::options;
+use super::scenario::read_all;
+
+const ORIGINAL: &[u8] = b"hello";
+
+// (name, flags) for each way of opening a file that SFTP clients send
+const ACCESS: &[(&str, &str)] = &[
+	("read", "r"),
+	("write", "w"),
+	("read-write", "rw"),
+	("append", "wa"),
+	("read-append", "rwa")
+];
+const CREATION: &[(&str, &str)] = &[
+	("open", ""),
+	("create", "c"),
+	("create-truncate", "ct"),
+	("create-new", "cx"),
+	("truncate", "t")
+];
+
+pub(crate) fn scenarios(all: &mut Vec<Scenario>) /* {{{ */ {
+	for &(access_name, access) in ACCESS {
+		for &(creation_name, creation) in CREATION {
+			// Creating or truncating without write access, and truncating an append-only handle, are meaningless;
+			//    backends may refuse them however they like
+			if((!access.contains('w') && !creation.is_empty()) || (access.contains('a') && creation.contains('t'))) {
+				continue;
+			}
+			for &existing in &[true, false] {
+				let name = format!("open/{}/{}/{}", access_name, creation_name, if(existing) { "existing" } else { "missing" });
+				add(all, name, move |s| open_case(s, access, creation, existing));
+			}
+		}
+	}
+} // }}}
+
+// Splices `data` into `content` at `offset`, zero-filling any gap, as a positional write would
+fn overlay(content: &mut Vec<u8>, offset: usize, data: &[u8]) /* {{{ */ {
+	if(content.len() < offset + data.len()) {
+		content.resize(offset + data.len(), 0);
+	}
+	content[offset..offset + data.len()].copy_from_slice(data);
+} // }}}
+
+async fn open_case(s: Scratch, access: &'static str, creation: &'static str, existing: bool) -> Result<(), Error> /* {{{ */ {
+	if(existing) {
+		s.create("f", ORIGINAL).await?;
+	}
+	let result = s.open("f", options(&format!("{}{}", access, creation))).await;
+	let creates = creation.contains('c');
+	let exclusive = creation.contains('x');
+	let truncates = creation.contains('t');
+	let expected = match (existing, creates) {
+		(false, false) => {
+			expect_status(result, StatusType::NoSuchFile, "opening a missing file without create")?;
+			ensure!(!s.exists("f").await, "failed open left a file behind");
+			return Ok(());
+		},
+		(true, _) if exclusive => {
+			expect_err(result, "create_new on an existing file")?;
+			ensure!(s.contents("f").await? == ORIGINAL, "failed create_new modified the existing file");
+			return Ok(());
+		},
+		(false, true) => Vec::new(),
+		(true, _) if truncates => Vec::new(),
+		(true, _) => ORIGINAL.to_vec()
+	};
+	let file = result.context("open failed")?;
+
+	if(access.contains('r')) {
+		let data = read_all(&file).await?;
+		ensure!(data == expected, "read {:?} after opening; expected {:?}", data, expected);
+	} else {
+		expect_err(file.read_at(0, 16).await, "reading a handle opened without read access")?;
+	}
+
+	let mut expected = expected;
+	if(access.contains('w')) {
+		file.write_at(1, b"XY").await.context("write_at failed")?;
+		match access.contains('a') {
+			// Append forces every write to the end of the file, whatever its offset
+			true => expected.extend_from_slice(b"XY"),
+			false => overlay(&mut expected, 1, b"XY")
+		};
+	} else {
+		expect_err(file.write_at(0, b"XY").await, "writing a handle opened without write access")?;
+	}
+	file.close().await.context("close failed")?;
+
+	let data = s.contents("f").await?;
+	ensure!(data == expected, "file contains {:?} after closing; expected {:?}", data, expected);
+	Ok(())
+} // }}}
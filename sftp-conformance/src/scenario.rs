@@ -0,0 +1,141 @@
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+
+use anyhow::Context;
+use anyhow::Error;
+
+use sftp_protocol::Error as ProtocolError;
+use sftp_protocol::stream::packet::status::StatusType;
+use sftp_server::backend::DynBackend;
+use sftp_server::backend::OpenOptions;
+use sftp_server::file::OpenFile;
+
+/// One named check, run in a directory of its own
+pub struct Scenario {
+	pub name: String,
+	body: Box<dyn Fn(Scratch) -> BoxFuture<'static, Result<(), Error>> + Send + Sync>
+}
+
+impl Scenario {
+	pub async fn run(&self, scratch: Scratch) -> Result<(), Error> /* {{{ */ {
+		(self.body)(scratch).await
+	} // }}}
+}
+
+pub(crate) fn add<F, Fut>(all: &mut Vec<Scenario>, name: impl Into<String>, body: F) /* {{{ */
+where
+	F: Fn(Scratch) -> Fut + Send + Sync + 'static,
+	Fut: Future<Output = Result<(), Error>> + Send + 'static
+{
+	all.push(Scenario{
+		name: name.into(),
+		body: Box::new(move |scratch| body(scratch).boxed())
+	});
+} // }}}
+
+/// Builds OpenOptions from a string of flags, like fopen():  "r" read, "w" write, "a" append, "c" create,
+///    "t" truncate, "x" create_new
+pub fn options(flags: &str) -> OpenOptions /* {{{ */ {
+	OpenOptions{
+		read: flags.contains('r'),
+		write: flags.contains('w'),
+		append: flags.contains('a'),
+		create: flags.contains('c'),
+		truncate: flags.contains('t'),
+		create_new: flags.contains('x'),
+		..Default::default()
+	}
+} // }}}
+
+/// Reads a file from the start until read_at() reports the end, tolerating short reads
+pub async fn read_all(file: &OpenFile) -> Result<Vec<u8>, Error> /* {{{ */ {
+	let mut data = Vec::new();
+	loop {
+		let chunk = file.read_at(data.len() as u64, 64 * 1024).await.context("read_at failed")?;
+		if(chunk.is_empty()) {
+			return Ok(data);
+		}
+		data.extend_from_slice(&chunk);
+	}
+} // }}}
+
+// Predictable, non-repeating-looking content, so that misplaced bytes show up
+pub(crate) fn pattern(len: usize) -> Vec<u8> /* {{{ */ {
+	(0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+} // }}}
+
+pub(crate) fn expect_status<T>(result: Result<T, ProtocolError>, expected: StatusType, what: &str) -> Result<(), Error> /* {{{ */ {
+	match result {
+		Ok(_) => Err(Error::msg(format!("{} succeeded; expected {:?}", what, expected))),
+		Err(e) if e.status_type() as u32 == expected as u32 => Ok(()),
+		Err(e) => Err(Error::msg(format!("{} failed with {:?} ({}); expected {:?}", what, e.status_type(), e, expected)))
+	}
+} // }}}
+
+pub(crate) fn expect_err<T>(result: Result<T, ProtocolError>, what: &str) -> Result<(), Error> /* {{{ */ {
+	match result {
+		Ok(_) => Err(Error::msg(format!("{} succeeded; expected an error", what))),
+		Err(_) => Ok(())
+	}
+} // }}}
+
+/// The directory a scenario runs in, with shortcuts for the setup and checks scenarios have in common
+#[derive(Clone)]
+pub struct Scratch {
+	pub backend: Arc<dyn DynBackend>,
+	pub dir: PathBuf
+}
+
+impl Scratch {
+	pub fn new(backend: Arc<dyn DynBackend>, dir: PathBuf) -> Self /* {{{ */ {
+		Self{
+			backend: backend,
+			dir: dir
+		}
+	} // }}}
+
+	pub fn path(&self, name: &str) -> PathBuf /* {{{ */ {
+		self.dir.join(name)
+	} // }}}
+
+	pub async fn open(&self, name: &str, options: OpenOptions) -> Result<OpenFile, ProtocolError> /* {{{ */ {
+		self.backend.open(&self.path(name), options).await
+	} // }}}
+
+	/// Creates (or replaces) a file with the given contents
+	pub async fn create(&self, name: &str, data: &[u8]) -> Result<(), Error> /* {{{ */ {
+		let file = self.open(name, options("wct")).await.with_context(|| format!("failed to create {}", name))?;
+		if(!data.is_empty()) {
+			file.write_at(0, data).await.with_context(|| format!("failed to write {}", name))?;
+		}
+		file.close().await.with_context(|| format!("failed to close {}", name))?;
+		Ok(())
+	} // }}}
+
+	pub async fn contents(&self, name: &str) -> Result<Vec<u8>, Error> /* {{{ */ {
+		let file = self.open(name, options("r")).await.with_context(|| format!("failed to open {} for reading", name))?;
+		let data = read_all(&file).await?;
+		file.close().await.with_context(|| format!("failed to close {}", name))?;
+		Ok(data)
+	} // }}}
+
+	pub async fn exists(&self, name: &str) -> bool /* {{{ */ {
+		self.backend.metadata(&self.path(name)).await.is_ok()
+	} // }}}
+
+	pub async fn mkdir(&self, name: &str) -> Result<(), Error> /* {{{ */ {
+		self.backend.mkdir(&self.path(name), Default::default()).await.with_context(|| format!("failed to create directory {}", name))?;
+		Ok(())
+	} // }}}
+
+	/// Names in the directory `name` ("" for the scenario's own directory), sorted
+	pub async fn names(&self, name: &str) -> Result<Vec<String>, Error> /* {{{ */ {
+		let mut names: Vec<_> = self.backend.list(&self.path(name)).await.context("list failed")?.into_iter().map(|m| m.path).collect();
+		names.sort();
+		Ok(names)
+	} // }}}
+}
@@ -0,0 +1,155 @@
+#![allow(unused_parens)]
+//! Runs the suite against `BlockingAdapter`, over the simplest `BlockingBackend` there is:  std::fs under a temporary
+//!    directory
+
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::DirBuilder;
+use std::fs::Permissions;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use filetime::FileTime;
+use filetime::set_file_times;
+
+use sftp_protocol::Error;
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::Metadata;
+use sftp_server::backend::BlockingAdapter;
+use sftp_server::backend::BlockingBackend;
+use sftp_server::backend::OpenOptions;
+use sftp_server::backend::Result;
+
+struct StdFilesystem {
+	root: PathBuf
+}
+
+impl StdFilesystem {
+	fn new(root: impl AsRef<Path>) -> io::Result<Self> /* {{{ */ {
+		fs::create_dir_all(&root)?;
+		// Canonical, so realpath() can strip it back off
+		Ok(Self{root: root.as_ref().canonicalize()?})
+	} // }}}
+
+	fn full_path(&self, path: &Path) -> Result<PathBuf> /* {{{ */ {
+		Ok(self.root.join(self.normalize_path(path)?))
+	} // }}}
+}
+
+fn convert_metadata(path: String, meta: &fs::Metadata) -> Metadata /* {{{ */ {
+	let atime = meta.accessed().unwrap_or(UNIX_EPOCH);
+	let mtime = meta.modified().unwrap_or(UNIX_EPOCH);
+	Metadata::new(&path, meta.len(), meta.is_dir(), meta.is_file(), None, meta.uid(), meta.gid(), meta.mode(), atime, mtime)
+} // }}}
+
+impl BlockingBackend for StdFilesystem {
+	type File = fs::File;
+
+	fn metadata(&self, path: &Path) -> Result<Metadata> {
+		let meta = fs::metadata(self.full_path(path)?)?;
+		Ok(convert_metadata(path.to_string_lossy().to_string(), &meta))
+	}
+
+	fn list(&self, path: &Path) -> Result<VecDeque<Metadata>> {
+		let mut result = VecDeque::new();
+		for entry in fs::read_dir(self.full_path(path)?)? {
+			let entry = entry?;
+			result.push_back(convert_metadata(entry.file_name().to_string_lossy().to_string(), &entry.metadata()?));
+		}
+		Ok(result)
+	}
+
+	fn open(&self, path: &Path, options: OpenOptions) -> Result<fs::File> {
+		let path = self.full_path(path)?;
+		let existed = fs::symlink_metadata(&path).is_ok();
+		let permissions = options.attrs.get_permissions();
+		let mut std_options = fs::OpenOptions::new();
+		std_options
+			.read(options.read)
+			.write(options.write)
+			.append(options.append)
+			.create(options.create)
+			.truncate(options.truncate)
+			.create_new(options.create_new);
+		if let Some(permissions) = permissions {
+			std_options.mode(permissions);
+		}
+		let file = std_options.open(&path)?;
+		// The umask may have stripped bits from the mode
+		if let (false, Some(permissions)) = (existed, permissions) {
+			file.set_permissions(Permissions::from_mode(permissions))?;
+		}
+		Ok(file)
+	}
+
+	fn set_metadata(&self, path: &Path, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		let path = self.full_path(path)?;
+		fs::symlink_metadata(&path)?;
+		if(uid_and_gid.is_some()) {
+			return Err(Error::Unsupported);
+		}
+		if let Some(permissions) = permissions {
+			fs::set_permissions(&path, Permissions::from_mode(permissions))?;
+		}
+		if let Some((atime, mtime)) = atime_and_mtime {
+			set_file_times(&path, FileTime::from_unix_time(atime as i64, 0), FileTime::from_unix_time(mtime as i64, 0))?;
+		}
+		Ok(())
+	}
+
+	fn delete_file(&self, path: &Path) -> Result<()> {
+		Ok(fs::remove_file(self.full_path(path)?)?)
+	}
+
+	fn mkdir(&self, path: &Path, attrs: FileAttributes) -> Result<()> {
+		let mut builder = DirBuilder::new();
+		if let Some(permissions) = attrs.get_permissions() {
+			builder.mode(permissions);
+		}
+		builder.create(self.full_path(path)?)?;
+		self.set_metadata(path, attrs.get_uid_gid(), attrs.get_permissions(), attrs.get_atime_mtime())
+	}
+
+	fn rmdir(&self, path: &Path) -> Result<()> {
+		Ok(fs::remove_dir(self.full_path(path)?)?)
+	}
+
+	fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+		let from = self.full_path(from)?;
+		let to = self.full_path(to)?;
+		fs::symlink_metadata(&from)?;
+		// Racy, but nothing else touches the test's directory
+		if(from != to && fs::symlink_metadata(&to).is_ok()) {
+			return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+		}
+		Ok(fs::rename(from, to)?)
+	}
+
+	fn posix_rename(&self, from: &Path, to: &Path) -> Result<()> {
+		Ok(fs::rename(self.full_path(from)?, self.full_path(to)?)?)
+	}
+
+	fn hardlink(&self, from: &Path, to: &Path) -> Result<()> {
+		Ok(fs::hard_link(self.full_path(from)?, self.full_path(to)?)?)
+	}
+
+	fn realpath(&self, path: &Path) -> Result<PathBuf> {
+		let path = self.full_path(path)?.canonicalize()?;
+		match path.strip_prefix(&self.root) {
+			Ok(v) => Ok(PathBuf::from("/").join(v)),
+			Err(_) => Err(Error::InvalidPath)
+		}
+	}
+}
+
+sftp_conformance::backend_conformance!({
+	let root = std::env::temp_dir().join(format!("sftp-blocking-conformance-{}", std::process::id()));
+	let backend = StdFilesystem::new(&root).expect("Failed to create the conformance root");
+	BlockingAdapter::new(backend, 8)
+});
@@ -0,0 +1,259 @@
+use std::collections::HashSet;
+
+use anyhow::Error;
+
+use log::LevelFilter;
+
+use sftp_server::config::Config;
+use sftp_server::config::ModePolicy;
+use sftp_server::config::expand_start_dir;
+use sftp_server::supported_requests;
+
+const USAGE: &str = "usage: sftp-filesystem [-ehR] [-d start_directory] [-f log_facility] [-l log_level]
+	[-P denied_requests] [-p allowed_requests] [-u umask]
+       sftp-filesystem -Q protocol_feature";
+
+// Flags accepted by OpenSSH's sftp-server, so that sshd_config can point "Subsystem sftp" at us unchanged
+#[derive(Debug)]
+pub struct StdioArgs {
+	// As given, with its escapes expanded for each session; see expand_start_dir()
+	pub start_dir: Option<String>,
+	pub read_only: bool,
+	pub umask: Option<u32>,
+	pub log_level: LevelFilter,
+	pub denied: Option<HashSet<String>>,
+	pub allowed: Option<HashSet<String>>,
+	// -h and -Q ask for information instead of a session
+	pub help: bool,
+	pub query: Option<String>
+}
+
+impl Default for StdioArgs {
+	fn default() -> Self /* {{{ */ {
+		Self{
+			start_dir: None,
+			read_only: false,
+			umask: None,
+			log_level: LevelFilter::Error,
+			denied: None,
+			allowed: None,
+			help: false,
+			query: None
+		}
+	} // }}}
+}
+
+fn parse_log_level(level: &str) -> Result<LevelFilter, Error> /* {{{ */ {
+	Ok(match level.to_ascii_uppercase().as_str() {
+		"QUIET" => LevelFilter::Off,
+		"FATAL" | "ERROR" => LevelFilter::Error,
+		"INFO" => LevelFilter::Info,
+		"VERBOSE" => LevelFilter::Info,
+		"DEBUG" | "DEBUG1" => LevelFilter::Debug,
+		"DEBUG2" | "DEBUG3" => LevelFilter::Trace,
+		_ => return Err(Error::msg(format!("Invalid log level \"{}\"", level)))
+	})
+} // }}}
+
+fn parse_list(list: &str) -> HashSet<String> /* {{{ */ {
+	list.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+} // }}}
+
+impl StdioArgs {
+	pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Error> /* {{{ */ {
+		let mut this = Self::default();
+		let mut args = args.into_iter().skip(1);
+		while let Some(arg) = args.next() {
+			if(!arg.starts_with('-') || arg.len() < 2) {
+				return Err(Error::msg(format!("Unexpected argument \"{}\"", arg)));
+			}
+			let flag = &arg[1..2];
+			// Like getopt(), accept both "-d dir" and "-ddir"
+			let mut value = || -> Result<String, Error> {
+				match arg.len() > 2 {
+					true => Ok(arg[2..].to_string()),
+					false => args.next().ok_or_else(|| Error::msg(format!("Missing value for -{}", flag)))
+				}
+			};
+			match flag {
+				"d" => {
+					let start_dir = value()?;
+					// Checked now, so that a mistake stops the server rather than being ignored by every session
+					expand_start_dir(&start_dir, "", "").map_err(Error::msg)?;
+					this.start_dir = Some(start_dir);
+				},
+				"R" => this.read_only = true,
+				"u" => {
+					let umask = value()?;
+					this.umask = Some(u32::from_str_radix(&umask, 8).map_err(|_| Error::msg(format!("Invalid umask \"{}\"", umask)))?);
+				},
+				"l" => this.log_level = parse_log_level(&value()?)?,
+				"P" => this.denied = Some(parse_list(&value()?)),
+				"p" => this.allowed = Some(parse_list(&value()?)),
+				"h" => this.help = true,
+				"Q" => this.query = Some(value()?),
+				// We always log to stderr, so there's no syslog facility to pick
+				"e" => (),
+				"f" => {
+					value()?;
+				},
+				_ => return Err(Error::msg(format!("Unknown option -{}", flag)))
+			};
+		}
+		Ok(this)
+	} // }}}
+
+	/// Answers -h or -Q the way OpenSSH's sftp-server does, returning the exit code if the process shouldn't go on to
+	///    serve a session
+	pub fn answer_query(&self) -> Option<i32> /* {{{ */ {
+		if(self.help) {
+			eprintln!("{}", USAGE);
+			return Some(1);
+		}
+		match self.query.as_deref() {
+			Some("requests") => {
+				for name in supported_requests() {
+					println!("{}", name);
+				}
+				Some(0)
+			},
+			Some(feature) => {
+				eprintln!("invalid query type \"{}\"", feature);
+				Some(1)
+			},
+			None => None
+		}
+	} // }}}
+
+	pub fn apply(&self, config: &mut Config) /* {{{ */ {
+		if let Some(start_dir) = &self.start_dir {
+			config.start_dir = Some(start_dir.clone());
+		}
+		config.read_only = config.read_only || self.read_only;
+		if let Some(umask) = self.umask {
+			config.mode_policy = ModePolicy::new(umask);
+		}
+		if let Some(denied) = &self.denied {
+			config.request_filter.denied = denied.clone();
+		}
+		if let Some(allowed) = &self.allowed {
+			config.request_filter.allowed = Some(allowed.clone());
+		}
+	} // }}}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use anyhow::Error;
+
+	use log::LevelFilter;
+
+	use sftp_server::config::Config;
+
+	use super::StdioArgs;
+
+	fn parse(args: &[&str]) -> Result<StdioArgs, Error> /* {{{ */ {
+		StdioArgs::parse(std::iter::once("sftp-server").chain(args.iter().copied()).map(String::from))
+	} // }}}
+
+	fn set(names: &[&str]) -> Option<HashSet<String>> /* {{{ */ {
+		Some(names.iter().map(|s| s.to_string()).collect())
+	} // }}}
+
+	#[test]
+	fn no_flags() -> Result<(), Error> {
+		let args = parse(&[])?;
+		assert_eq!(args.start_dir, None);
+		assert!(!args.read_only);
+		assert_eq!(args.umask, None);
+		assert_eq!(args.log_level, LevelFilter::Error);
+		assert_eq!(args.denied, None);
+		assert_eq!(args.allowed, None);
+		assert!(!args.help);
+		assert_eq!(args.query, None);
+		Ok(())
+	}
+
+	#[test]
+	fn start_dir() -> Result<(), Error> {
+		assert_eq!(parse(&["-d", "/srv/%u"])?.start_dir.as_deref(), Some("/srv/%u"));
+		assert_eq!(parse(&["-d%d/uploads"])?.start_dir.as_deref(), Some("%d/uploads"));
+		assert_eq!(parse(&["-d", "/100%%"])?.start_dir.as_deref(), Some("/100%%"));
+		assert!(parse(&["-d", "/srv/%x"]).is_err());
+		assert!(parse(&["-d", "/srv/%"]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn read_only() -> Result<(), Error> {
+		assert!(parse(&["-R"])?.read_only);
+		Ok(())
+	}
+
+	#[test]
+	fn umask() -> Result<(), Error> {
+		assert_eq!(parse(&["-u", "027"])?.umask, Some(0o027));
+		assert_eq!(parse(&["-u077"])?.umask, Some(0o077));
+		assert!(parse(&["-u", "8"]).is_err());
+		assert!(parse(&["-u", "rwx"]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn log_level() -> Result<(), Error> {
+		assert_eq!(parse(&["-l", "QUIET"])?.log_level, LevelFilter::Off);
+		assert_eq!(parse(&["-l", "verbose"])?.log_level, LevelFilter::Info);
+		assert_eq!(parse(&["-lDEBUG1"])?.log_level, LevelFilter::Debug);
+		assert_eq!(parse(&["-l", "DEBUG3"])?.log_level, LevelFilter::Trace);
+		assert!(parse(&["-l", "LOUD"]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn request_lists() -> Result<(), Error> {
+		let args = parse(&["-P", "remove,rename", "-p", "open, read,,close"])?;
+		assert_eq!(args.denied, set(&["remove", "rename"]));
+		assert_eq!(args.allowed, set(&["open", "read", "close"]));
+		Ok(())
+	}
+
+	#[test]
+	fn help_and_query() -> Result<(), Error> {
+		let args = parse(&["-h"])?;
+		assert!(args.help);
+		assert_eq!(args.answer_query(), Some(1));
+		assert_eq!(parse(&["-Q", "requests"])?.answer_query(), Some(0));
+		assert_eq!(parse(&["-Qfeatures"])?.answer_query(), Some(1));
+		assert_eq!(parse(&["-R"])?.answer_query(), None);
+		Ok(())
+	}
+
+	#[test]
+	fn logging_flags_ignored() -> Result<(), Error> {
+		parse(&["-e", "-f", "AUTH", "-fLOCAL0"])?;
+		Ok(())
+	}
+
+	#[test]
+	fn bad_input() {
+		assert!(parse(&["-x"]).is_err());
+		assert!(parse(&["-d"]).is_err());
+		assert!(parse(&["-R", "-u"]).is_err());
+		assert!(parse(&["extra"]).is_err());
+		assert!(parse(&["-"]).is_err());
+	}
+
+	#[test]
+	fn apply() -> Result<(), Error> {
+		let mut config = Config::default();
+		parse(&["-d", "%d/in", "-R", "-u", "077", "-P", "remove", "-p", "open,close"])?.apply(&mut config);
+		assert_eq!(config.start_dir.as_deref(), Some("%d/in"));
+		assert!(config.read_only);
+		assert_eq!(config.mode_policy.umask, 0o077);
+		assert_eq!(Some(config.request_filter.denied), set(&["remove"]));
+		assert_eq!(config.request_filter.allowed, set(&["open", "close"]));
+		Ok(())
+	}
+}
@@ -1,31 +1,64 @@
 use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::DirBuilder;
 use std::fs::Permissions;
+use std::io;
 use std::os::linux::fs::MetadataExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use chrono::DateTime;
 use chrono::NaiveDateTime;
 use chrono::Utc;
 
-use tokio::fs::OpenOptions;
-use tokio::fs::create_dir;
+use tokio::fs::canonicalize;
+use tokio::fs::hard_link;
 use tokio::fs::read_dir;
 use tokio::fs::read_link;
 use tokio::fs::remove_dir;
 use tokio::fs::remove_file;
 use tokio::fs::rename;
 use tokio::fs::set_permissions;
+use tokio::task::spawn_blocking;
 
 use filetime::FileTime;
 use filetime::set_file_times;
 
+use nix::errno::Errno;
+use nix::fcntl::FallocateFlags;
+use nix::fcntl::fallocate;
+use nix::sys::stat::Mode;
+use nix::sys::stat::fchmod;
+use nix::sys::stat::futimens;
+use nix::sys::statvfs::FsFlags;
+use nix::sys::statvfs::Statvfs;
+use nix::sys::statvfs::fstatvfs;
+use nix::sys::statvfs::statvfs;
+use nix::sys::time::TimeSpec;
+use nix::libc;
+use nix::sys::time::TimeValLike;
+
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::FsStats;
 use sftp_protocol::common::Metadata;
+use sftp_protocol::Error;
 use sftp_server::file::OpenFile;
+use sftp_server::file::PositionalFile;
 use sftp_server::backend::Backend;
+use sftp_server::backend::OpenOptions;
 use sftp_server::backend::PathRef;
 use sftp_server::backend::Result;
+use sftp_server::lock::LockKey;
+
+// From linux/fs.h; not every libc target exports it
+const RENAME_NOREPLACE: libc::c_uint = 1;
 
 #[derive(Clone, Debug)]
 pub struct Filesystem {
@@ -38,26 +71,173 @@ lazy_static! {
 
 async fn metadata(path: impl AsRef<Path>) -> Result<Metadata> {
 	let meta = tokio::fs::metadata(&path).await?;
+	let link_target = match meta.file_type().is_symlink() {
+		true => Some(read_link(path.as_ref()).await?.to_string_lossy().to_string()),
+		false => None
+	};
+	Ok(convert_metadata(path.as_ref().to_string_lossy().to_string(), link_target, &meta))
+}
+
+fn convert_metadata(path: String, link_target: Option<String>, meta: &std::fs::Metadata) -> Metadata {
 	let mut output = Metadata{
-		path: path.as_ref().to_string_lossy().to_string(),
+		path: path,
 		size: meta.len(),
 		is_dir: meta.is_dir(),
 		is_file: meta.is_file(),
-		link_target: match meta.file_type().is_symlink() {
-			true => Some(read_link(path.as_ref()).await?.to_string_lossy().to_string()),
-			false => None
-		},
+		link_target: link_target,
 		uid: meta.st_uid(),
 		gid: meta.st_gid(),
 		// If we're on Windows, mode bits don't exist, so just lie.  TODO:  Figure out a decent way to synthesize on Windows.
 		permissions: 0o755,
 		atime: meta.accessed().map(|v| v.into()).unwrap_or(*ZEROTIME),
-		mtime: meta.accessed().map(|v| v.into()).unwrap_or(*ZEROTIME)
+		mtime: meta.modified().map(|v| v.into()).unwrap_or(*ZEROTIME)
 	};
 	if(cfg!(unix)) {
 		output.permissions = meta.permissions().mode();
 	}
-	Ok(output)
+	output
+}
+
+fn convert_statvfs(stats: &Statvfs) -> FsStats /* {{{ */ {
+	// Block counts are in units of the fragment size, not the preferred I/O size
+	let fragment_size = stats.fragment_size() as u64;
+	FsStats{
+		block_size: stats.block_size() as u64,
+		total_bytes: stats.blocks() as u64 * fragment_size,
+		free_bytes: stats.blocks_free() as u64 * fragment_size,
+		available_bytes: stats.blocks_available() as u64 * fragment_size,
+		total_inodes: stats.files() as u64,
+		free_inodes: stats.files_free() as u64,
+		available_inodes: stats.files_available() as u64,
+		name_max: stats.name_max() as u64,
+		read_only: stats.flags().contains(FsFlags::ST_RDONLY)
+	}
+} // }}}
+
+// Runs a blocking call on tokio's blocking pool; unlike block_in_place(), that works on any runtime, including the
+//    current-thread one that #[tokio::test] uses
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> /* {{{ */ {
+	spawn_blocking(f).await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+} // }}}
+
+// Opens `path` as `options` asks, returning whether this call created it.  That's decided by open() itself, with an
+//    exclusive create tried first, since a file that appears between looking for it and opening it would otherwise be
+//    taken for ours.
+fn open_file(path: &Path, options: &OpenOptions) -> io::Result<(std::fs::File, bool)> /* {{{ */ {
+	let open = |create_new: bool| {
+		let mut std_options = std::fs::OpenOptions::new();
+		std_options
+			.read(options.read)
+			.write(options.write)
+			.append(options.append)
+			.truncate(options.truncate)
+			.create_new(create_new);
+		// Passing the mode to open() means the file never exists with looser permissions than requested; the process
+		//    umask may still strip bits from it, so the exact mode is set again once it's open
+		if let Some(permissions) = options.attrs.get_permissions() {
+			std_options.mode(permissions);
+		}
+		std_options.open(path)
+	};
+	if(options.create_new) {
+		return Ok((open(true)?, true));
+	}
+	if(!options.create) {
+		return Ok((open(false)?, false));
+	}
+	loop {
+		match open(true) {
+			Ok(file) => return Ok((file, true)),
+			Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+			Err(e) => return Err(e)
+		};
+		match open(false) {
+			Ok(file) => return Ok((file, false)),
+			// Removed again in between; try creating it once more
+			Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+			Err(e) => return Err(e)
+		};
+	}
+} // }}}
+
+#[derive(Debug)]
+pub struct FilesystemFile {
+	path: PathBuf,
+	fd: Arc<std::fs::File>
+}
+
+impl FilesystemFile {
+	// pread()/pwrite() block, so run them on the blocking pool; that way concurrent requests on one handle overlap
+	async fn blocking<T: Send + 'static>(&self, f: impl FnOnce(&std::fs::File) -> io::Result<T> + Send + 'static) -> Result<T> /* {{{ */ {
+		let fd = self.fd.clone();
+		let result = spawn_blocking(move || f(&fd)).await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+		Ok(result)
+	} // }}}
+}
+
+#[async_trait]
+impl PositionalFile for FilesystemFile {
+	async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>> {
+		self.blocking(move |fd| {
+			let mut data = vec![0u8; len as usize];
+			let mut filled = 0;
+			while(filled < data.len()) {
+				match fd.read_at(&mut data[filled..], offset + filled as u64) {
+					Ok(0) => break,
+					Ok(count) => filled += count,
+					Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+					Err(e) => return Err(e)
+				};
+			}
+			data.truncate(filled);
+			Ok(data)
+		}).await
+	}
+
+	async fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+		let data = data.to_vec();
+		self.blocking(move |fd| fd.write_all_at(&data, offset)).await
+	}
+
+	async fn len(&self) -> Result<u64> {
+		self.blocking(|fd| Ok(fd.metadata()?.len())).await
+	}
+
+	async fn sync(&self) -> Result<()> {
+		self.blocking(|fd| fd.sync_all()).await
+	}
+
+	async fn metadata(&self) -> Result<Metadata> {
+		let meta = self.blocking(|fd| fd.metadata()).await?;
+		Ok(convert_metadata(self.path.to_string_lossy().to_string(), None, &meta))
+	}
+
+	async fn set_metadata(&self, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		let fd = self.fd.clone();
+		run_blocking(move || {
+			let fd = fd.as_raw_fd();
+			if let Some((uid, gid)) = uid_and_gid {
+				let uid = nix::unistd::Uid::from_raw(uid);
+				let gid = nix::unistd::Gid::from_raw(gid);
+				nix::unistd::fchown(fd, Some(uid), Some(gid))?;
+			}
+			if let Some(permissions) = permissions {
+				fchmod(fd, Mode::from_bits_truncate(permissions))?;
+			}
+			if let Some((atime, mtime)) = atime_and_mtime {
+				let atime = TimeSpec::seconds(atime as i64);
+				let mtime = TimeSpec::seconds(mtime as i64);
+				futimens(fd, &atime, &mtime)?;
+			}
+			Ok(())
+		}).await
+	}
+
+	async fn statvfs(&self) -> Result<FsStats> {
+		let fd = self.fd.clone();
+		let stats = run_blocking(move || Ok(fstatvfs(&*fd)?)).await?;
+		Ok(convert_statvfs(&stats))
+	}
 }
 
 #[async_trait]
@@ -79,20 +259,39 @@ impl Backend for Filesystem {
 		Ok(result)
 	}
 
-	async fn open(&self, path: impl PathRef + 'async_trait, read: bool, write: bool, append: bool, create: bool, truncate: bool, create_new: bool) -> Result<OpenFile> {
+	async fn open(&self, path: impl PathRef + 'async_trait, options: OpenOptions) -> Result<OpenFile> {
 		let path = self.full_normalize_path(path)?;
-		let fd = OpenOptions::new()
-			.create(true)
-			.read(read)
-			.write(write)
-			.append(append)
-			.create(create)
-			.truncate(truncate)
-			.create_new(create_new)
-			.open(&path)
-			.await?;
-		let metadata = metadata(&path).await?;
-		Ok(OpenFile::new(metadata, fd))
+		let preallocate = options.preallocate();
+		let attrs = options.attrs.clone();
+		let (fd, created) = {
+			let path = path.clone();
+			run_blocking(move || Ok(open_file(&path, &options)?)).await?
+		};
+		let file = FilesystemFile{path: path, fd: Arc::new(fd)};
+		if let Some(size) = preallocate {
+			// KEEP_SIZE reserves the blocks without changing the file's length, so a short upload doesn't leave trailing zeros
+			let result = file.blocking(move |fd| {
+				fallocate(fd.as_raw_fd(), FallocateFlags::FALLOC_FL_KEEP_SIZE, 0, size as i64).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+			}).await;
+			// Not every filesystem supports it, and the upload works either way
+			if let Err(e) = result {
+				debug!("Failed to preallocate {} bytes for {:?}:  {:?}", size, file.path, e);
+			}
+		}
+		if(created) {
+			// Ownership is left to the server's own credentials; a client may change it afterwards with SETSTAT, if
+			//    it's allowed to
+			let result = file.set_metadata(None, attrs.get_permissions(), attrs.get_atime_mtime()).await;
+			// Don't leave a file behind with a mode the client never asked for
+			if let Err(e) = result {
+				if let Err(e) = remove_file(&file.path).await {
+					warn!("Failed to remove {:?} after setting it up failed:  {:?}", file.path, e);
+				}
+				return Err(e);
+			}
+		}
+		let metadata = file.metadata().await?;
+		Ok(OpenFile::positional(metadata, file))
 	}
 
 	async fn set_metadata(&self, path: impl PathRef + 'async_trait, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
@@ -101,7 +300,8 @@ impl Backend for Filesystem {
 			if(cfg!(unix)) {
 				let uid = nix::unistd::Uid::from_raw(uid);
 				let gid = nix::unistd::Gid::from_raw(gid);
-				tokio::task::block_in_place(|| nix::unistd::chown(&path, Some(uid), Some(gid)))?;
+				let path = path.clone();
+				run_blocking(move || Ok(nix::unistd::chown(&path, Some(uid), Some(gid))?)).await?;
 			} else {
 				eprintln!("!!! Filesystem::set_metadata():  Can't set UID on non-Unix platforms");
 			}
@@ -116,7 +316,7 @@ impl Backend for Filesystem {
 		if let Some((atime, mtime)) = atime_and_mtime {
 			let atime = FileTime::from_unix_time(atime as i64, 0);
 			let mtime = FileTime::from_unix_time(mtime as i64, 0);
-			tokio::task::block_in_place(|| set_file_times(&path, atime, mtime))?;
+			run_blocking(move || Ok(set_file_times(&path, atime, mtime)?)).await?;
 		}
 		Ok(())
 	}
@@ -127,10 +327,16 @@ impl Backend for Filesystem {
 		Ok(())
 	}
 
-	async fn mkdir(&self, path: impl PathRef + 'async_trait) -> Result<()> {
-		let path = self.full_normalize_path(path)?;
-		create_dir(path).await?;
-		Ok(())
+	async fn mkdir(&self, path: impl PathRef + 'async_trait, attrs: FileAttributes) -> Result<()> {
+		let path = path.as_ref().to_path_buf();
+		let full_path = self.full_normalize_path(&path)?;
+		let mut builder = DirBuilder::new();
+		if let Some(permissions) = attrs.get_permissions() {
+			builder.mode(permissions);
+		}
+		run_blocking(move || Ok(builder.create(&full_path)?)).await?;
+		// As with open(), the umask may have stripped bits from the mode given to mkdir()
+		self.set_metadata(&path, attrs.get_uid_gid(), attrs.get_permissions(), attrs.get_atime_mtime()).await
 	}
 
 	async fn rmdir(&self, path: impl PathRef + 'async_trait) -> Result<()> {
@@ -139,13 +345,58 @@ impl Backend for Filesystem {
 		Ok(())
 	}
 
+	async fn realpath(&self, path: impl PathRef + 'async_trait) -> Result<PathBuf> {
+		let path = tokio::fs::canonicalize(self.full_normalize_path(path)?).await?;
+		match path.strip_prefix(&self.root) {
+			Ok(v) => Ok(PathBuf::from("/").join(v)),
+			// A symlink pointing outside of the root
+			Err(_) => Err(Error::InvalidPath)
+		}
+	}
+
+	async fn hardlink(&self, from: impl PathRef + 'async_trait, to: impl PathRef + 'async_trait) -> Result<()> {
+		let from = self.full_normalize_path(from)?;
+		let to = self.full_normalize_path(to)?;
+		hard_link(from, to).await?;
+		Ok(())
+	}
+
+	async fn statvfs(&self, path: impl PathRef + 'async_trait) -> Result<FsStats> {
+		let path = self.full_normalize_path(path)?;
+		let stats = run_blocking(move || Ok(statvfs(&path)?)).await?;
+		Ok(convert_statvfs(&stats))
+	}
+
 	async fn rename(&self, from: impl PathRef + 'async_trait, to: impl PathRef + 'async_trait) -> Result<()> {
 		let from = self.full_normalize_path(from)?;
 		let to = self.full_normalize_path(to)?;
 		// TODO:  This fails across mountpoints; when that happens, manually copy and delete the source
+		run_blocking(move || rename_noreplace(&from, &to)).await
+	}
+
+	async fn posix_rename(&self, from: impl PathRef + 'async_trait, to: impl PathRef + 'async_trait) -> Result<()> {
+		let from = self.full_normalize_path(from)?;
+		let to = self.full_normalize_path(to)?;
 		rename(from, to).await?;
 		Ok(())
 	}
+
+	async fn lock_key(&self, path: impl PathRef + 'async_trait) -> Result<LockKey> {
+		let path = self.full_normalize_path(path)?;
+		match tokio::fs::metadata(&path).await {
+			// Every name for a file, through hard links or symlinks, leads to the same inode
+			Ok(meta) => Ok(LockKey::File{device: meta.st_dev(), inode: meta.st_ino()}),
+			// Not created yet; go by where it will be, with any symlinks in the directories above it resolved
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				let resolved = match (path.parent(), path.file_name()) {
+					(Some(parent), Some(name)) => canonicalize(parent).await.ok().map(|dir| dir.join(name)),
+					_ => None
+				};
+				Ok(LockKey::Path(resolved.unwrap_or(path)))
+			},
+			Err(e) => Err(e.into())
+		}
+	}
 }
 
 impl Filesystem {
@@ -159,9 +410,624 @@ impl Filesystem {
 		let result = self.root.join(self.normalize_path(path.as_ref())?);
 		Ok(result)
 	}
+}
 
-	fn canonicalize(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
-		Ok(self.full_normalize_path(path)?.canonicalize()?)
+// Renames `from` to `to`, failing if `to` exists.  renameat2() checks that atomically; on filesystems that don't
+//    support RENAME_NOREPLACE, fall back to checking first, which leaves a window for a racing create to be replaced.
+fn rename_noreplace(from: &Path, to: &Path) -> Result<()> /* {{{ */ {
+	// Renaming a path to itself is a successful no-op for rename(), but RENAME_NOREPLACE sees the target existing
+	if(from == to) {
+		std::fs::symlink_metadata(from)?;
+		return Ok(());
+	}
+	let c_from = CString::new(from.as_os_str().as_bytes()).map_err(|_| Error::InvalidPath)?;
+	let c_to = CString::new(to.as_os_str().as_bytes()).map_err(|_| Error::InvalidPath)?;
+	let result = unsafe {
+		libc::syscall(libc::SYS_renameat2, libc::AT_FDCWD, c_from.as_ptr(), libc::AT_FDCWD, c_to.as_ptr(), RENAME_NOREPLACE)
+	};
+	match Errno::result(result) {
+		Ok(_) => Ok(()),
+		Err(nix::Error::Sys(Errno::EINVAL)) | Err(nix::Error::Sys(Errno::ENOSYS)) => {
+			if(std::fs::symlink_metadata(to).is_ok()) {
+				return Err(io::Error::from_raw_os_error(libc::EEXIST).into());
+			}
+			std::fs::rename(from, to)?;
+			Ok(())
+		},
+		Err(e) => Err(e.into())
+	}
+} // }}}
+
+#[cfg(test)]
+mod tests {
+	use std::fs::Permissions;
+	use std::os::unix::fs::MetadataExt;
+	use std::os::unix::fs::PermissionsExt;
+	use std::path::Path;
+	use std::path::PathBuf;
+	use std::sync::Arc;
+	use std::time::Duration;
+
+	use tokio::io::AsyncReadExt;
+	use tokio::io::AsyncWriteExt;
+	use tokio::task::JoinHandle;
+
+	use anyhow::Error;
+
+	use filetime::FileTime;
+	use filetime::set_file_times;
+
+	use sftp_protocol::common::FileAttributes;
+	use sftp_protocol::stream::packet::open::OpenFlags;
+	use sftp_protocol::stream::packet::read::Read;
+	use sftp_protocol::stream::packet::stat::Stat;
+	use sftp_protocol::stream::packet::status::StatusType;
+	use sftp_protocol::stream::packet::write::Write;
+	use sftp_protocol::stream::packet::PayloadTrait;
+	use sftp_protocol::Payload;
+	use sftp_server::Server;
+	use sftp_server::SessionContext;
+	use sftp_server::backend::AtomicUploadLayer;
+	use sftp_server::backend::Backend;
+	use sftp_server::backend::BackendBuilder;
+	use sftp_server::backend::DynBackend;
+	use sftp_server::backend::OnDisconnect;
+	use sftp_server::backend::OpenOptions;
+	use sftp_server::backend::atomic_upload::temp_path;
+	use sftp_server::config::Buffering;
+	use sftp_server::config::Config;
+	use sftp_server::config::ModePolicy;
+	use sftp_server::config::Recording;
+	use sftp_server::record::Record;
+	use sftp_server::scp::Command;
+	use sftp_server::testing::TestClient;
+	use sftp_server::transport;
+	use sftp_server::transport::PIPE_CAPACITY;
+	use sftp_server::transport::Pipe;
+
+	use super::Filesystem;
+
+	sftp_conformance::backend_conformance!({
+		let root = std::env::temp_dir().join(format!("sftp-filesystem-conformance-{}", std::process::id()));
+		std::fs::create_dir_all(&root).expect("Failed to create the conformance root");
+		Filesystem::new(&root).expect("Failed to open the conformance root")
+	});
+
+	// An empty directory of the test's own
+	fn scratch(name: &str) -> PathBuf /* {{{ */ {
+		let root = std::env::temp_dir().join(format!("sftp-filesystem-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&root);
+		std::fs::create_dir_all(&root).expect("Failed to create the test's root");
+		root
+	} // }}}
+
+	// A server over a scratch directory
+	fn server(name: &str) -> Server /* {{{ */ {
+		Server::new(Filesystem::new(&scratch(name)).expect("Failed to open the test's root"), 0)
+	} // }}}
+
+	// Plain #[tokio::test] runs on the current-thread scheduler, which is also what keeps Filesystem honest about
+	//    never calling block_in_place()
+	#[tokio::test]
+	async fn put_get_ls() -> Result<(), Error> {
+		let mut client = TestClient::connect(&server("put-get-ls")).await?;
+		client.mkdir("/dir").await?;
+		client.put("/dir/small.txt", b"hello").await?;
+		// Spans several of put()'s and get()'s chunks, with a partial one at the end
+		let large = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		client.put("/dir/large.bin", &large).await?;
+		assert_eq!(client.get("/dir/small.txt").await?, b"hello");
+		assert_eq!(client.get("/dir/large.bin").await?, large);
+		let mut names = client.ls("/dir").await?;
+		names.sort();
+		assert_eq!(names, vec!["large.bin", "small.txt"]);
+		client.finish().await
 	}
-}
 
+	#[tokio::test]
+	async fn buffered_put_get() -> Result<(), Error> {
+		let mut config = Config::default();
+		config.buffering = Buffering{read_ahead: 64 * 1024, write_behind: 64 * 1024};
+		let mut client = TestClient::connect(&server("buffered").with_config(config)).await?;
+		let large = (0..300_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		client.put("/large.bin", &large).await?;
+		assert_eq!(client.get("/large.bin").await?, large);
+		client.finish().await
+	}
+
+	#[tokio::test]
+	async fn locks_follow_the_file() -> Result<(), Error> {
+		let server = server("locks");
+		let mut writer = TestClient::connect(&server).await?;
+		let mut other = TestClient::connect(&server).await?;
+		// Locked by its path until the open creates it, and by the file itself from then on
+		let handle = writer.open("/a.txt", OpenFlags::Write | OpenFlags::Create).await?;
+		assert!(other.open("/a.txt", OpenFlags::Write).await.is_err());
+		assert!(other.rename("/a.txt", "/b.txt").await.is_err());
+		assert!(other.remove("/a.txt").await.is_err());
+		// Reading it is still allowed by default
+		let reader = other.open("/a.txt", OpenFlags::Read).await?;
+		other.close(reader).await?;
+		writer.close(handle).await?;
+		other.rename("/a.txt", "/b.txt").await?;
+		let handle = writer.open("/b.txt", OpenFlags::Write).await?;
+		assert!(other.rename("/b.txt", "/a.txt").await.is_err());
+		writer.close(handle).await?;
+		other.rename("/b.txt", "/a.txt").await?;
+		writer.finish().await?;
+		other.finish().await
+	}
+
+	#[tokio::test]
+	async fn start_dir_escapes() -> Result<(), Error> {
+		let root = scratch("start-dir");
+		std::fs::create_dir_all(root.join("home/alice/in"))?;
+		std::fs::create_dir_all(root.join("100%/alice"))?;
+		std::fs::write(root.join("home/alice/in/a.txt"), b"in")?;
+		std::fs::write(root.join("home/alice/top.txt"), b"top")?;
+		std::fs::write(root.join("100%/alice/b.txt"), b"percent")?;
+		for (start_dir, relative, expected) in &[("%d/in", "a.txt", &b"in"[..]), ("in", "a.txt", &b"in"[..]), ("/100%%/%u", "b.txt", &b"percent"[..])] {
+			let mut config = Config::default();
+			config.start_dir = Some(start_dir.to_string());
+			let server = Server::new(Filesystem::new(&root)?, 0).with_config(config);
+			let ctx = SessionContext{user: Some("alice".to_string()), home: Some("/home/alice".to_string()), ..Default::default()};
+			let mut client = TestClient::connect_as(&server, ctx).await?;
+			assert_eq!(&client.get(relative).await?[..], *expected, "{}", start_dir);
+			// The home directory is still where "~" is
+			assert_eq!(client.get("~/top.txt").await?, b"top");
+			client.finish().await?;
+		}
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn raw_send_and_expect() -> Result<(), Error> {
+		let mut client = TestClient::connect_raw(&server("raw"), SessionContext::default());
+		// INIT for version 3, encoded by hand:  length, type, version
+		client.send_raw(&[0, 0, 0, 5, 1, 0, 0, 0, 3]).await?;
+		match client.recv().await?.payload {
+			Payload::Version(v) => assert_eq!(v.version, 3),
+			other => panic!("Expected VERSION, got {:?}", other)
+		};
+		let id = client.next_id();
+		client.send(&Stat{id: id, path: "/".to_string()}.into_packet()).await?;
+		match client.expect(id).await?.payload {
+			Payload::Attrs(_) => (),
+			other => panic!("Expected ATTRS, got {:?}", other)
+		};
+		client.finish().await
+	}
+
+	#[tokio::test]
+	async fn stat_missing_file() -> Result<(), Error> {
+		let mut client = TestClient::connect(&server("stat-missing")).await?;
+		let id = client.next_id();
+		client.expect_status(Stat{id: id, path: "/missing".to_string()}, StatusType::NoSuchFile).await?;
+		assert!(client.stat("/missing").await.is_err());
+		client.finish().await
+	}
+
+	#[tokio::test]
+	async fn pipelined_writes() -> Result<(), Error> {
+		let mut client = TestClient::connect(&server("pipelined")).await?;
+		let handle = client.open("/pipelined.bin", OpenFlags::Write | OpenFlags::Create).await?;
+		let mut ids = Vec::new();
+		for i in 0..16u8 {
+			let id = client.next_id();
+			client.send(&Write{id: id, handle: handle, offset: i as u64 * 1024, data: vec![i; 1024]}.into_packet()).await?;
+			ids.push(id);
+		}
+		// Collected newest first, so every earlier response has to be stashed by expect() on the way
+		for id in ids.into_iter().rev() {
+			match client.expect(id).await?.payload {
+				Payload::Status(status) if status.status as u32 == StatusType::OK as u32 => (),
+				other => panic!("Expected OK for write {}, got {:?}", id, other)
+			};
+		}
+		client.close(handle).await?;
+		let expected = (0..16u8).flat_map(|i| vec![i; 1024]).collect::<Vec<_>>();
+		assert_eq!(client.get("/pipelined.bin").await?, expected);
+		client.finish().await
+	}
+
+	#[tokio::test]
+	async fn flood_is_held_back() -> Result<(), Error> {
+		let mut config = Config::default();
+		config.limits.max_in_flight = 4;
+		let mut client = TestClient::connect(&server("flood").with_config(config)).await?;
+		client.put("/flood.bin", b"x").await?;
+		let handle = client.open("/flood.bin", OpenFlags::Read).await?;
+		// Requests whose responses are never read:  once the responses fill their side of the pipe, the session stops
+		//    taking requests, and then the requests fill theirs and the client has to wait
+		let mut sent = Vec::new();
+		loop {
+			let id = client.next_id();
+			let packet = Read{id: id, handle: handle, offset: 0, len: 1}.into_packet();
+			match tokio::time::timeout(Duration::from_millis(200), client.send(&packet)).await {
+				Ok(result) => result?,
+				Err(_) => break
+			};
+			sent.push(id);
+			assert!(sent.len() < 1_000_000, "Session never pushed back on the client");
+		}
+		assert!(sent.len() > 4, "Session pushed back after only {} requests", sent.len());
+		assert!(client.backlog() < PIPE_CAPACITY + 1024, "{} bytes of requests buffered", client.backlog());
+		// Nothing was lost along the way
+		for id in sent {
+			match client.expect(id).await?.payload {
+				Payload::Data(_) => (),
+				other => panic!("Expected DATA for read {}, got {:?}", id, other)
+			};
+		}
+		client.close(handle).await?;
+		client.finish().await
+	}
+
+	// The one recording in `dir`, once its writer has finished with it
+	async fn recording(dir: &Path) -> Result<Vec<u8>, Error> /* {{{ */ {
+		for _ in 0..500 {
+			if let Some(entry) = std::fs::read_dir(dir)?.next() {
+				let bytes = std::fs::read(entry?.path())?;
+				let mut input = &bytes[..];
+				let mut ended = false;
+				while let Some(record) = Record::read(&mut input)? {
+					ended |= matches!(record, Record::End{..});
+				}
+				if(ended) {
+					return Ok(bytes);
+				}
+			}
+			tokio::time::delay_for(Duration::from_millis(10)).await;
+		}
+		Err(Error::msg("The recording was never finished"))
+	} // }}}
+
+	#[tokio::test]
+	async fn record_and_replay() -> Result<(), Error> {
+		let dir = scratch("recordings");
+		let mut config = Config::default();
+		config.recording = Some(Recording{dir: dir.clone(), redact_data: false});
+		let mut client = TestClient::connect(&server("recorded").with_config(config)).await?;
+		client.put("/file.txt", b"hello").await?;
+		assert_eq!(client.get("/file.txt").await?, b"hello");
+		// Two handles at once, each of which has to be mapped to its own replacement
+		let first = client.open("/file.txt", OpenFlags::Read).await?;
+		let second = client.open("/file.txt", OpenFlags::Read).await?;
+		assert_eq!(client.read(second, 1, 4).await?, Some(b"ello".to_vec()));
+		assert_eq!(client.read(first, 0, 1).await?, Some(b"h".to_vec()));
+		client.close(first).await?;
+		client.close(second).await?;
+		// A STAT without its path, which is rejected without being parsed but still answered
+		let id = client.next_id();
+		let mut stat = vec![0, 0, 0, 5, 17];
+		stat.extend_from_slice(&id.to_be_bytes());
+		client.send_raw(&stat).await?;
+		match client.expect(id).await?.payload {
+			Payload::Status(status) => assert_eq!(status.status as u32, StatusType::BadMessage as u32),
+			other => panic!("Expected STATUS, got {:?}", other)
+		};
+		assert!(client.open("/missing.txt", OpenFlags::Read).await.is_err());
+		client.finish().await?;
+		let recording = recording(&dir).await?;
+
+		let report = server("replayed").replay(&recording[..]).await?;
+		assert_eq!(report.sessions, 1);
+		assert_eq!(report.rejected, 1);
+		assert!(report.mismatches.is_empty(), "{}", report);
+
+		// Where the replay goes differently, only the request that differs is reported
+		let root = scratch("replayed-differently");
+		std::fs::write(root.join("missing.txt"), b"")?;
+		let report = Server::new(Filesystem::new(&root)?, 0).replay(&recording[..]).await?;
+		assert_eq!(report.mismatches.len(), 1, "{}", report);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn open_sets_up_only_what_it_creates() -> Result<(), Error> {
+		let root = scratch("open-create");
+		let backend = Filesystem::new(&root)?;
+		std::fs::write(root.join("existing"), b"")?;
+		std::fs::set_permissions(root.join("existing"), Permissions::from_mode(0o600))?;
+		let mut attrs = FileAttributes::new();
+		attrs.set_permissions(0o4755);
+		attrs.set_uid_gid(nix::unistd::getuid().as_raw() + 1, nix::unistd::getgid().as_raw() + 1);
+		let options = OpenOptions{
+			write: true,
+			create: true,
+			attrs: ModePolicy::default().apply(&attrs, false),
+			..Default::default()
+		};
+		backend.open("/existing", options.clone()).await?.close().await?;
+		backend.open("/new", options).await?.close().await?;
+		assert_eq!(std::fs::metadata(root.join("existing"))?.mode() & 0o7777, 0o600);
+		let created = std::fs::metadata(root.join("new"))?;
+		// The setuid bit isn't allowed by default, and the client's owner is ignored
+		assert_eq!(created.mode() & 0o7777, 0o755);
+		assert_eq!(created.uid(), nix::unistd::getuid().as_raw());
+		assert_eq!(created.gid(), nix::unistd::getgid().as_raw());
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn open_exclusive() -> Result<(), Error> {
+		let root = scratch("open-exclusive");
+		let backend = Filesystem::new(&root)?;
+		std::fs::write(root.join("existing"), b"data")?;
+		let options = OpenOptions{write: true, create_new: true, ..Default::default()};
+		assert!(backend.open("/existing", options.clone()).await.is_err());
+		assert_eq!(std::fs::read(root.join("existing"))?, b"data");
+		backend.open("/new", options).await?.close().await?;
+		assert!(root.join("new").exists());
+		Ok(())
+	}
+
+	// A scratch directory with an "up" directory in it, behind atomic uploads there
+	fn atomic(name: &str, on_disconnect: OnDisconnect) -> (PathBuf, Arc<dyn DynBackend>) /* {{{ */ {
+		let root = scratch(name);
+		std::fs::create_dir(root.join("up")).expect("Failed to create the upload directory");
+		let backend = BackendBuilder::new(Filesystem::new(&root).expect("Failed to open the test's root"))
+			.layer(AtomicUploadLayer::new().path("/up", on_disconnect))
+			.build();
+		(root, backend)
+	} // }}}
+
+	fn upload() -> OpenOptions /* {{{ */ {
+		OpenOptions{write: true, create: true, truncate: true, ..Default::default()}
+	} // }}}
+
+	#[tokio::test]
+	async fn atomic_upload_renamed_on_close() -> Result<(), Error> {
+		let (root, backend) = atomic("atomic-close", OnDisconnect::Discard);
+		let temp = temp_path(Path::new("/up/a.txt")).unwrap();
+		let file = backend.open(Path::new("/up/a.txt"), upload()).await?;
+		file.write_at(0, b"data").await?;
+		assert!(!root.join("up/a.txt").exists());
+		assert!(root.join(temp.strip_prefix("/")?).exists());
+		// Hidden while it's in progress
+		assert!(backend.metadata(&temp).await.is_err());
+		assert!(backend.list(Path::new("/up")).await?.is_empty());
+		assert!(backend.open(&temp, OpenOptions{read: true, ..Default::default()}).await.is_err());
+		file.close().await?;
+		assert_eq!(std::fs::read(root.join("up/a.txt"))?, b"data");
+		assert!(!root.join(temp.strip_prefix("/")?).exists());
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn atomic_upload_discarded_on_disconnect() -> Result<(), Error> {
+		let (root, backend) = atomic("atomic-discard", OnDisconnect::Discard);
+		std::fs::write(root.join("up/a.txt"), b"old")?;
+		let file = backend.open(Path::new("/up/a.txt"), upload()).await?;
+		file.write_at(0, b"partial").await?;
+		file.abort().await?;
+		assert_eq!(std::fs::read(root.join("up/a.txt"))?, b"old");
+		assert_eq!(std::fs::read_dir(root.join("up"))?.count(), 1);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn atomic_upload_resumed() -> Result<(), Error> {
+		let (root, backend) = atomic("atomic-resume", OnDisconnect::Keep(Duration::from_secs(60)));
+		let file = backend.open(Path::new("/up/a.txt"), upload()).await?;
+		file.write_at(0, b"abc").await?;
+		file.abort().await?;
+		assert!(!root.join("up/a.txt").exists());
+		// What's been sent so far, so the client knows where to carry on from
+		assert_eq!(backend.metadata(Path::new("/up/a.txt")).await?.size, 3);
+		let file = backend.open(Path::new("/up/a.txt"), OpenOptions{write: true, create: true, ..Default::default()}).await?;
+		file.write_at(3, b"def").await?;
+		file.close().await?;
+		assert_eq!(std::fs::read(root.join("up/a.txt"))?, b"abcdef");
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn atomic_upload_expires() -> Result<(), Error> {
+		let (root, backend) = atomic("atomic-expire", OnDisconnect::Keep(Duration::from_secs(60)));
+		let temp = root.join(temp_path(Path::new("/up/a.txt")).unwrap().strip_prefix("/")?);
+		std::fs::write(&temp, b"stale")?;
+		set_file_times(&temp, FileTime::from_unix_time(1_000_000_000, 0), FileTime::from_unix_time(1_000_000_000, 0))?;
+		assert!(backend.metadata(Path::new("/up/a.txt")).await.is_err());
+		assert!(!temp.exists());
+		// So a new upload starts from scratch
+		let file = backend.open(Path::new("/up/a.txt"), OpenOptions{write: true, create: true, ..Default::default()}).await?;
+		file.write_at(0, b"new").await?;
+		file.close().await?;
+		assert_eq!(std::fs::read(root.join("up/a.txt"))?, b"new");
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn atomic_upload_hides_only_its_own_paths() -> Result<(), Error> {
+		let (root, backend) = atomic("atomic-paths", OnDisconnect::Discard);
+		let name = temp_path(Path::new("/a.txt")).unwrap();
+		std::fs::write(root.join(name.strip_prefix("/")?), b"not an upload")?;
+		std::fs::write(root.join("up").join(name.strip_prefix("/")?), b"an upload")?;
+		let names: Vec<_> = backend.list(Path::new("/")).await?.into_iter().map(|m| m.path).collect();
+		assert!(names.contains(&name.strip_prefix("/")?.to_string_lossy().to_string()));
+		assert!(backend.metadata(&name).await.is_ok());
+		backend.delete_file(&name).await?;
+		assert!(backend.list(Path::new("/up")).await?.is_empty());
+		assert!(backend.metadata(&Path::new("/up").join(name.strip_prefix("/")?)).await.is_err());
+		Ok(())
+	}
+
+	// Starts an SCP transfer of `command` against `root`, returning the client's end of it and the transfer itself
+	fn scp(root: &Path, command: &str) -> (Pipe, JoinHandle<Result<(), Error>>) /* {{{ */ {
+		let server = Server::new(Filesystem::new(root).expect("Failed to open the test's root"), 0);
+		let command = Command::parse(command).expect("Not an scp command");
+		let (local, remote) = transport::pipe();
+		(remote, tokio::spawn(async move { server.serve_scp(local, SessionContext::default(), &command).await }))
+	} // }}}
+
+	async fn scp_line(io: &mut Pipe) -> Result<String, Error> /* {{{ */ {
+		let mut line = Vec::new();
+		loop {
+			match io.read_u8().await? {
+				b'\n' => return Ok(String::from_utf8_lossy(&line).to_string()),
+				byte => line.push(byte)
+			};
+		}
+	} // }}}
+
+	// The server's reply to something the client sent:  0 to carry on, or 1 or 2 with the message that came with it
+	async fn scp_reply(io: &mut Pipe) -> Result<(u8, String), Error> /* {{{ */ {
+		match io.read_u8().await? {
+			0 => Ok((0, String::new())),
+			code => Ok((code, scp_line(io).await?))
+		}
+	} // }}}
+
+	async fn scp_ok(io: &mut Pipe) -> Result<(), Error> /* {{{ */ {
+		match scp_reply(io).await? {
+			(0, _) => Ok(()),
+			(code, message) => Err(Error::msg(format!("scp replied {}:  {}", code, message)))
+		}
+	} // }}}
+
+	#[tokio::test]
+	async fn scp_upload() -> Result<(), Error> {
+		let root = scratch("scp-upload");
+		let (mut io, transfer) = scp(&root, "scp -t /upload.txt");
+		scp_ok(&mut io).await?;
+		// The target isn't a directory, so the name in the record doesn't matter
+		io.write_all(b"C0644 5 other.txt\n").await?;
+		scp_ok(&mut io).await?;
+		io.write_all(b"hello\0").await?;
+		scp_ok(&mut io).await?;
+		drop(io);
+		transfer.await??;
+		assert_eq!(std::fs::read(root.join("upload.txt"))?, b"hello");
+		assert!(!root.join("other.txt").exists());
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn scp_upload_one_file_per_target() -> Result<(), Error> {
+		let root = scratch("scp-one-file");
+		let (mut io, transfer) = scp(&root, "scp -t /upload.txt");
+		scp_ok(&mut io).await?;
+		io.write_all(b"C0644 5 first.txt\n").await?;
+		scp_ok(&mut io).await?;
+		io.write_all(b"first\0").await?;
+		scp_ok(&mut io).await?;
+		io.write_all(b"C0644 6 second.txt\n").await?;
+		let (code, message) = scp_reply(&mut io).await?;
+		assert_eq!(code, 2);
+		assert!(message.contains("Not a directory"), "{}", message);
+		assert!(transfer.await?.is_err());
+		assert_eq!(std::fs::read(root.join("upload.txt"))?, b"first");
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn scp_upload_tree() -> Result<(), Error> {
+		let root = scratch("scp-upload-tree");
+		let (mut io, transfer) = scp(&root, "scp -r -t /");
+		scp_ok(&mut io).await?;
+		for record in &[&b"D0755 0 dir\n"[..], b"C0644 3 a.txt\n", b"abc\0", b"D0700 0 sub\n", b"C0600 0 empty\n", b"\0", b"E\n", b"E\n"] {
+			io.write_all(record).await?;
+			scp_ok(&mut io).await?;
+		}
+		drop(io);
+		transfer.await??;
+		assert_eq!(std::fs::read(root.join("dir/a.txt"))?, b"abc");
+		assert_eq!(std::fs::read(root.join("dir/sub/empty"))?, b"");
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn scp_upload_preserves_times() -> Result<(), Error> {
+		let root = scratch("scp-upload-times");
+		let (mut io, transfer) = scp(&root, "scp -p -t /");
+		scp_ok(&mut io).await?;
+		// mtime first, then atime
+		for record in &[&b"T1000000000 0 1100000000 0\n"[..], b"C0640 2 t.txt\n", b"hi\0"] {
+			io.write_all(record).await?;
+			scp_ok(&mut io).await?;
+		}
+		drop(io);
+		transfer.await??;
+		let meta = std::fs::metadata(root.join("t.txt"))?;
+		assert_eq!(meta.mtime(), 1_000_000_000);
+		assert_eq!(meta.atime(), 1_100_000_000);
+		assert_eq!(meta.mode() & 0o7777, 0o640);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn scp_upload_refused() -> Result<(), Error> {
+		let root = scratch("scp-upload-refused");
+		let (mut io, transfer) = scp(&root, "scp -t /missing/x.txt");
+		scp_ok(&mut io).await?;
+		io.write_all(b"C0644 5 x.txt\n").await?;
+		// Refused before any of the contents are sent; the transfer carries on, but ends as a failure
+		let (code, message) = scp_reply(&mut io).await?;
+		assert_eq!(code, 1, "{}", message);
+		drop(io);
+		assert!(transfer.await?.is_err());
+		assert!(!root.join("missing").exists());
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn scp_upload_protocol_errors() -> Result<(), Error> {
+		let root = scratch("scp-protocol-errors");
+		for record in &[&b"E\n"[..], b"C0644 1 ../x\n", b"C0644 1 a/b\n", b"Cgarbage\n", b"Q\n"] {
+			let (mut io, transfer) = scp(&root, "scp -r -t /");
+			scp_ok(&mut io).await?;
+			io.write_all(record).await?;
+			let (code, message) = scp_reply(&mut io).await?;
+			assert_eq!(code, 2, "{:?}:  {}", String::from_utf8_lossy(record), message);
+			assert!(transfer.await?.is_err());
+		}
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn scp_download() -> Result<(), Error> {
+		let root = scratch("scp-download");
+		std::fs::write(root.join("src.txt"), b"hello")?;
+		let (mut io, transfer) = scp(&root, "scp -f /src.txt");
+		io.write_all(b"\0").await?;
+		let record = scp_line(&mut io).await?;
+		assert!(record.starts_with('C') && record.ends_with(" 5 src.txt"), "{}", record);
+		io.write_all(b"\0").await?;
+		let mut data = [0u8; 5];
+		io.read_exact(&mut data).await?;
+		assert_eq!(&data, b"hello");
+		scp_ok(&mut io).await?;
+		io.write_all(b"\0").await?;
+		transfer.await??;
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn scp_download_tree_with_times() -> Result<(), Error> {
+		let root = scratch("scp-download-tree");
+		std::fs::create_dir(root.join("tree"))?;
+		std::fs::write(root.join("tree/a.txt"), b"abc")?;
+		set_file_times(root.join("tree/a.txt"), FileTime::from_unix_time(1_100_000_000, 0), FileTime::from_unix_time(1_000_000_000, 0))?;
+		set_file_times(root.join("tree"), FileTime::from_unix_time(1_300_000_000, 0), FileTime::from_unix_time(1_200_000_000, 0))?;
+		let (mut io, transfer) = scp(&root, "scp -r -p -f /tree");
+		io.write_all(b"\0").await?;
+		assert_eq!(scp_line(&mut io).await?, "T1200000000 0 1300000000 0");
+		io.write_all(b"\0").await?;
+		assert!(scp_line(&mut io).await?.ends_with(" 0 tree"));
+		io.write_all(b"\0").await?;
+		assert_eq!(scp_line(&mut io).await?, "T1000000000 0 1100000000 0");
+		io.write_all(b"\0").await?;
+		assert!(scp_line(&mut io).await?.ends_with(" 3 a.txt"));
+		io.write_all(b"\0").await?;
+		let mut data = [0u8; 3];
+		io.read_exact(&mut data).await?;
+		assert_eq!(&data, b"abc");
+		scp_ok(&mut io).await?;
+		io.write_all(b"\0").await?;
+		assert_eq!(scp_line(&mut io).await?, "E");
+		io.write_all(b"\0").await?;
+		transfer.await??;
+		Ok(())
+	}
+}
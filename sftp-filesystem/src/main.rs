@@ -2,14 +2,21 @@
 #![allow(non_upper_case_globals)]
 #[macro_use] extern crate async_trait;
 #[macro_use] extern crate lazy_static;
+#[macro_use] extern crate log;
 
 use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::Arc;
 use std::time::Duration;
 
 use tokio::fs::create_dir_all;
+#[cfg(feature = "standalone")]
+use tokio::fs::read_to_string;
+#[cfg(feature = "standalone")]
+use tokio::signal::unix::SignalKind;
+#[cfg(feature = "standalone")]
+use tokio::signal::unix::signal;
 use tokio::task::block_in_place;
 
 use anyhow::Error;
@@ -21,7 +28,18 @@ use thrussh_keys::key::KeyPair;
 use thrussh_keys::PublicKeyBase64;
 
 use sftp_server::Server;
+#[cfg(feature = "standalone")]
+use sftp_server::auth::Credentials;
+#[cfg(feature = "standalone")]
+use sftp_server::ServerBuilder;
+use sftp_server::config::ModePolicy;
+use sftp_server::config::Recording;
+use sftp_server::config::SessionPolicy;
 
+#[cfg(feature = "legacy")]
+mod args;
+#[cfg(feature = "legacy")]
+use args::StdioArgs;
 mod filesystem;
 use filesystem::Filesystem;
 
@@ -32,7 +50,38 @@ struct Config {
 	#[envconfig(from = "DATA_DIR", default = "/tmp/sftp/data")]
 	pub data_dir: PathBuf,
 	#[envconfig(from = "SSH_PORT", default = "2222")]
-	pub port: u16
+	pub port: u16,
+	// Octal, as with umask(1)
+	#[envconfig(from = "UMASK", default = "022")]
+	pub umask: String,
+	// Serve legacy "scp" clients as well as SFTP
+	#[envconfig(from = "SCP", default = "false")]
+	pub scp: bool,
+	// Seconds that open sessions get to finish up after SIGTERM or SIGINT before they're cut off
+	#[envconfig(from = "SHUTDOWN_GRACE", default = "30")]
+	pub shutdown_grace: u64,
+	// Session limits; 0 disables each one.  Timeouts are in seconds.
+	#[envconfig(from = "IDLE_TIMEOUT", default = "0")]
+	pub idle_timeout: u64,
+	#[envconfig(from = "MAX_SESSION_LIFETIME", default = "0")]
+	pub max_session_lifetime: u64,
+	#[envconfig(from = "MAX_SESSIONS_PER_USER", default = "0")]
+	pub max_sessions_per_user: usize,
+	#[envconfig(from = "MAX_SESSIONS_PER_ADDRESS", default = "0")]
+	pub max_sessions_per_address: usize,
+	// Directory to record every session to, for replaying with "sftp-filesystem replay <file>"; empty disables recording
+	#[envconfig(from = "RECORD_DIR", default = "")]
+	pub record_dir: String,
+	#[envconfig(from = "RECORD_REDACT", default = "false")]
+	pub record_redact: bool,
+	// The one user the standalone server lets in, with SFTP_PASSWORD and/or any key in AUTHORIZED_KEYS (by default,
+	//    "authorized_keys" in CONFIG_DIR); the server won't start without at least one of them
+	#[envconfig(from = "SFTP_USER", default = "sftp")]
+	pub user: String,
+	#[envconfig(from = "SFTP_PASSWORD", default = "")]
+	pub password: String,
+	#[envconfig(from = "AUTHORIZED_KEYS", default = "")]
+	pub authorized_keys: String
 }
 
 #[cfg(feature = "standalone")]
@@ -45,7 +94,7 @@ async fn load_or_create_keypair(path_private: impl AsRef<Path>, path_public: imp
 			},
 			Err(_) => {
 				let key = thrussh_keys::key::KeyPair::generate_ed25519().unwrap();
-				let f = OpenOptions::new().create(true).truncate(true).write(true).read(false).open(&path_private)?;
+				let f = OpenOptions::new().create(true).truncate(true).write(true).read(false).mode(0o600).open(&path_private)?;
 				thrussh_keys::encode_pkcs8_pem(&key, f)?;
 				eprintln!("--- created ED25519 keypair and wrote it to {}", path_private.as_ref().to_str().unwrap());
 				let f = OpenOptions::new().create(true).truncate(true).write(true).read(false).open(&path_public)?;
@@ -57,27 +106,107 @@ async fn load_or_create_keypair(path_private: impl AsRef<Path>, path_public: imp
 	})
 } // }}}
 
+#[cfg(feature = "standalone")]
+async fn load_credentials(config: &Config) -> Result<Credentials, Error> /* {{{ */ {
+	let mut credentials = Credentials::new();
+	if(!config.password.is_empty()) {
+		credentials = credentials.password(config.user.as_str(), config.password.as_str());
+	}
+	let path = match config.authorized_keys.is_empty() {
+		true => config.config_dir.join("authorized_keys"),
+		false => PathBuf::from(&config.authorized_keys)
+	};
+	match read_to_string(&path).await {
+		Ok(contents) => credentials = credentials.authorized_keys(config.user.as_str(), &contents),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+		Err(e) => return Err(Error::new(e).context(format!("Failed to read {}", path.display())))
+	};
+	if(credentials.is_empty()) {
+		return Err(Error::msg(format!("Nobody could log in; set SFTP_PASSWORD or add keys to {}", path.display())));
+	}
+	Ok(credentials)
+} // }}}
+
 #[tokio::main]
 async fn main() {
+	let args: Vec<String> = std::env::args().collect();
+	// "replay <file>" runs a session recording against DATA_DIR and reports where the responses differ
+	let replay = match args.get(1).map(String::as_str) {
+		Some("replay") => Some(args.get(2).cloned().expect("Usage:  sftp-filesystem replay <recording>")),
+		_ => None
+	};
+	#[cfg(feature = "legacy")]
+	let stdio_args = match replay {
+		Some(_) => StdioArgs::default(),
+		None => StdioArgs::parse(args).unwrap()
+	};
+	#[cfg(feature = "legacy")]
+	{
+		if let Some(code) = stdio_args.answer_query() {
+			std::process::exit(code);
+		}
+	}
+	#[cfg(feature = "legacy")]
+	env_logger::Builder::from_default_env().filter_level(stdio_args.log_level).init();
+	#[cfg(not(feature = "legacy"))]
 	env_logger::init();
 	let config = Config::init().unwrap();
 	create_dir_all(&config.config_dir).await.unwrap();
 	create_dir_all(&config.data_dir).await.unwrap();
 
+	let mut server_config = sftp_server::config::Config::default();
+	server_config.mode_policy = ModePolicy::new(u32::from_str_radix(&config.umask, 8).unwrap());
+	server_config.scp = config.scp;
+	server_config.sessions = SessionPolicy{
+		idle_timeout: Some(Duration::from_secs(config.idle_timeout)).filter(|d| d.as_secs() > 0),
+		max_lifetime: Some(Duration::from_secs(config.max_session_lifetime)).filter(|d| d.as_secs() > 0),
+		max_per_user: Some(config.max_sessions_per_user).filter(|n| *n > 0),
+		max_per_address: Some(config.max_sessions_per_address).filter(|n| *n > 0)
+	};
+	if(!config.record_dir.is_empty()) {
+		create_dir_all(&config.record_dir).await.unwrap();
+		server_config.recording = Some(Recording{
+			dir: PathBuf::from(&config.record_dir),
+			redact_data: config.record_redact
+		});
+	}
+	#[cfg(feature = "legacy")]
+	stdio_args.apply(&mut server_config);
+
 	let backend = Filesystem::new(&config.data_dir).unwrap();
-	let mut server = Server::new(backend, 0);
+
+	if let Some(path) = replay {
+		let recording = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+		server_config.recording = None;
+		let report = Server::new(backend, 0).with_config(server_config).replay(recording).await.unwrap();
+		println!("{}", report);
+		std::process::exit(if(report.mismatches.is_empty()) { 0 } else { 1 });
+	}
 
 	#[cfg(feature = "standalone")]
 	{
-		let mut ssh_config = thrussh::server::Config::default();
-		ssh_config.connection_timeout = Some(Duration::from_secs(60));
-		ssh_config.auth_rejection_time = Duration::from_secs(1);
-		ssh_config.keys.push(load_or_create_keypair(config.config_dir.join("test.server.key"), config.config_dir.join("test.server.key.pub"), None).await.unwrap());
-		let ssh_config = Arc::new(ssh_config);
-
-		thrussh::server::run(ssh_config, &format!("0.0.0.0:{}", config.port), server).await.unwrap();
+		let key = load_or_create_keypair(config.config_dir.join("test.server.key"), config.config_dir.join("test.server.key.pub"), None).await.unwrap();
+		let credentials = load_credentials(&config).await.unwrap();
+		let handle = ServerBuilder::new()
+			.listen(format!("0.0.0.0:{}", config.port))
+			.host_key(key)
+			.authenticator(credentials)
+			.backend(backend)
+			.config(server_config)
+			.start()
+			.await
+			.unwrap();
+		let mut sigterm = signal(SignalKind::terminate()).unwrap();
+		tokio::select! {
+			_ = sigterm.recv() => info!("Received SIGTERM"),
+			_ = tokio::signal::ctrl_c() => info!("Received SIGINT")
+		};
+		let grace = Duration::from_secs(config.shutdown_grace);
+		info!("Shutting down; giving {} open session(s) up to {:?} to finish", handle.sessions().len(), grace);
+		let summary = handle.shutdown_gracefully(grace).await;
+		eprintln!("--- shut down:  {}", summary);
 	}
 	#[cfg(feature = "legacy")]
-	server.run().await.unwrap();
+	Server::new(backend, 0).with_config(server_config).run().await.unwrap();
 }
 
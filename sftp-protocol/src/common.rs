@@ -6,6 +6,8 @@ use serde::ser::Serialize;
 use serde::ser::Serializer;
 use serde::ser::SerializeStruct;
 
+mod fs_stats;
+pub use fs_stats::FsStats;
 mod metadata;
 pub use metadata::Metadata;
 
@@ -76,6 +78,13 @@ impl FileAttributes {
 		}
 	} // }}}
 
+	pub fn get_size(&self) -> Option<u64> /* {{{ */ {
+		match self.flags.contains(FileAttrFlags::Size) {
+			true => self.size,
+			false => None
+		}
+	} // }}}
+
 	pub fn set_size(&mut self, size: u64) /* {{{ */ {
 		self.flags.set(FileAttrFlags::Size, true);
 		self.size = Some(size);
@@ -0,0 +1,15 @@
+/// Capacity and usage of the filesystem holding a path, as statvfs() reports it.  A backend enforcing per-user quotas
+///    reports the user's limits here rather than the underlying disk's.
+#[derive(Clone, Debug, Default)]
+pub struct FsStats {
+	pub block_size: u64,
+	pub total_bytes: u64,
+	pub free_bytes: u64,
+	// Free space usable by the user; less than free_bytes when some is reserved (e.g. for root)
+	pub available_bytes: u64,
+	pub total_inodes: u64,
+	pub free_inodes: u64,
+	pub available_inodes: u64,
+	pub name_max: u64,
+	pub read_only: bool
+}
@@ -1,3 +1,5 @@
+use crate::stream::packet::status::StatusType;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
 	#[error("error decoding packet header")]
@@ -8,9 +10,30 @@ pub enum Error {
 	Packet,
 	#[error("invalid path")]
 	InvalidPath,
+	#[error("operation unsupported")]
+	Unsupported,
+	#[error("permission denied")]
+	PermissionDenied,
 	#[error("I/O failure")]
 	IO(#[from] std::io::Error),
 	#[error("metadata error")]
 	Metadata(#[from] nix::Error)
 }
 
+impl Error {
+	// The status code a server should send back when a request fails with this error
+	pub fn status_type(&self) -> StatusType /* {{{ */ {
+		match self {
+			Self::Unsupported => StatusType::OpUnsupported,
+			Self::PermissionDenied => StatusType::PermissionDenied,
+			Self::IO(e) => match e.kind() {
+				std::io::ErrorKind::NotFound => StatusType::NoSuchFile,
+				std::io::ErrorKind::PermissionDenied => StatusType::PermissionDenied,
+				_ => StatusType::Failure
+			},
+			Self::Metadata(nix::Error::Sys(nix::errno::Errno::ENOENT)) => StatusType::NoSuchFile,
+			Self::Metadata(nix::Error::Sys(nix::errno::Errno::EACCES)) | Self::Metadata(nix::Error::Sys(nix::errno::Errno::EPERM)) => StatusType::PermissionDenied,
+			_ => StatusType::Failure
+		}
+	} // }}}
+}
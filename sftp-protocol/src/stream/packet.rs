@@ -166,6 +166,38 @@ pub enum Payload {
 }
 
 impl Payload {
+	pub fn request_id(&self) -> Option<u32> /* {{{ */ {
+		match self {
+			Self::Init(_) => None,
+			Self::Version(_) => None,
+			Self::Open(v) => Some(v.id),
+			Self::Close(v) => Some(v.id),
+			Self::Read(v) => Some(v.id),
+			Self::Write(v) => Some(v.id),
+			Self::Lstat(v) => Some(v.id),
+			Self::Fstat(v) => Some(v.id),
+			Self::SetStat(v) => Some(v.id),
+			Self::FSetStat(v) => Some(v.id),
+			Self::OpenDir(v) => Some(v.id),
+			Self::ReadDir(v) => Some(v.id),
+			Self::Remove(v) => Some(v.id),
+			Self::MkDir(v) => Some(v.id),
+			Self::RmDir(v) => Some(v.id),
+			Self::RealPath(v) => Some(v.id),
+			Self::Stat(v) => Some(v.id),
+			Self::Rename(v) => Some(v.id),
+			Self::ReadLink(v) => Some(v.id),
+			Self::Symlink(v) => Some(v.id),
+			Self::Status(v) => Some(v.id),
+			Self::Handle(v) => Some(v.id),
+			Self::Data(v) => Some(v.id),
+			Self::Name(v) => Some(v.id),
+			Self::Attrs(v) => Some(v.id),
+			Self::Extended(v) => Some(v.id),
+			Self::ExtendedReply(v) => Some(v.id)
+		}
+	} // }}}
+
 	pub fn init(version: u32, extension_data: Vec<u8>) -> Self {
 		Self::Init(Init{
 			version: version,
@@ -217,7 +249,13 @@ impl Payload {
 		}
 	}
 
-	// TODO:  Configurable limit for size
+	pub fn data(id: u32, data: Vec<u8>) -> Data {
+		Data{
+			id: id,
+			data: data
+		}
+	}
+
 	pub fn data_with_size(id: u32, size: u32) -> Data {
 		Data{
 			id: id,
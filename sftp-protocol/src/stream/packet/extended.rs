@@ -1,3 +1,4 @@
+use crate::common::FsStats;
 use super::kind::PacketType;
 use super::PayloadTrait;
 
@@ -8,14 +9,15 @@ pub struct Request {
 	#[nom(Parse(crate::util::parse_string))]
 	#[serde(serialize_with = "crate::util::str_with_u32_length")]
 	pub request: String,
-	#[serde(serialize_with = "crate::util::vec_with_u32_length")]
+	// Request-specific, and runs to the end of the packet
+	#[serde(serialize_with = "crate::util::vec_u8_as_slice")]
 	pub data: Vec<u8>
 }
 
 impl PayloadTrait for Request {
 	const Type: PacketType = PacketType::Extended;
 	fn binsize(&self) -> u32 {
-		4 + (4 + self.request.len() as u32) + (4 + self.data.len() as u32)
+		4 + (4 + self.request.len() as u32) + self.data.len() as u32
 	}
 }
 
@@ -23,14 +25,137 @@ impl PayloadTrait for Request {
 #[nom(BigEndian)]
 pub struct Response {
 	pub id: u32,
-	#[serde(serialize_with = "crate::util::vec_with_u32_length")]
+	// Reply-specific, and runs to the end of the packet
+	#[serde(serialize_with = "crate::util::vec_u8_as_slice")]
 	pub data: Vec<u8>
 }
 
 impl PayloadTrait for Response {
 	const Type: PacketType = PacketType::ExtendedReply;
 	fn binsize(&self) -> u32 {
-		4 + (4 + self.data.len() as u32)
+		4 + self.data.len() as u32
 	}
 }
 
+
+// Bodies of the extension requests the server understands, parsed from `Request::data` once `Request::request` is known
+
+#[derive(Debug, Nom)]
+#[nom(BigEndian)]
+pub struct Fsync {
+	#[nom(Parse(crate::util::parse_uuid))]
+	pub handle: uuid::Uuid
+}
+
+#[derive(Debug, Nom)]
+#[nom(BigEndian)]
+pub struct PosixRename {
+	#[nom(Parse(crate::util::parse_string))]
+	pub oldpath: String,
+	#[nom(Parse(crate::util::parse_string))]
+	pub newpath: String
+}
+
+// hardlink@openssh.com
+#[derive(Debug, Nom)]
+#[nom(BigEndian)]
+pub struct Hardlink {
+	#[nom(Parse(crate::util::parse_string))]
+	pub oldpath: String,
+	#[nom(Parse(crate::util::parse_string))]
+	pub newpath: String
+}
+
+// statvfs@openssh.com
+#[derive(Debug, Nom)]
+#[nom(BigEndian)]
+pub struct Statvfs {
+	#[nom(Parse(crate::util::parse_string))]
+	pub path: String
+}
+
+// fstatvfs@openssh.com
+#[derive(Debug, Nom)]
+#[nom(BigEndian)]
+pub struct Fstatvfs {
+	#[nom(Parse(crate::util::parse_uuid))]
+	pub handle: uuid::Uuid
+}
+
+// space-available, from draft-ietf-secsh-filexfer-13
+#[derive(Debug, Nom)]
+#[nom(BigEndian)]
+pub struct SpaceAvailable {
+	#[nom(Parse(crate::util::parse_string))]
+	pub path: String
+}
+
+
+// Reply bodies, serialized into `Response::data`
+
+bitflags! {
+	#[derive(Default)]
+	pub struct StatvfsFlags: u64 {
+		const ReadOnly = 0x00000001;
+		const NoSuid = 0x00000002;
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatvfsReply {
+	pub bsize: u64,
+	pub frsize: u64,
+	pub blocks: u64,
+	pub bfree: u64,
+	pub bavail: u64,
+	pub files: u64,
+	pub ffree: u64,
+	pub favail: u64,
+	pub fsid: u64,
+	pub flag: u64,
+	pub namemax: u64
+}
+
+impl From<&FsStats> for StatvfsReply {
+	fn from(stats: &FsStats) -> Self {
+		let block_size = stats.block_size.max(1);
+		let mut flags = StatvfsFlags::default();
+		flags.set(StatvfsFlags::ReadOnly, stats.read_only);
+		Self{
+			bsize: block_size,
+			frsize: block_size,
+			blocks: stats.total_bytes / block_size,
+			bfree: stats.free_bytes / block_size,
+			bavail: stats.available_bytes / block_size,
+			files: stats.total_inodes,
+			ffree: stats.free_inodes,
+			favail: stats.available_inodes,
+			fsid: 0,
+			flag: flags.bits(),
+			namemax: stats.name_max
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct SpaceAvailableReply {
+	pub bytes_on_device: u64,
+	pub unused_bytes_on_device: u64,
+	pub bytes_available_to_user: u64,
+	pub unused_bytes_available_to_user: u64,
+	pub bytes_per_allocation_unit: u32
+}
+
+impl From<&FsStats> for SpaceAvailableReply {
+	fn from(stats: &FsStats) -> Self {
+		// Space reserved for someone else counts against what the user could ever have, not just what's free now
+		let reserved = stats.free_bytes.saturating_sub(stats.available_bytes);
+		Self{
+			bytes_on_device: stats.total_bytes,
+			unused_bytes_on_device: stats.free_bytes,
+			bytes_available_to_user: stats.total_bytes.saturating_sub(reserved),
+			unused_bytes_available_to_user: stats.available_bytes,
+			bytes_per_allocation_unit: stats.block_size.min(u32::MAX as u64) as u32
+		}
+	}
+}
@@ -16,3 +16,20 @@ impl PayloadTrait for Version {
 	}
 }
 
+
+impl Version {
+	// Builds a VERSION advertising the given (name, version) extension pairs
+	pub fn with_extensions(version: u32, extensions: &[(&str, &str)]) -> Self {
+		let mut extension_data = Vec::new();
+		for (name, data) in extensions {
+			for s in &[name, data] {
+				extension_data.extend_from_slice(&(s.len() as u32).to_be_bytes());
+				extension_data.extend_from_slice(s.as_bytes());
+			}
+		}
+		Self{
+			version: version,
+			extension_data: extension_data
+		}
+	}
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use thrussh_keys::PublicKeyBase64;
+use thrussh_keys::key::PublicKey;
+
+/// Decides which SSH logins are accepted.  Every method refuses by default, so implement the ones you support.
+pub trait Authenticator : Send + Sync {
+	fn password(&self, _user: &str, _password: &str) -> bool {
+		false
+	}
+
+	fn public_key(&self, _user: &str, _key: &PublicKey) -> bool {
+		false
+	}
+}
+
+// Refuses everyone; what a `Server` uses until it's given an authenticator
+impl Authenticator for () {}
+
+/// Accepts any user with any password or key; only suitable for testing, and never used unless asked for
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AcceptAll;
+
+impl Authenticator for AcceptAll {
+	fn password(&self, _user: &str, _password: &str) -> bool {
+		true
+	}
+
+	fn public_key(&self, _user: &str, _key: &PublicKey) -> bool {
+		true
+	}
+}
+
+/// Accepts the passwords and public keys it's been given for each user, and nothing else
+#[derive(Clone, Debug, Default)]
+pub struct Credentials {
+	passwords: HashMap<String, String>,
+	// Keys in their base64 wire encoding, as they appear in authorized_keys files
+	keys: HashMap<String, HashSet<String>>
+}
+
+impl Credentials {
+	pub fn new() -> Self /* {{{ */ {
+		Self::default()
+	} // }}}
+
+	// Replaces any password already set for `user`
+	pub fn password(mut self, user: impl Into<String>, password: impl Into<String>) -> Self /* {{{ */ {
+		self.passwords.insert(user.into(), password.into());
+		self
+	} // }}}
+
+	pub fn public_key(mut self, user: impl Into<String>, key: &PublicKey) -> Self /* {{{ */ {
+		self.keys.entry(user.into()).or_default().insert(key.public_key_base64());
+		self
+	} // }}}
+
+	/// Adds every key in an OpenSSH authorized_keys file for `user`.  Options in front of a key are ignored, and lines
+	///    without a key this server understands are skipped with a warning.
+	pub fn authorized_keys(mut self, user: impl Into<String>, contents: &str) -> Self /* {{{ */ {
+		let user = user.into();
+		for (i, line) in contents.lines().enumerate() {
+			let line = line.trim();
+			if(line.is_empty() || line.starts_with('#')) {
+				continue;
+			}
+			match line.split_whitespace().find_map(|token| thrussh_keys::parse_public_key_base64(token).ok()) {
+				Some(key) => self = self.public_key(user.as_str(), &key),
+				None => warn!("Skipping line {} of {}'s authorized keys:  no supported public key", i + 1, user)
+			};
+		}
+		self
+	} // }}}
+
+	// True if nobody could log in
+	pub fn is_empty(&self) -> bool /* {{{ */ {
+		self.passwords.is_empty() && self.keys.values().all(HashSet::is_empty)
+	} // }}}
+}
+
+impl Authenticator for Credentials {
+	fn password(&self, user: &str, password: &str) -> bool {
+		match self.passwords.get(user) {
+			Some(expected) => constant_time_eq(expected.as_bytes(), password.as_bytes()),
+			None => false
+		}
+	}
+
+	fn public_key(&self, user: &str, key: &PublicKey) -> bool {
+		match self.keys.get(user) {
+			Some(keys) => keys.contains(&key.public_key_base64()),
+			None => false
+		}
+	}
+}
+
+// Compares without stopping at the first difference, so how long a wrong password takes to refuse says nothing about it
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool /* {{{ */ {
+	if(a.len() != b.len()) {
+		return false;
+	}
+	a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+} // }}}
+
+#[cfg(test)]
+mod tests {
+	use thrussh_keys::PublicKeyBase64;
+	use thrussh_keys::key::KeyPair;
+	use thrussh_keys::key::PublicKey;
+
+	use super::Authenticator;
+	use super::Credentials;
+
+	fn key() -> PublicKey /* {{{ */ {
+		KeyPair::generate_ed25519().expect("Failed to generate a key").clone_public_key()
+	} // }}}
+
+	#[test]
+	fn passwords() {
+		let credentials = Credentials::new().password("alice", "secret").password("bob", "old").password("bob", "new");
+		assert!(Authenticator::password(&credentials, "alice", "secret"));
+		assert!(!Authenticator::password(&credentials, "alice", "secreT"));
+		assert!(!Authenticator::password(&credentials, "alice", "secret2"));
+		assert!(!Authenticator::password(&credentials, "alice", ""));
+		assert!(!Authenticator::password(&credentials, "bob", "old"));
+		assert!(Authenticator::password(&credentials, "bob", "new"));
+		assert!(!Authenticator::password(&credentials, "carol", "secret"));
+	}
+
+	#[test]
+	fn authorized_keys() {
+		let (plain, with_options, quoted, commented, unlisted) = (key(), key(), key(), key(), key());
+		let contents = [
+			"# Alice's keys".to_string(),
+			"".to_string(),
+			format!("ssh-ed25519 {} alice@laptop", plain.public_key_base64()),
+			format!("   no-port-forwarding,no-pty ssh-ed25519 {}", with_options.public_key_base64()),
+			format!("command=\"echo hello there\",from=\"10.0.0.0/8\" ssh-ed25519 {} alice@desktop", quoted.public_key_base64()),
+			format!("# ssh-ed25519 {}", commented.public_key_base64()),
+			"ssh-ed25519 not-base64 broken".to_string(),
+			"some nonsense".to_string()
+		].join("\n");
+		let credentials = Credentials::new().authorized_keys("alice", &contents);
+		assert!(Authenticator::public_key(&credentials, "alice", &plain));
+		assert!(Authenticator::public_key(&credentials, "alice", &with_options));
+		assert!(Authenticator::public_key(&credentials, "alice", &quoted));
+		assert!(!Authenticator::public_key(&credentials, "alice", &commented));
+		assert!(!Authenticator::public_key(&credentials, "alice", &unlisted));
+		assert!(!Authenticator::public_key(&credentials, "bob", &plain));
+		// Keys don't stand in for passwords
+		assert!(!Authenticator::password(&credentials, "alice", ""));
+	}
+
+	#[test]
+	fn nothing_usable() {
+		assert!(Credentials::new().is_empty());
+		assert!(Credentials::new().authorized_keys("alice", "# nothing here\n\nssh-ed25519 AAAA\n").is_empty());
+		assert!(!Credentials::new().public_key("alice", &key()).is_empty());
+		assert!(!Credentials::new().password("alice", "secret").is_empty());
+	}
+}
@@ -1,36 +1,274 @@
 use std::collections::VecDeque;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use lexiclean::Lexiclean;
 
 use sftp_protocol::Error;
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::FsStats;
 use sftp_protocol::common::Metadata;
+use sftp_protocol::stream::packet::open::OpenFlags;
 use super::file::OpenFile;
+use super::lock::LockKey;
+use super::session::SessionContext;
+
+pub mod atomic_upload;
+pub use atomic_upload::AtomicUploadLayer;
+pub use atomic_upload::OnDisconnect;
+pub mod blocking;
+pub use blocking::BlockingAdapter;
+pub use blocking::BlockingBackend;
+pub use blocking::BlockingFile;
+pub mod layer;
+pub use layer::BackendBuilder;
+pub use layer::Layer;
+pub use layer::Layered;
+pub use layer::Middleware;
+pub mod logging;
+pub use logging::LoggingLayer;
+pub mod read_only;
+pub use read_only::ReadOnlyLayer;
 
 pub type Result<T> = std::result::Result<T, Error>;
 pub trait PathRef: AsRef<Path> + Send {}
 impl<T> PathRef for T where T: AsRef<Path> + Send {}
 
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+	pub read: bool,
+	pub write: bool,
+	pub append: bool,
+	pub create: bool,
+	pub truncate: bool,
+	pub create_new: bool,
+	// Initial attributes for the file, if it's created; permissions have already been resolved against the server's ModePolicy
+	pub attrs: FileAttributes
+}
+
+impl OpenOptions {
+	pub fn from_flags(flags: OpenFlags, attrs: FileAttributes) -> Self /* {{{ */ {
+		Self{
+			read: flags.contains(OpenFlags::Read),
+			write: flags.contains(OpenFlags::Write),
+			append: flags.contains(OpenFlags::Append),
+			create: flags.contains(OpenFlags::Create),
+			truncate: flags.contains(OpenFlags::Truncate),
+			create_new: flags.contains(OpenFlags::Exclude),
+			attrs: attrs
+		}
+	} // }}}
+
+	// Size the client expects a file it's creating to end up, if it said; backends that can should reserve the space
+	//    up front (without changing the file's apparent size) so that chunks written out of order don't fragment it
+	pub fn preallocate(&self) -> Option<u64> /* {{{ */ {
+		match self.create {
+			true => self.attrs.get_size().filter(|&size| size > 0),
+			false => None
+		}
+	} // }}}
+}
+
 #[async_trait]
-pub trait Backend : Clone {
+pub trait Backend : Clone + Send + Sync {
 	async fn metadata(&self, path: impl PathRef + 'async_trait) -> Result<Metadata>;
 	async fn list(&self, path: impl PathRef + 'async_trait) -> Result<VecDeque<Metadata>>;
-	async fn open(&self, path: impl PathRef + 'async_trait, read: bool, write: bool, append: bool, create: bool, truncate: bool, create_new: bool) -> Result<OpenFile>;
+	async fn open(&self, path: impl PathRef + 'async_trait, options: OpenOptions) -> Result<OpenFile>;
 	async fn set_metadata(&self, path: impl PathRef + 'async_trait, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()>;
 	async fn delete_file(&self, path: impl PathRef + 'async_trait) -> Result<()>;
-	async fn mkdir(&self, path: impl PathRef + 'async_trait) -> Result<()>;
+	async fn mkdir(&self, path: impl PathRef + 'async_trait, attrs: FileAttributes) -> Result<()>;
 	async fn rmdir(&self, path: impl PathRef + 'async_trait) -> Result<()>;
+	// Must fail if `to` already exists, as SSH_FXP_RENAME requires; backends that can should check atomically
 	async fn rename(&self, from: impl PathRef + 'async_trait, to: impl PathRef + 'async_trait) -> Result<()>;
 
+	// Like rename(), but atomically replaces `to` if it exists, as with POSIX rename(); serves posix-rename@openssh.com
+	async fn posix_rename(&self, _from: impl PathRef + 'async_trait, _to: impl PathRef + 'async_trait) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	// Creates `to` as another name for the existing file `from`, failing if `to` exists; serves hardlink@openssh.com
+	async fn hardlink(&self, _from: impl PathRef + 'async_trait, _to: impl PathRef + 'async_trait) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	// Resolves any symlinks in an existing path, returning it in the same "/"-rooted namespace the server uses
+	async fn realpath(&self, path: impl PathRef + 'async_trait) -> Result<PathBuf> {
+		Ok(PathBuf::from("/").join(self.normalize_path(path)?))
+	}
+
+	// Capacity and usage of the storage holding a path; backends that enforce quotas should report the session user's
+	async fn statvfs(&self, _path: impl PathRef + 'async_trait) -> Result<FsStats> {
+		Err(Error::Unsupported)
+	}
+
+	// What the server locks a path on while it's open, renamed or deleted.  The default is the normalized path, which
+	//    only keeps sessions apart if they all share one root and files have no other names; backends whose files can
+	//    be reached by more than one path (links, per-user roots over shared storage) should identify the file itself.
+	async fn lock_key(&self, path: impl PathRef + 'async_trait) -> Result<LockKey> {
+		Ok(LockKey::Path(self.normalize_path(path)?))
+	}
+
+	// Paths from the server are rooted at "/"; the result is relative, so that it can be joined onto the backend's own root
 	fn normalize_path(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
-		let path = path.as_ref().lexiclean();
-		if let Some(first) = path.ancestors().next() {
-			if(first == PathBuf::from("..")) {
-				return Err(Error::InvalidPath);
-			}
-		}
-		Ok(path)
+		normalize_path(path)
 	}
 }
 
+/// What `Backend::normalize_path()` does by default:  resolves "." and ".." lexically and strips the leading "/",
+///    refusing paths that would climb out of the root
+pub fn normalize_path(path: impl AsRef<Path>) -> Result<PathBuf> /* {{{ */ {
+	let path = path.as_ref().lexiclean();
+	let path = match path.has_root() {
+		true => path.strip_prefix("/").unwrap().to_path_buf(),
+		false => path
+	};
+	if let Some(Component::ParentDir) = path.components().next() {
+		return Err(Error::InvalidPath);
+	}
+	Ok(path)
+} // }}}
+
+/// Object-safe counterpart to `Backend`, so that backends can be chosen at runtime and held as `Arc<dyn DynBackend>`.
+///    Every `Backend` implements this automatically.
+#[async_trait]
+pub trait DynBackend : Send + Sync {
+	async fn metadata(&self, path: &Path) -> Result<Metadata>;
+	async fn list(&self, path: &Path) -> Result<VecDeque<Metadata>>;
+	async fn open(&self, path: &Path, options: OpenOptions) -> Result<OpenFile>;
+	async fn set_metadata(&self, path: &Path, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()>;
+	async fn delete_file(&self, path: &Path) -> Result<()>;
+	async fn mkdir(&self, path: &Path, attrs: FileAttributes) -> Result<()>;
+	async fn rmdir(&self, path: &Path) -> Result<()>;
+	async fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+	async fn posix_rename(&self, from: &Path, to: &Path) -> Result<()>;
+	async fn hardlink(&self, from: &Path, to: &Path) -> Result<()>;
+	async fn realpath(&self, path: &Path) -> Result<PathBuf>;
+	async fn statvfs(&self, path: &Path) -> Result<FsStats>;
+	async fn lock_key(&self, path: &Path) -> Result<LockKey>;
+}
+
+#[async_trait]
+impl<B: Backend> DynBackend for B {
+	async fn metadata(&self, path: &Path) -> Result<Metadata> {
+		Backend::metadata(self, path).await
+	}
+
+	async fn list(&self, path: &Path) -> Result<VecDeque<Metadata>> {
+		Backend::list(self, path).await
+	}
+
+	async fn open(&self, path: &Path, options: OpenOptions) -> Result<OpenFile> {
+		Backend::open(self, path, options).await
+	}
+
+	async fn set_metadata(&self, path: &Path, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		Backend::set_metadata(self, path, uid_and_gid, permissions, atime_and_mtime).await
+	}
+
+	async fn delete_file(&self, path: &Path) -> Result<()> {
+		Backend::delete_file(self, path).await
+	}
+
+	async fn mkdir(&self, path: &Path, attrs: FileAttributes) -> Result<()> {
+		Backend::mkdir(self, path, attrs).await
+	}
+
+	async fn rmdir(&self, path: &Path) -> Result<()> {
+		Backend::rmdir(self, path).await
+	}
+
+	async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+		Backend::rename(self, from, to).await
+	}
+
+	async fn posix_rename(&self, from: &Path, to: &Path) -> Result<()> {
+		Backend::posix_rename(self, from, to).await
+	}
+
+	async fn hardlink(&self, from: &Path, to: &Path) -> Result<()> {
+		Backend::hardlink(self, from, to).await
+	}
+
+	async fn realpath(&self, path: &Path) -> Result<PathBuf> {
+		Backend::realpath(self, path).await
+	}
+
+	async fn statvfs(&self, path: &Path) -> Result<FsStats> {
+		Backend::statvfs(self, path).await
+	}
+
+	async fn lock_key(&self, path: &Path) -> Result<LockKey> {
+		Backend::lock_key(self, path).await
+	}
+}
+
+// Stands in for the backend of a server that hasn't started a session yet; sessions always get theirs from the factory
+pub(crate) struct Unassigned;
+
+#[async_trait]
+impl DynBackend for Unassigned {
+	async fn metadata(&self, _path: &Path) -> Result<Metadata> {
+		Err(Error::Unsupported)
+	}
+
+	async fn list(&self, _path: &Path) -> Result<VecDeque<Metadata>> {
+		Err(Error::Unsupported)
+	}
+
+	async fn open(&self, _path: &Path, _options: OpenOptions) -> Result<OpenFile> {
+		Err(Error::Unsupported)
+	}
+
+	async fn set_metadata(&self, _path: &Path, _uid_and_gid: Option<(u32, u32)>, _permissions: Option<u32>, _atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	async fn delete_file(&self, _path: &Path) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	async fn mkdir(&self, _path: &Path, _attrs: FileAttributes) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	async fn rmdir(&self, _path: &Path) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	async fn rename(&self, _from: &Path, _to: &Path) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	async fn posix_rename(&self, _from: &Path, _to: &Path) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	async fn hardlink(&self, _from: &Path, _to: &Path) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	async fn realpath(&self, _path: &Path) -> Result<PathBuf> {
+		Err(Error::Unsupported)
+	}
+
+	async fn statvfs(&self, _path: &Path) -> Result<FsStats> {
+		Err(Error::Unsupported)
+	}
+
+	async fn lock_key(&self, path: &Path) -> Result<LockKey> {
+		Ok(LockKey::Path(normalize_path(path)?))
+	}
+}
+
+/// Picks the backend for a session once its user is known
+pub trait BackendFactory : Send + Sync {
+	fn backend(&self, ctx: &SessionContext) -> anyhow::Result<Arc<dyn DynBackend>>;
+}
+
+impl<F> BackendFactory for F where F: Fn(&SessionContext) -> anyhow::Result<Arc<dyn DynBackend>> + Send + Sync {
+	fn backend(&self, ctx: &SessionContext) -> anyhow::Result<Arc<dyn DynBackend>> {
+		self(ctx)
+	}
+}
@@ -0,0 +1,324 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use sftp_protocol::Error;
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::FsStats;
+use sftp_protocol::common::Metadata;
+
+use crate::file::OpenFile;
+use crate::file::PositionalFile;
+use super::DynBackend;
+use super::OpenOptions;
+use super::Result;
+use super::layer::Layer;
+use super::layer::Layered;
+use super::layer::Middleware;
+
+const TEMP_SUFFIX: &str = ".sftp-upload";
+// Longest file name, in bytes, that common filesystems allow
+const NAME_MAX: usize = 255;
+
+/// What happens to an upload whose session ends before the client closes it
+#[derive(Clone, Copy, Debug)]
+pub enum OnDisconnect {
+	Discard,
+	// Keep the partial upload, so that a client reconnecting within this long can resume it
+	Keep(Duration)
+}
+
+/// Makes uploads atomic:  under the configured paths, a file opened for writing with create or truncate is written to
+///    a hidden temporary name in the same directory, and only renamed into place when the client closes it.  Under the
+///    same paths, temporary names are left out of `list()` and `metadata()`, and clients can't open, change or link
+///    them directly; elsewhere, names that merely look like them are left alone.  Partial
+///    uploads kept with `OnDisconnect::Keep` are deleted once they've gone untouched for that long.
+#[derive(Clone, Debug, Default)]
+pub struct AtomicUploadLayer {
+	rules: Vec<(PathBuf, OnDisconnect)>
+}
+
+impl AtomicUploadLayer {
+	pub fn new() -> Self /* {{{ */ {
+		Self::default()
+	} // }}}
+
+	// Applies to uploads anywhere under `prefix` ("/" for everything); where prefixes overlap, the longest one wins
+	pub fn path(mut self, prefix: impl Into<PathBuf>, on_disconnect: OnDisconnect) -> Self /* {{{ */ {
+		self.rules.push((prefix.into(), on_disconnect));
+		self
+	} // }}}
+}
+
+impl Layer for AtomicUploadLayer {
+	fn layer(&self, inner: Arc<dyn DynBackend>) -> Arc<dyn DynBackend> {
+		Arc::new(Layered(AtomicUpload{
+			inner: inner,
+			rules: self.rules.clone()
+		}))
+	}
+}
+
+/// Hidden name in the same directory that an upload to `path` is written to until it's closed
+pub fn temp_path(path: &Path) -> Option<PathBuf> /* {{{ */ {
+	let name = path.file_name()?.to_string_lossy();
+	let temp = format!(".{}{}", name, TEMP_SUFFIX);
+	if(temp.len() <= NAME_MAX) {
+		return Some(path.with_file_name(temp));
+	}
+	// Too long to decorate; keep what fits of the name next to a hash of all of it, so long names still differ
+	let hash = format!("{:016x}", fnv1a(name.as_bytes()));
+	let mut keep = NAME_MAX - hash.len() - TEMP_SUFFIX.len() - 2;
+	while(!name.is_char_boundary(keep)) {
+		keep -= 1;
+	}
+	Some(path.with_file_name(format!(".{}.{}{}", &name[..keep], hash, TEMP_SUFFIX)))
+} // }}}
+
+// Stable across builds and restarts, unlike std's hasher, so a resuming client finds the same temporary name
+fn fnv1a(data: &[u8]) -> u64 /* {{{ */ {
+	data.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+} // }}}
+
+fn is_temp_path(path: &Path) -> bool /* {{{ */ {
+	match path.file_name() {
+		Some(name) => {
+			let name = name.to_string_lossy();
+			name.len() > 1 + TEMP_SUFFIX.len() && name.starts_with('.') && name.ends_with(TEMP_SUFFIX)
+		},
+		None => false
+	}
+} // }}}
+
+pub struct AtomicUpload {
+	inner: Arc<dyn DynBackend>,
+	rules: Vec<(PathBuf, OnDisconnect)>
+}
+
+impl AtomicUpload {
+	fn rule(&self, path: &Path) -> Option<OnDisconnect> /* {{{ */ {
+		self.rules.iter()
+			.filter(|(prefix, _)| path.starts_with(prefix))
+			.max_by_key(|(prefix, _)| prefix.components().count())
+			.map(|(_, on_disconnect)| *on_disconnect)
+	} // }}}
+
+	// Metadata for a partial upload at `temp` that can still be resumed
+	async fn resumable(&self, temp: &Path, on_disconnect: OnDisconnect) -> Option<Metadata> /* {{{ */ {
+		match on_disconnect {
+			OnDisconnect::Keep(ttl) => unexpired(&*self.inner, temp, ttl).await,
+			OnDisconnect::Discard => None
+		}
+	} // }}}
+
+	// Whether `path` is where an upload under one of our paths is written until it's closed
+	fn is_temp(&self, path: &Path) -> bool /* {{{ */ {
+		is_temp_path(path) && self.rule(path).is_some()
+	} // }}}
+
+	// Temporary names belong to uploads in progress, possibly other sessions'; nothing else may touch them
+	fn refuse_temp(&self, path: &Path) -> Result<()> /* {{{ */ {
+		match self.is_temp(path) {
+			true => Err(Error::PermissionDenied),
+			false => Ok(())
+		}
+	} // }}}
+}
+
+// Metadata for the partial upload at `temp` if it's been written to within `ttl`; stale ones are deleted as they're found
+async fn unexpired(backend: &dyn DynBackend, temp: &Path, ttl: Duration) -> Option<Metadata> /* {{{ */ {
+	let metadata = backend.metadata(temp).await.ok()?;
+	let age = Utc::now().signed_duration_since(metadata.mtime).to_std().unwrap_or_default();
+	if(age <= ttl) {
+		return Some(metadata);
+	}
+	if let Err(e) = backend.delete_file(temp).await {
+		warn!("Failed to delete expired partial upload {:?}:  {:?}", temp, e);
+	}
+	None
+} // }}}
+
+#[async_trait]
+impl Middleware for AtomicUpload {
+	fn inner(&self) -> &dyn DynBackend {
+		&*self.inner
+	}
+
+	async fn metadata(&self, path: &Path) -> Result<Metadata> {
+		if(self.is_temp(path)) {
+			return Err(io::Error::new(io::ErrorKind::NotFound, "no such file").into());
+		}
+		let result = self.inner.metadata(path).await;
+		if(result.is_err()) {
+			// Lets a resuming client (e.g. OpenSSH's "reput") find out how much of the file it already sent
+			if let (Some(on_disconnect), Some(temp)) = (self.rule(path), temp_path(path)) {
+				if let Some(metadata) = self.resumable(&temp, on_disconnect).await {
+					return Ok(metadata);
+				}
+			}
+		}
+		result
+	}
+
+	async fn list(&self, path: &Path) -> Result<VecDeque<Metadata>> {
+		let mut entries = self.inner.list(path).await?;
+		entries.retain(|entry| !self.is_temp(&path.join(&entry.path)));
+		Ok(entries)
+	}
+
+	async fn open(&self, path: &Path, options: OpenOptions) -> Result<OpenFile> {
+		self.refuse_temp(path)?;
+		let (on_disconnect, temp) = match (self.rule(path), temp_path(path)) {
+			(Some(on_disconnect), Some(temp)) if options.create || options.truncate => (on_disconnect, temp),
+			_ => return self.inner.open(path, options).await
+		};
+		let exists = self.inner.metadata(path).await.is_ok();
+		if(exists && options.create_new) {
+			return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists").into());
+		}
+		let resume = !options.truncate && self.resumable(&temp, on_disconnect).await.is_some();
+		if(exists && !options.truncate && !resume) {
+			// Modifying an existing file in place; there's no upload to make atomic
+			return self.inner.open(path, options).await;
+		}
+		let mut temp_options = options;
+		temp_options.create = true;
+		temp_options.create_new = false;
+		temp_options.truncate = !resume;
+		let file = self.inner.open(&temp, temp_options).await?;
+		let backend = self.inner.clone();
+		let target = path.to_path_buf();
+		Ok(file.wrap(|fd| AtomicFile{
+			fd: fd,
+			backend: backend,
+			temp: temp,
+			target: target,
+			on_disconnect: on_disconnect
+		}))
+	}
+
+	async fn set_metadata(&self, path: &Path, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		self.refuse_temp(path)?;
+		self.inner.set_metadata(path, uid_and_gid, permissions, atime_and_mtime).await
+	}
+
+	async fn delete_file(&self, path: &Path) -> Result<()> {
+		self.refuse_temp(path)?;
+		self.inner.delete_file(path).await
+	}
+
+	async fn mkdir(&self, path: &Path, attrs: FileAttributes) -> Result<()> {
+		self.refuse_temp(path)?;
+		self.inner.mkdir(path, attrs).await
+	}
+
+	async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+		self.refuse_temp(from)?;
+		self.refuse_temp(to)?;
+		self.inner.rename(from, to).await
+	}
+
+	async fn posix_rename(&self, from: &Path, to: &Path) -> Result<()> {
+		self.refuse_temp(from)?;
+		self.refuse_temp(to)?;
+		self.inner.posix_rename(from, to).await
+	}
+
+	// Above all, a link to a temporary name would let other clients see the upload before it's complete
+	async fn hardlink(&self, from: &Path, to: &Path) -> Result<()> {
+		self.refuse_temp(from)?;
+		self.refuse_temp(to)?;
+		self.inner.hardlink(from, to).await
+	}
+}
+
+struct AtomicFile {
+	fd: Box<dyn PositionalFile>,
+	backend: Arc<dyn DynBackend>,
+	temp: PathBuf,
+	target: PathBuf,
+	on_disconnect: OnDisconnect
+}
+
+impl fmt::Debug for AtomicFile {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("AtomicFile")
+			.field("fd", &self.fd)
+			.field("temp", &self.temp)
+			.field("target", &self.target)
+			.finish()
+	}
+}
+
+#[async_trait]
+impl PositionalFile for AtomicFile {
+	async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>> {
+		self.fd.read_at(offset, len).await
+	}
+
+	async fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+		self.fd.write_at(offset, data).await
+	}
+
+	async fn len(&self) -> Result<u64> {
+		self.fd.len().await
+	}
+
+	async fn flush(&self) -> Result<()> {
+		self.fd.flush().await
+	}
+
+	async fn sync(&self) -> Result<()> {
+		self.fd.sync().await
+	}
+
+	async fn close(&self) -> Result<()> {
+		self.fd.close().await?;
+		match self.backend.posix_rename(&self.temp, &self.target).await {
+			// Without an atomic replace, the target is briefly missing while it's swapped out, but never half-written
+			Err(Error::Unsupported) => {
+				if(self.backend.metadata(&self.target).await.is_ok()) {
+					self.backend.delete_file(&self.target).await?;
+				}
+				self.backend.rename(&self.temp, &self.target).await
+			},
+			result => result
+		}
+	}
+
+	async fn abort(&self) -> Result<()> {
+		let result = self.fd.abort().await;
+		match self.on_disconnect {
+			OnDisconnect::Discard => self.backend.delete_file(&self.temp).await?,
+			// Nothing else would clean up after a client that never comes back.  A resumed upload is written to in
+			//    the meantime, so it's young enough to survive this check.
+			OnDisconnect::Keep(ttl) => {
+				let backend = self.backend.clone();
+				let temp = self.temp.clone();
+				tokio::spawn(async move {
+					tokio::time::delay_for(ttl + Duration::from_secs(1)).await;
+					unexpired(&*backend, &temp, ttl).await;
+				});
+			}
+		};
+		result
+	}
+
+	async fn metadata(&self) -> Result<Metadata> {
+		self.fd.metadata().await
+	}
+
+	async fn set_metadata(&self, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		self.fd.set_metadata(uid_and_gid, permissions, atime_and_mtime).await
+	}
+
+	async fn statvfs(&self) -> Result<FsStats> {
+		self.fd.statvfs().await
+	}
+}
@@ -0,0 +1,321 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
+
+use sftp_protocol::Error;
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::FsStats;
+use sftp_protocol::common::Metadata;
+
+use crate::file::OpenFile;
+use crate::file::PositionalFile;
+use crate::file::ZERO_FILL_CHUNK;
+use crate::lock::LockKey;
+use super::Backend;
+use super::OpenOptions;
+use super::PathRef;
+use super::Result;
+use super::normalize_path;
+
+/// Synchronous counterpart to `Backend`, for storage that only has a blocking API.  Implementations are free to block;
+///    `BlockingAdapter` runs every call on tokio's blocking pool.
+pub trait BlockingBackend : Send + Sync + 'static {
+	type File: BlockingFile;
+
+	fn metadata(&self, path: &Path) -> Result<Metadata>;
+	fn list(&self, path: &Path) -> Result<VecDeque<Metadata>>;
+	fn open(&self, path: &Path, options: OpenOptions) -> Result<Self::File>;
+	fn set_metadata(&self, path: &Path, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()>;
+	fn delete_file(&self, path: &Path) -> Result<()>;
+	fn mkdir(&self, path: &Path, attrs: FileAttributes) -> Result<()>;
+	fn rmdir(&self, path: &Path) -> Result<()>;
+	// Same contracts as Backend::rename(), Backend::posix_rename() and Backend::hardlink()
+	fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+	fn posix_rename(&self, _from: &Path, _to: &Path) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	fn hardlink(&self, _from: &Path, _to: &Path) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	// Same contract as Backend::realpath()
+	fn realpath(&self, path: &Path) -> Result<PathBuf> {
+		Ok(PathBuf::from("/").join(self.normalize_path(path)?))
+	}
+
+	// Same contract as Backend::statvfs()
+	fn statvfs(&self, _path: &Path) -> Result<FsStats> {
+		Err(Error::Unsupported)
+	}
+
+	// Same contract as Backend::lock_key()
+	fn lock_key(&self, path: &Path) -> Result<LockKey> {
+		Ok(LockKey::Path(self.normalize_path(path)?))
+	}
+
+	// Same contract as Backend::normalize_path()
+	fn normalize_path(&self, path: &Path) -> Result<PathBuf> {
+		normalize_path(path)
+	}
+}
+
+/// File opened by a `BlockingBackend`; reads and writes go through the usual std traits, with a seek before each
+pub trait BlockingFile : Read + Write + Seek + Send + fmt::Debug + 'static {
+	// Equivalent of fsync(); the default only flushes
+	fn sync(&mut self) -> io::Result<()> {
+		self.flush()
+	}
+
+	// Equivalent of fstat()
+	fn metadata(&self) -> Result<Metadata> {
+		Err(Error::Unsupported)
+	}
+
+	// Equivalent of fchown()/fchmod()/futimens()
+	fn set_metadata(&mut self, _uid_and_gid: Option<(u32, u32)>, _permissions: Option<u32>, _atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		Err(Error::Unsupported)
+	}
+
+	// Equivalent of fstatvfs()
+	fn statvfs(&self) -> Result<FsStats> {
+		Err(Error::Unsupported)
+	}
+}
+
+impl BlockingFile for std::fs::File {
+	fn sync(&mut self) -> io::Result<()> {
+		self.sync_all()
+	}
+
+	fn metadata(&self) -> Result<Metadata> {
+		let meta = std::fs::File::metadata(self)?;
+		let atime = meta.accessed().unwrap_or(UNIX_EPOCH);
+		let mtime = meta.modified().unwrap_or(UNIX_EPOCH);
+		// A handle has no name of its own; callers that need one already know the path they opened
+		Ok(Metadata::new("", meta.len(), meta.is_dir(), meta.is_file(), None, meta.uid(), meta.gid(), meta.mode(), atime, mtime))
+	}
+}
+
+/// Serves a `BlockingBackend` as a `Backend`.  Every call, including reads and writes on its files, runs on tokio's
+///    blocking pool; at most `max_blocking` of them run at once across the backend and all of its files, so that a
+///    burst of requests can't tie up the whole pool.
+pub struct BlockingAdapter<B> {
+	inner: Arc<B>,
+	permits: Arc<Semaphore>
+}
+
+impl<B> Clone for BlockingAdapter<B> {
+	fn clone(&self) -> Self {
+		Self{
+			inner: self.inner.clone(),
+			permits: self.permits.clone()
+		}
+	}
+}
+
+impl<B: BlockingBackend> BlockingAdapter<B> {
+	pub fn new(backend: B, max_blocking: usize) -> Self /* {{{ */ {
+		Self{
+			inner: Arc::new(backend),
+			permits: Arc::new(Semaphore::new(max_blocking.max(1)))
+		}
+	} // }}}
+
+	async fn run<T: Send + 'static>(&self, f: impl FnOnce(&B) -> Result<T> + Send + 'static) -> Result<T> /* {{{ */ {
+		let inner = self.inner.clone();
+		run_blocking(&self.permits, move || f(&inner)).await
+	} // }}}
+}
+
+// Waits for a permit, then runs `f` on the blocking pool while holding it
+async fn run_blocking<T: Send + 'static>(permits: &Semaphore, f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> /* {{{ */ {
+	let _permit = permits.acquire().await;
+	spawn_blocking(f).await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+} // }}}
+
+#[async_trait]
+impl<B: BlockingBackend> Backend for BlockingAdapter<B> {
+	async fn metadata(&self, path: impl PathRef + 'async_trait) -> Result<Metadata> {
+		let path = path.as_ref().to_path_buf();
+		self.run(move |b| b.metadata(&path)).await
+	}
+
+	async fn list(&self, path: impl PathRef + 'async_trait) -> Result<VecDeque<Metadata>> {
+		let path = path.as_ref().to_path_buf();
+		self.run(move |b| b.list(&path)).await
+	}
+
+	async fn open(&self, path: impl PathRef + 'async_trait, options: OpenOptions) -> Result<OpenFile> {
+		let path = path.as_ref().to_path_buf();
+		let (metadata, file) = self.run(move |b| {
+			let file = b.open(&path, options)?;
+			Ok((b.metadata(&path)?, file))
+		}).await?;
+		Ok(OpenFile::positional(metadata, BlockingOpenFile{
+			file: Arc::new(Mutex::new(file)),
+			permits: self.permits.clone()
+		}))
+	}
+
+	async fn set_metadata(&self, path: impl PathRef + 'async_trait, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		let path = path.as_ref().to_path_buf();
+		self.run(move |b| b.set_metadata(&path, uid_and_gid, permissions, atime_and_mtime)).await
+	}
+
+	async fn delete_file(&self, path: impl PathRef + 'async_trait) -> Result<()> {
+		let path = path.as_ref().to_path_buf();
+		self.run(move |b| b.delete_file(&path)).await
+	}
+
+	async fn mkdir(&self, path: impl PathRef + 'async_trait, attrs: FileAttributes) -> Result<()> {
+		let path = path.as_ref().to_path_buf();
+		self.run(move |b| b.mkdir(&path, attrs)).await
+	}
+
+	async fn rmdir(&self, path: impl PathRef + 'async_trait) -> Result<()> {
+		let path = path.as_ref().to_path_buf();
+		self.run(move |b| b.rmdir(&path)).await
+	}
+
+	async fn rename(&self, from: impl PathRef + 'async_trait, to: impl PathRef + 'async_trait) -> Result<()> {
+		let from = from.as_ref().to_path_buf();
+		let to = to.as_ref().to_path_buf();
+		self.run(move |b| b.rename(&from, &to)).await
+	}
+
+	async fn posix_rename(&self, from: impl PathRef + 'async_trait, to: impl PathRef + 'async_trait) -> Result<()> {
+		let from = from.as_ref().to_path_buf();
+		let to = to.as_ref().to_path_buf();
+		self.run(move |b| b.posix_rename(&from, &to)).await
+	}
+
+	async fn hardlink(&self, from: impl PathRef + 'async_trait, to: impl PathRef + 'async_trait) -> Result<()> {
+		let from = from.as_ref().to_path_buf();
+		let to = to.as_ref().to_path_buf();
+		self.run(move |b| b.hardlink(&from, &to)).await
+	}
+
+	async fn realpath(&self, path: impl PathRef + 'async_trait) -> Result<PathBuf> {
+		let path = path.as_ref().to_path_buf();
+		self.run(move |b| b.realpath(&path)).await
+	}
+
+	async fn statvfs(&self, path: impl PathRef + 'async_trait) -> Result<FsStats> {
+		let path = path.as_ref().to_path_buf();
+		self.run(move |b| b.statvfs(&path)).await
+	}
+
+	async fn lock_key(&self, path: impl PathRef + 'async_trait) -> Result<LockKey> {
+		let path = path.as_ref().to_path_buf();
+		self.run(move |b| b.lock_key(&path)).await
+	}
+
+	fn normalize_path(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+		self.inner.normalize_path(path.as_ref())
+	}
+}
+
+/// `PositionalFile` over a `BlockingFile`; each call holds the file exclusively while it seeks and transfers, on the
+///    blocking pool, under the same concurrency limit as the backend that opened it
+pub struct BlockingOpenFile<F> {
+	file: Arc<Mutex<F>>,
+	permits: Arc<Semaphore>
+}
+
+impl<F: BlockingFile> BlockingOpenFile<F> {
+	async fn run<T: Send + 'static>(&self, f: impl FnOnce(&mut F) -> Result<T> + Send + 'static) -> Result<T> /* {{{ */ {
+		let file = self.file.clone();
+		run_blocking(&self.permits, move || {
+			// A panic in an earlier call leaves the file wherever it was; every call seeks first anyway
+			let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+			f(&mut file)
+		}).await
+	} // }}}
+}
+
+impl<F: fmt::Debug> fmt::Debug for BlockingOpenFile<F> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("BlockingOpenFile")
+			.field("file", &self.file)
+			.finish()
+	}
+}
+
+#[async_trait]
+impl<F: BlockingFile> PositionalFile for BlockingOpenFile<F> {
+	async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>> {
+		self.run(move |file| {
+			file.seek(SeekFrom::Start(offset))?;
+			let mut data = vec![0u8; len as usize];
+			let mut filled = 0;
+			while(filled < data.len()) {
+				let count = file.read(&mut data[filled..])?;
+				if(count == 0) {
+					break;
+				}
+				filled += count;
+			}
+			data.truncate(filled);
+			Ok(data)
+		}).await
+	}
+
+	async fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+		let data = data.to_vec();
+		self.run(move |file| {
+			// As with SeekableFile, don't count on seeking past the end to leave zeros behind
+			let mut len = file.seek(SeekFrom::End(0))?;
+			if(len < offset) {
+				let zeros = vec![0u8; (offset - len).min(ZERO_FILL_CHUNK) as usize];
+				while(len < offset) {
+					let count = (offset - len).min(zeros.len() as u64) as usize;
+					file.write_all(&zeros[..count])?;
+					len += count as u64;
+				}
+			} else {
+				file.seek(SeekFrom::Start(offset))?;
+			}
+			file.write_all(&data)?;
+			Ok(())
+		}).await
+	}
+
+	async fn len(&self) -> Result<u64> {
+		self.run(|file| Ok(file.seek(SeekFrom::End(0))?)).await
+	}
+
+	async fn flush(&self) -> Result<()> {
+		self.run(|file| Ok(file.flush()?)).await
+	}
+
+	async fn sync(&self) -> Result<()> {
+		self.run(|file| Ok(file.sync()?)).await
+	}
+
+	async fn metadata(&self) -> Result<Metadata> {
+		self.run(|file| file.metadata()).await
+	}
+
+	async fn set_metadata(&self, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		self.run(move |file| file.set_metadata(uid_and_gid, permissions, atime_and_mtime)).await
+	}
+
+	async fn statvfs(&self) -> Result<FsStats> {
+		self.run(|file| file.statvfs()).await
+	}
+}
@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::FsStats;
+use sftp_protocol::common::Metadata;
+
+use crate::file::OpenFile;
+use crate::lock::LockKey;
+use super::DynBackend;
+use super::OpenOptions;
+use super::Result;
+
+/// Wraps a backend in another one, in the style of tower's `Layer`.  Any
+///    `Fn(Arc<dyn DynBackend>) -> Arc<dyn DynBackend>` closure is a layer.
+pub trait Layer : Send + Sync {
+	fn layer(&self, inner: Arc<dyn DynBackend>) -> Arc<dyn DynBackend>;
+}
+
+impl<F> Layer for F where F: Fn(Arc<dyn DynBackend>) -> Arc<dyn DynBackend> + Send + Sync {
+	fn layer(&self, inner: Arc<dyn DynBackend>) -> Arc<dyn DynBackend> {
+		self(inner)
+	}
+}
+
+/// Convenience for writing layers:  every call forwards to `inner()` unless overridden, so a middleware only needs to
+///    implement the calls it cares about.  Wrap it in `Layered` to get a `DynBackend`.
+#[async_trait]
+pub trait Middleware : Send + Sync {
+	fn inner(&self) -> &dyn DynBackend;
+
+	// Called on every file successfully opened through the default `open()`, to intercept its stream
+	fn wrap_file(&self, _path: &Path, file: OpenFile) -> OpenFile {
+		file
+	}
+
+	async fn metadata(&self, path: &Path) -> Result<Metadata> {
+		self.inner().metadata(path).await
+	}
+
+	async fn list(&self, path: &Path) -> Result<VecDeque<Metadata>> {
+		self.inner().list(path).await
+	}
+
+	async fn open(&self, path: &Path, options: OpenOptions) -> Result<OpenFile> {
+		let file = self.inner().open(path, options).await?;
+		Ok(self.wrap_file(path, file))
+	}
+
+	async fn set_metadata(&self, path: &Path, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		self.inner().set_metadata(path, uid_and_gid, permissions, atime_and_mtime).await
+	}
+
+	async fn delete_file(&self, path: &Path) -> Result<()> {
+		self.inner().delete_file(path).await
+	}
+
+	async fn mkdir(&self, path: &Path, attrs: FileAttributes) -> Result<()> {
+		self.inner().mkdir(path, attrs).await
+	}
+
+	async fn rmdir(&self, path: &Path) -> Result<()> {
+		self.inner().rmdir(path).await
+	}
+
+	async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+		self.inner().rename(from, to).await
+	}
+
+	async fn posix_rename(&self, from: &Path, to: &Path) -> Result<()> {
+		self.inner().posix_rename(from, to).await
+	}
+
+	async fn hardlink(&self, from: &Path, to: &Path) -> Result<()> {
+		self.inner().hardlink(from, to).await
+	}
+
+	async fn realpath(&self, path: &Path) -> Result<PathBuf> {
+		self.inner().realpath(path).await
+	}
+
+	async fn statvfs(&self, path: &Path) -> Result<FsStats> {
+		self.inner().statvfs(path).await
+	}
+
+	async fn lock_key(&self, path: &Path) -> Result<LockKey> {
+		self.inner().lock_key(path).await
+	}
+}
+
+/// Adapts a `Middleware` into a `DynBackend`
+#[derive(Debug)]
+pub struct Layered<M>(pub M);
+
+#[async_trait]
+impl<M: Middleware> DynBackend for Layered<M> {
+	async fn metadata(&self, path: &Path) -> Result<Metadata> {
+		Middleware::metadata(&self.0, path).await
+	}
+
+	async fn list(&self, path: &Path) -> Result<VecDeque<Metadata>> {
+		Middleware::list(&self.0, path).await
+	}
+
+	async fn open(&self, path: &Path, options: OpenOptions) -> Result<OpenFile> {
+		Middleware::open(&self.0, path, options).await
+	}
+
+	async fn set_metadata(&self, path: &Path, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		Middleware::set_metadata(&self.0, path, uid_and_gid, permissions, atime_and_mtime).await
+	}
+
+	async fn delete_file(&self, path: &Path) -> Result<()> {
+		Middleware::delete_file(&self.0, path).await
+	}
+
+	async fn mkdir(&self, path: &Path, attrs: FileAttributes) -> Result<()> {
+		Middleware::mkdir(&self.0, path, attrs).await
+	}
+
+	async fn rmdir(&self, path: &Path) -> Result<()> {
+		Middleware::rmdir(&self.0, path).await
+	}
+
+	async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+		Middleware::rename(&self.0, from, to).await
+	}
+
+	async fn posix_rename(&self, from: &Path, to: &Path) -> Result<()> {
+		Middleware::posix_rename(&self.0, from, to).await
+	}
+
+	async fn hardlink(&self, from: &Path, to: &Path) -> Result<()> {
+		Middleware::hardlink(&self.0, from, to).await
+	}
+
+	async fn realpath(&self, path: &Path) -> Result<PathBuf> {
+		Middleware::realpath(&self.0, path).await
+	}
+
+	async fn statvfs(&self, path: &Path) -> Result<FsStats> {
+		Middleware::statvfs(&self.0, path).await
+	}
+
+	async fn lock_key(&self, path: &Path) -> Result<LockKey> {
+		Middleware::lock_key(&self.0, path).await
+	}
+}
+
+/// Stacks layers around a backend.  Layers apply in the order they're added, so the first one added is the outermost
+///    and sees each call first.
+pub struct BackendBuilder {
+	backend: Arc<dyn DynBackend>,
+	layers: Vec<Box<dyn Layer>>
+}
+
+impl BackendBuilder {
+	pub fn new(backend: impl DynBackend + 'static) -> Self /* {{{ */ {
+		Self::from_arc(Arc::new(backend))
+	} // }}}
+
+	pub fn from_arc(backend: Arc<dyn DynBackend>) -> Self /* {{{ */ {
+		Self{
+			backend: backend,
+			layers: Vec::new()
+		}
+	} // }}}
+
+	pub fn layer(mut self, layer: impl Layer + 'static) -> Self /* {{{ */ {
+		self.layers.push(Box::new(layer));
+		self
+	} // }}}
+
+	pub fn build(self) -> Arc<dyn DynBackend> /* {{{ */ {
+		self.layers.iter().rev().fold(self.backend, |backend, layer| layer.layer(backend))
+	} // }}}
+}
@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use log::Level;
+
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::FsStats;
+use sftp_protocol::common::Metadata;
+
+use crate::file::OpenFile;
+use crate::file::PositionalFile;
+use super::DynBackend;
+use super::OpenOptions;
+use super::Result;
+use super::layer::Layer;
+use super::layer::Layered;
+use super::layer::Middleware;
+
+/// Logs every backend call with how long it took, and a summary of the bytes transferred when each file is closed
+#[derive(Clone, Debug)]
+pub struct LoggingLayer {
+	level: Level,
+	target: String
+}
+
+impl Default for LoggingLayer {
+	fn default() -> Self /* {{{ */ {
+		Self::new(Level::Debug)
+	} // }}}
+}
+
+impl LoggingLayer {
+	pub fn new(level: Level) -> Self /* {{{ */ {
+		Self{
+			level: level,
+			target: module_path!().to_string()
+		}
+	} // }}}
+
+	pub fn with_target(mut self, target: impl Into<String>) -> Self /* {{{ */ {
+		self.target = target.into();
+		self
+	} // }}}
+}
+
+impl Layer for LoggingLayer {
+	fn layer(&self, inner: Arc<dyn DynBackend>) -> Arc<dyn DynBackend> {
+		Arc::new(Layered(Logging{
+			inner: inner,
+			level: self.level,
+			target: self.target.clone()
+		}))
+	}
+}
+
+pub struct Logging {
+	inner: Arc<dyn DynBackend>,
+	level: Level,
+	target: String
+}
+
+impl Logging {
+	fn log<T>(&self, op: &str, path: &Path, started: Instant, result: &Result<T>) /* {{{ */ {
+		match result {
+			Ok(_) => log!(target: self.target.as_str(), self.level, "{} {:?} ({:?})", op, path, started.elapsed()),
+			Err(e) => log!(target: self.target.as_str(), self.level, "{} {:?} failed after {:?}:  {}", op, path, started.elapsed(), e)
+		};
+	} // }}}
+}
+
+#[async_trait]
+impl Middleware for Logging {
+	fn inner(&self) -> &dyn DynBackend {
+		&*self.inner
+	}
+
+	fn wrap_file(&self, path: &Path, file: OpenFile) -> OpenFile {
+		let level = self.level;
+		let target = self.target.clone();
+		let path = path.to_path_buf();
+		file.wrap(|fd| LoggedFile{
+			fd: fd,
+			path: path,
+			level: level,
+			target: target,
+			read: AtomicU64::new(0),
+			written: AtomicU64::new(0)
+		})
+	}
+
+	async fn metadata(&self, path: &Path) -> Result<Metadata> {
+		let started = Instant::now();
+		let result = self.inner.metadata(path).await;
+		self.log("metadata", path, started, &result);
+		result
+	}
+
+	async fn list(&self, path: &Path) -> Result<VecDeque<Metadata>> {
+		let started = Instant::now();
+		let result = self.inner.list(path).await;
+		self.log("list", path, started, &result);
+		result
+	}
+
+	async fn open(&self, path: &Path, options: OpenOptions) -> Result<OpenFile> {
+		let started = Instant::now();
+		let result = self.inner.open(path, options).await;
+		self.log("open", path, started, &result);
+		result.map(|file| self.wrap_file(path, file))
+	}
+
+	async fn set_metadata(&self, path: &Path, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		let started = Instant::now();
+		let result = self.inner.set_metadata(path, uid_and_gid, permissions, atime_and_mtime).await;
+		self.log("set_metadata", path, started, &result);
+		result
+	}
+
+	async fn delete_file(&self, path: &Path) -> Result<()> {
+		let started = Instant::now();
+		let result = self.inner.delete_file(path).await;
+		self.log("delete_file", path, started, &result);
+		result
+	}
+
+	async fn mkdir(&self, path: &Path, attrs: FileAttributes) -> Result<()> {
+		let started = Instant::now();
+		let result = self.inner.mkdir(path, attrs).await;
+		self.log("mkdir", path, started, &result);
+		result
+	}
+
+	async fn rmdir(&self, path: &Path) -> Result<()> {
+		let started = Instant::now();
+		let result = self.inner.rmdir(path).await;
+		self.log("rmdir", path, started, &result);
+		result
+	}
+
+	async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+		let started = Instant::now();
+		let result = self.inner.rename(from, to).await;
+		self.log(&format!("rename to {:?}", to), from, started, &result);
+		result
+	}
+
+	async fn posix_rename(&self, from: &Path, to: &Path) -> Result<()> {
+		let started = Instant::now();
+		let result = self.inner.posix_rename(from, to).await;
+		self.log(&format!("posix_rename to {:?}", to), from, started, &result);
+		result
+	}
+
+	async fn hardlink(&self, from: &Path, to: &Path) -> Result<()> {
+		let started = Instant::now();
+		let result = self.inner.hardlink(from, to).await;
+		self.log(&format!("hardlink as {:?}", to), from, started, &result);
+		result
+	}
+
+	async fn realpath(&self, path: &Path) -> Result<PathBuf> {
+		let started = Instant::now();
+		let result = self.inner.realpath(path).await;
+		self.log("realpath", path, started, &result);
+		result
+	}
+
+	async fn statvfs(&self, path: &Path) -> Result<FsStats> {
+		let started = Instant::now();
+		let result = self.inner.statvfs(path).await;
+		self.log("statvfs", path, started, &result);
+		result
+	}
+}
+
+#[derive(Debug)]
+struct LoggedFile {
+	fd: Box<dyn PositionalFile>,
+	path: PathBuf,
+	level: Level,
+	target: String,
+	read: AtomicU64,
+	written: AtomicU64
+}
+
+impl Drop for LoggedFile {
+	fn drop(&mut self) {
+		log!(target: self.target.as_str(), self.level, "close {:?} ({} bytes read, {} bytes written)", self.path, self.read.load(Ordering::Relaxed), self.written.load(Ordering::Relaxed));
+	}
+}
+
+#[async_trait]
+impl PositionalFile for LoggedFile {
+	async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>> {
+		let data = self.fd.read_at(offset, len).await?;
+		self.read.fetch_add(data.len() as u64, Ordering::Relaxed);
+		Ok(data)
+	}
+
+	async fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+		self.fd.write_at(offset, data).await?;
+		self.written.fetch_add(data.len() as u64, Ordering::Relaxed);
+		Ok(())
+	}
+
+	async fn len(&self) -> Result<u64> {
+		self.fd.len().await
+	}
+
+	async fn flush(&self) -> Result<()> {
+		self.fd.flush().await
+	}
+
+	async fn sync(&self) -> Result<()> {
+		self.fd.sync().await
+	}
+
+	async fn close(&self) -> Result<()> {
+		self.fd.close().await
+	}
+
+	async fn abort(&self) -> Result<()> {
+		self.fd.abort().await
+	}
+
+	async fn metadata(&self) -> Result<Metadata> {
+		self.fd.metadata().await
+	}
+
+	async fn set_metadata(&self, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		self.fd.set_metadata(uid_and_gid, permissions, atime_and_mtime).await
+	}
+
+	async fn statvfs(&self) -> Result<FsStats> {
+		self.fd.statvfs().await
+	}
+}
@@ -0,0 +1,138 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use sftp_protocol::Error;
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::FsStats;
+use sftp_protocol::common::Metadata;
+
+use crate::file::OpenFile;
+use crate::file::PositionalFile;
+use super::DynBackend;
+use super::OpenOptions;
+use super::Result;
+use super::layer::Layer;
+use super::layer::Layered;
+use super::layer::Middleware;
+
+/// Refuses every call that could modify the backend, regardless of what the server's own configuration allows
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOnlyLayer;
+
+impl Layer for ReadOnlyLayer {
+	fn layer(&self, inner: Arc<dyn DynBackend>) -> Arc<dyn DynBackend> {
+		Arc::new(Layered(ReadOnly{
+			inner: inner
+		}))
+	}
+}
+
+pub struct ReadOnly {
+	inner: Arc<dyn DynBackend>
+}
+
+#[async_trait]
+impl Middleware for ReadOnly {
+	fn inner(&self) -> &dyn DynBackend {
+		&*self.inner
+	}
+
+	// Handles opened for reading can still be used to change the file's metadata, unless they're stopped here
+	fn wrap_file(&self, _path: &Path, file: OpenFile) -> OpenFile {
+		file.wrap(|fd| ReadOnlyFile{
+			fd: fd
+		})
+	}
+
+	async fn open(&self, path: &Path, options: OpenOptions) -> Result<OpenFile> {
+		if(options.write || options.append || options.create || options.truncate || options.create_new) {
+			return Err(Error::PermissionDenied);
+		}
+		let file = self.inner.open(path, options).await?;
+		Ok(self.wrap_file(path, file))
+	}
+
+	async fn set_metadata(&self, _path: &Path, _uid_and_gid: Option<(u32, u32)>, _permissions: Option<u32>, _atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn delete_file(&self, _path: &Path) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn mkdir(&self, _path: &Path, _attrs: FileAttributes) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn rmdir(&self, _path: &Path) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn rename(&self, _from: &Path, _to: &Path) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn posix_rename(&self, _from: &Path, _to: &Path) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn hardlink(&self, _from: &Path, _to: &Path) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn statvfs(&self, path: &Path) -> Result<FsStats> {
+		let mut stats = self.inner.statvfs(path).await?;
+		stats.read_only = true;
+		Ok(stats)
+	}
+}
+
+#[derive(Debug)]
+pub struct ReadOnlyFile {
+	fd: Box<dyn PositionalFile>
+}
+
+#[async_trait]
+impl PositionalFile for ReadOnlyFile {
+	async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>> {
+		self.fd.read_at(offset, len).await
+	}
+
+	async fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn len(&self) -> Result<u64> {
+		self.fd.len().await
+	}
+
+	async fn flush(&self) -> Result<()> {
+		self.fd.flush().await
+	}
+
+	async fn sync(&self) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn close(&self) -> Result<()> {
+		self.fd.close().await
+	}
+
+	async fn abort(&self) -> Result<()> {
+		self.fd.abort().await
+	}
+
+	async fn metadata(&self) -> Result<Metadata> {
+		self.fd.metadata().await
+	}
+
+	async fn set_metadata(&self, _uid_and_gid: Option<(u32, u32)>, _permissions: Option<u32>, _atime_and_mtime: Option<(u32, u32)>) -> Result<()> {
+		Err(Error::PermissionDenied)
+	}
+
+	async fn statvfs(&self) -> Result<FsStats> {
+		let mut stats = self.fd.statvfs().await?;
+		stats.read_only = true;
+		Ok(stats)
+	}
+}
@@ -0,0 +1,376 @@
+use std::io;
+use std::mem;
+
+use tokio::sync::Mutex as AsyncMutex;
+
+use sftp_protocol::common::FsStats;
+use sftp_protocol::common::Metadata;
+use sftp_protocol::Error as ProtocolError;
+
+use super::config::Buffering;
+use super::file::PositionalFile;
+
+#[derive(Debug, Default)]
+struct ReadBuffer {
+	offset: u64,
+	data: Vec<u8>,
+	// The backend returned less than a full window, so nothing exists past the end of `data`
+	eof: bool,
+	// Where the next READ will start if the client is reading sequentially
+	next: u64
+}
+
+impl ReadBuffer {
+	fn get(&self, offset: u64, len: u32) -> Option<Vec<u8>> /* {{{ */ {
+		let end = self.offset + self.data.len() as u64;
+		if(offset < self.offset || offset >= end) {
+			return None;
+		}
+		let wanted = offset + len as u64;
+		if(wanted > end && !self.eof) {
+			return None;
+		}
+		let start = (offset - self.offset) as usize;
+		let stop = (wanted.min(end) - self.offset) as usize;
+		Some(self.data[start..stop].to_vec())
+	} // }}}
+
+	fn clear(&mut self) /* {{{ */ {
+		self.data = Vec::new();
+		self.eof = false;
+	} // }}}
+}
+
+#[derive(Debug, Default)]
+struct WriteBuffer {
+	offset: u64,
+	data: Vec<u8>,
+	// Set once a deferred write has failed; every later write and flush on the handle fails too, so the client can't
+	//    mistake a CLOSE for success after data was lost
+	failed: Option<String>
+}
+
+/// Serves sequential READs from a read-ahead window and coalesces consecutive WRITEs into larger backend writes.
+///    Buffered writes are flushed when a WRITE isn't contiguous with them, when the window fills, and before any
+///    other request on the handle; errors from those flushes are returned by whichever request triggered them.
+#[derive(Debug)]
+pub struct BufferedFile {
+	fd: Box<dyn PositionalFile>,
+	read_ahead: u32,
+	write_behind: u32,
+	reads: AsyncMutex<ReadBuffer>,
+	writes: AsyncMutex<WriteBuffer>
+}
+
+impl BufferedFile {
+	pub fn new(fd: Box<dyn PositionalFile>, buffering: &Buffering) -> Self /* {{{ */ {
+		Self{
+			fd: fd,
+			read_ahead: buffering.read_ahead,
+			write_behind: buffering.write_behind,
+			reads: AsyncMutex::new(ReadBuffer::default()),
+			writes: AsyncMutex::new(WriteBuffer::default())
+		}
+	} // }}}
+
+	async fn flush_writes(&self, writes: &mut WriteBuffer) -> Result<(), ProtocolError> /* {{{ */ {
+		if let Some(message) = &writes.failed {
+			return Err(io::Error::new(io::ErrorKind::Other, format!("earlier buffered write failed:  {}", message)).into());
+		}
+		if(writes.data.is_empty()) {
+			return Ok(());
+		}
+		let data = mem::take(&mut writes.data);
+		if let Err(e) = self.fd.write_at(writes.offset, &data).await {
+			writes.failed = Some(format!("{:?}", e));
+			return Err(e);
+		}
+		Ok(())
+	} // }}}
+
+	async fn flush_pending(&self) -> Result<(), ProtocolError> /* {{{ */ {
+		let mut writes = self.writes.lock().await;
+		self.flush_writes(&mut writes).await
+	} // }}}
+}
+
+#[async_trait]
+impl PositionalFile for BufferedFile {
+	async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>, ProtocolError> {
+		self.flush_pending().await?;
+		if(self.read_ahead == 0) {
+			return self.fd.read_at(offset, len).await;
+		}
+		let mut reads = self.reads.lock().await;
+		if let Some(data) = reads.get(offset, len) {
+			reads.next = offset + data.len() as u64;
+			return Ok(data);
+		}
+		if(reads.next != offset || len >= self.read_ahead) {
+			// Random access; don't hold up other requests on this handle while it's served, and don't keep a window
+			//    that's no longer being read from
+			reads.clear();
+			reads.next = offset + len as u64;
+			drop(reads);
+			return self.fd.read_at(offset, len).await;
+		}
+		let data = self.fd.read_at(offset, self.read_ahead).await?;
+		reads.eof = (data.len() as u32) < self.read_ahead;
+		reads.offset = offset;
+		reads.data = data;
+		let data = reads.get(offset, len).unwrap_or_default();
+		reads.next = offset + data.len() as u64;
+		Ok(data)
+	}
+
+	async fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), ProtocolError> {
+		self.reads.lock().await.clear();
+		let mut writes = self.writes.lock().await;
+		if(self.write_behind == 0) {
+			self.flush_writes(&mut writes).await?;
+			return self.fd.write_at(offset, data).await;
+		}
+		if(writes.offset + writes.data.len() as u64 != offset) {
+			self.flush_writes(&mut writes).await?;
+			writes.offset = offset;
+		} else if let Some(message) = &writes.failed {
+			return Err(io::Error::new(io::ErrorKind::Other, format!("earlier buffered write failed:  {}", message)).into());
+		}
+		writes.data.extend_from_slice(data);
+		if(writes.data.len() >= self.write_behind as usize) {
+			self.flush_writes(&mut writes).await?;
+			writes.offset = offset + data.len() as u64;
+		}
+		Ok(())
+	}
+
+	async fn len(&self) -> Result<u64, ProtocolError> {
+		self.flush_pending().await?;
+		self.fd.len().await
+	}
+
+	async fn flush(&self) -> Result<(), ProtocolError> {
+		self.flush_pending().await?;
+		self.fd.flush().await
+	}
+
+	async fn sync(&self) -> Result<(), ProtocolError> {
+		self.flush_pending().await?;
+		self.fd.sync().await
+	}
+
+	async fn close(&self) -> Result<(), ProtocolError> {
+		// The file underneath still needs to hear about it, but as an abort; e.g. an atomic upload that lost data
+		//    mustn't be put in place
+		if let Err(e) = self.flush_pending().await {
+			if let Err(abort_error) = self.fd.abort().await {
+				debug!("Failed to abort {:?} after a failed flush:  {:?}", self.fd, abort_error);
+			}
+			return Err(e);
+		}
+		self.fd.close().await
+	}
+
+	async fn abort(&self) -> Result<(), ProtocolError> {
+		let result = self.flush_pending().await;
+		self.fd.abort().await?;
+		result
+	}
+
+	async fn metadata(&self) -> Result<Metadata, ProtocolError> {
+		self.flush_pending().await?;
+		self.fd.metadata().await
+	}
+
+	async fn set_metadata(&self, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<(), ProtocolError> {
+		self.flush_pending().await?;
+		self.reads.lock().await.clear();
+		self.fd.set_metadata(uid_and_gid, permissions, atime_and_mtime).await
+	}
+
+	async fn statvfs(&self) -> Result<FsStats, ProtocolError> {
+		self.fd.statvfs().await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io;
+	use std::mem;
+	use std::sync::Arc;
+	use std::sync::Mutex;
+	use std::sync::atomic::AtomicBool;
+	use std::sync::atomic::Ordering;
+
+	use sftp_protocol::Error as ProtocolError;
+
+	use super::BufferedFile;
+	use super::Buffering;
+	use super::PositionalFile;
+
+	// A file in memory that logs each call that reaches it, and fails writes when told to
+	#[derive(Debug, Default)]
+	struct Backing {
+		data: Mutex<Vec<u8>>,
+		calls: Mutex<Vec<String>>,
+		fail_writes: AtomicBool
+	}
+
+	impl Backing {
+		fn calls(&self) -> Vec<String> /* {{{ */ {
+			mem::take(&mut *self.calls.lock().unwrap())
+		} // }}}
+
+		fn log(&self, call: String) /* {{{ */ {
+			self.calls.lock().unwrap().push(call);
+		} // }}}
+	}
+
+	#[derive(Debug)]
+	struct TestFile(Arc<Backing>);
+
+	#[async_trait]
+	impl PositionalFile for TestFile {
+		async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>, ProtocolError> {
+			self.0.log(format!("read {} {}", offset, len));
+			let data = self.0.data.lock().unwrap();
+			let start = (offset as usize).min(data.len());
+			let end = (offset as usize + len as usize).min(data.len());
+			Ok(data[start..end].to_vec())
+		}
+
+		async fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), ProtocolError> {
+			self.0.log(format!("write {} {}", offset, data.len()));
+			if(self.0.fail_writes.load(Ordering::SeqCst)) {
+				return Err(io::Error::new(io::ErrorKind::Other, "disk full").into());
+			}
+			let mut contents = self.0.data.lock().unwrap();
+			let end = offset as usize + data.len();
+			if(contents.len() < end) {
+				contents.resize(end, 0);
+			}
+			contents[offset as usize..end].copy_from_slice(data);
+			Ok(())
+		}
+
+		async fn len(&self) -> Result<u64, ProtocolError> {
+			Ok(self.0.data.lock().unwrap().len() as u64)
+		}
+
+		async fn sync(&self) -> Result<(), ProtocolError> {
+			self.0.log("sync".to_string());
+			Ok(())
+		}
+
+		async fn close(&self) -> Result<(), ProtocolError> {
+			self.0.log("close".to_string());
+			Ok(())
+		}
+
+		async fn abort(&self) -> Result<(), ProtocolError> {
+			self.0.log("abort".to_string());
+			Ok(())
+		}
+	}
+
+	fn buffered(contents: &[u8], read_ahead: u32, write_behind: u32) -> (Arc<Backing>, BufferedFile) /* {{{ */ {
+		let backing = Arc::new(Backing::default());
+		*backing.data.lock().unwrap() = contents.to_vec();
+		let file = BufferedFile::new(Box::new(TestFile(backing.clone())), &Buffering{read_ahead: read_ahead, write_behind: write_behind});
+		(backing, file)
+	} // }}}
+
+	#[tokio::test]
+	async fn writes_coalesce_until_a_gap() -> Result<(), ProtocolError> {
+		let (backing, file) = buffered(b"", 0, 16);
+		file.write_at(0, b"abcd").await?;
+		file.write_at(4, b"efgh").await?;
+		assert!(backing.calls().is_empty());
+		file.write_at(12, b"ijkl").await?;
+		assert_eq!(backing.calls(), vec!["write 0 8"]);
+		file.close().await?;
+		assert_eq!(backing.calls(), vec!["write 12 4", "close"]);
+		assert_eq!(&*backing.data.lock().unwrap(), b"abcdefgh\0\0\0\0ijkl");
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn writes_flush_when_the_window_fills() -> Result<(), ProtocolError> {
+		let (backing, file) = buffered(b"", 0, 8);
+		file.write_at(0, b"abcdef").await?;
+		assert!(backing.calls().is_empty());
+		file.write_at(6, b"ghijkl").await?;
+		assert_eq!(backing.calls(), vec!["write 0 12"]);
+		// The next contiguous write starts a new window
+		file.write_at(12, b"mn").await?;
+		assert!(backing.calls().is_empty());
+		file.sync().await?;
+		assert_eq!(backing.calls(), vec!["write 12 2", "sync"]);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn failed_flush_surfaces_on_the_next_request() -> Result<(), ProtocolError> {
+		let (backing, file) = buffered(b"", 0, 16);
+		backing.fail_writes.store(true, Ordering::SeqCst);
+		// Accepted, since it's only buffered
+		file.write_at(0, b"abcd").await?;
+		assert!(file.read_at(0, 4).await.is_err());
+		assert_eq!(backing.calls(), vec!["write 0 4"]);
+		// Once data has been lost, nothing more is accepted, even after the backend recovers
+		backing.fail_writes.store(false, Ordering::SeqCst);
+		assert!(file.write_at(4, b"efgh").await.is_err());
+		assert!(file.sync().await.is_err());
+		assert!(file.close().await.is_err());
+		assert_eq!(backing.calls(), vec!["abort"]);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn failed_flush_fails_close() -> Result<(), ProtocolError> {
+		let (backing, file) = buffered(b"", 0, 16);
+		backing.fail_writes.store(true, Ordering::SeqCst);
+		file.write_at(0, b"abcd").await?;
+		// The file underneath is aborted rather than closed, so e.g. an atomic upload isn't put in place
+		assert!(file.close().await.is_err());
+		assert_eq!(backing.calls(), vec!["write 0 4", "abort"]);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn sequential_reads_come_from_the_window() -> Result<(), ProtocolError> {
+		let (backing, file) = buffered(b"0123456789abcdef", 8, 0);
+		assert_eq!(file.read_at(0, 2).await?, b"01");
+		assert_eq!(file.read_at(2, 2).await?, b"23");
+		assert_eq!(file.read_at(4, 4).await?, b"4567");
+		assert_eq!(backing.calls(), vec!["read 0 8"]);
+		assert_eq!(file.read_at(8, 4).await?, b"89ab");
+		assert_eq!(file.read_at(12, 4).await?, b"cdef");
+		assert_eq!(file.read_at(16, 4).await?, b"");
+		assert_eq!(backing.calls(), vec!["read 8 8", "read 16 8"]);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn random_read_drops_the_window() -> Result<(), ProtocolError> {
+		let (backing, file) = buffered(b"0123456789abcdef", 8, 0);
+		assert_eq!(file.read_at(0, 2).await?, b"01");
+		backing.data.lock().unwrap()[2] = b'X';
+		assert_eq!(file.read_at(12, 2).await?, b"cd");
+		// Back within the old window, but it's gone, so this sees the file as it is now
+		assert_eq!(file.read_at(2, 2).await?, b"X3");
+		assert_eq!(backing.calls(), vec!["read 0 8", "read 12 2", "read 2 2"]);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn writes_drop_the_window() -> Result<(), ProtocolError> {
+		let (backing, file) = buffered(b"0123456789abcdef", 8, 8);
+		assert_eq!(file.read_at(0, 2).await?, b"01");
+		file.write_at(2, b"XY").await?;
+		// The write is flushed before the read, which then can't be served from the stale window
+		assert_eq!(file.read_at(2, 2).await?, b"XY");
+		assert_eq!(backing.calls(), vec!["read 0 8", "write 2 2", "read 2 8"]);
+		Ok(())
+	}
+}
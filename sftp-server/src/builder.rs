@@ -0,0 +1,384 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use anyhow::Error;
+
+use thrussh_keys::key::KeyPair;
+
+use super::Server;
+use super::SessionContext;
+use super::SessionInfo;
+use super::ShutdownSummary;
+use super::auth::Authenticator;
+use super::backend::BackendFactory;
+use super::backend::DynBackend;
+use super::config::Config;
+use super::config::Limits;
+use super::session::Hooks;
+use super::shutdown::Signal;
+
+// How often a graceful shutdown checks whether every connection has finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Once the grace period is up, how long clients get to answer their channels being closed, so that they can be sent a
+//    disconnect before their connections are dropped
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// How long cut-off sessions get to abort their open files before shutdown stops waiting for them
+const ABORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sets up and starts an SSH server that serves SFTP, for embedding in other programs:
+///
+/// ```ignore
+/// let handle = ServerBuilder::new()
+/// 	.listen("127.0.0.1:0")
+/// 	.backend(backend)
+/// 	.authenticator(authenticator)
+/// 	.start()
+/// 	.await?;
+/// println!("listening on {}", handle.local_addr());
+/// ```
+pub struct ServerBuilder {
+	listen: Vec<String>,
+	keys: Vec<KeyPair>,
+	authenticator: Option<Arc<dyn Authenticator>>,
+	backends: Option<Arc<dyn BackendFactory>>,
+	config: Config,
+	hooks: Option<Arc<dyn Hooks>>,
+	ssh_config: thrussh::server::Config
+}
+
+impl Default for ServerBuilder {
+	fn default() -> Self /* {{{ */ {
+		let mut ssh_config = thrussh::server::Config::default();
+		ssh_config.connection_timeout = Some(Duration::from_secs(60));
+		ssh_config.auth_rejection_time = Duration::from_secs(1);
+		Self{
+			listen: Vec::new(),
+			keys: Vec::new(),
+			authenticator: None,
+			backends: None,
+			config: Config::default(),
+			hooks: None,
+			ssh_config: ssh_config
+		}
+	} // }}}
+}
+
+impl ServerBuilder {
+	pub fn new() -> Self /* {{{ */ {
+		Self::default()
+	} // }}}
+
+	// May be called more than once to listen on several addresses; use port 0 for an ephemeral port
+	pub fn listen(mut self, addr: impl Into<String>) -> Self /* {{{ */ {
+		self.listen.push(addr.into());
+		self
+	} // }}}
+
+	// If no host keys are given, an ED25519 key is generated when the server starts
+	pub fn host_key(mut self, key: KeyPair) -> Self /* {{{ */ {
+		self.keys.push(key);
+		self
+	} // }}}
+
+	// Required; there's no default, so a server can't end up open to everyone by accident.  Tests that want that can
+	//    ask for `auth::AcceptAll`.
+	pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self /* {{{ */ {
+		self.authenticator = Some(Arc::new(authenticator));
+		self
+	} // }}}
+
+	// Serves the same backend to every user
+	pub fn backend(self, backend: impl DynBackend + 'static) -> Self /* {{{ */ {
+		let backend: Arc<dyn DynBackend> = Arc::new(backend);
+		self.backend_factory(move |_: &SessionContext| -> Result<Arc<dyn DynBackend>, Error> { Ok(backend.clone()) })
+	} // }}}
+
+	// Chooses each session's backend once its user has authenticated
+	pub fn backend_factory(mut self, factory: impl BackendFactory + 'static) -> Self /* {{{ */ {
+		self.backends = Some(Arc::new(factory));
+		self
+	} // }}}
+
+	pub fn config(mut self, config: Config) -> Self /* {{{ */ {
+		self.config = config;
+		self
+	} // }}}
+
+	pub fn limits(mut self, limits: Limits) -> Self /* {{{ */ {
+		self.config.limits = limits;
+		self
+	} // }}}
+
+	pub fn hooks(mut self, hooks: impl Hooks + 'static) -> Self /* {{{ */ {
+		self.hooks = Some(Arc::new(hooks));
+		self
+	} // }}}
+
+	// For SSH-level settings not covered above, such as timeouts and allowed authentication methods
+	pub fn ssh_config(mut self, f: impl FnOnce(&mut thrussh::server::Config)) -> Self /* {{{ */ {
+		f(&mut self.ssh_config);
+		self
+	} // }}}
+
+	/// Binds every listen address and starts accepting connections
+	pub async fn start(self) -> Result<ServerHandle, Error> /* {{{ */ {
+		if(self.listen.is_empty()) {
+			return Err(Error::msg("ServerBuilder needs at least one listen address"));
+		}
+		let backends = self.backends.ok_or_else(|| Error::msg("ServerBuilder needs a backend or backend factory"))?;
+		let authenticator = self.authenticator.ok_or_else(|| Error::msg("ServerBuilder needs an authenticator"))?;
+		let mut ssh_config = self.ssh_config;
+		ssh_config.keys = self.keys;
+		if(ssh_config.keys.is_empty()) {
+			ssh_config.keys.push(KeyPair::generate_ed25519().ok_or_else(|| Error::msg("Failed to generate a host key"))?);
+		}
+		// thrussh's timeout only sees SSH traffic, so it would otherwise cut off idle SFTP sessions that the session
+		//    policy still allows
+		if let (Some(idle), Some(timeout)) = (self.config.sessions.idle_timeout, ssh_config.connection_timeout) {
+			if(idle > timeout) {
+				ssh_config.connection_timeout = Some(idle);
+			}
+		}
+		let ssh_config = Arc::new(ssh_config);
+
+		let mut server = Server::from_factory(backends, 0).with_config(self.config);
+		server.authenticator = authenticator;
+		if let Some(hooks) = self.hooks {
+			server.hooks = hooks;
+		}
+
+		let stopped = Signal::new();
+		let connections = Arc::new(AtomicUsize::new(0));
+		let mut local_addrs = Vec::with_capacity(self.listen.len());
+		let mut tasks = Vec::with_capacity(self.listen.len());
+		for addr in &self.listen {
+			let listener = TcpListener::bind(addr.as_str()).await?;
+			let local_addr = listener.local_addr()?;
+			info!("Listening for SSH connections on {}", local_addr);
+			local_addrs.push(local_addr);
+			tasks.push(tokio::spawn(accept(listener, ssh_config.clone(), server.clone(), stopped.clone(), connections.clone())));
+		}
+		Ok(ServerHandle{
+			local_addrs: local_addrs,
+			server: server,
+			stopped: stopped,
+			connections: connections,
+			tasks: tasks
+		})
+	} // }}}
+}
+
+async fn accept(mut listener: TcpListener, ssh_config: Arc<thrussh::server::Config>, mut server: Server, stopped: Signal, connections: Arc<AtomicUsize>) /* {{{ */ {
+	loop {
+		let (stream, peer) = tokio::select! {
+			result = listener.accept() => match result {
+				Ok(v) => v,
+				Err(e) => {
+					warn!("Failed to accept connection:  {:?}", e);
+					continue;
+				}
+			},
+			_ = stopped.wait() => break
+		};
+		let handler = thrussh::server::Server::new(&mut server, Some(peer));
+		let ssh_config = ssh_config.clone();
+		let closing = server.closing.clone();
+		let connections = connections.clone();
+		connections.fetch_add(1, Ordering::SeqCst);
+		tokio::spawn(async move {
+			tokio::select! {
+				result = thrussh::server::run_stream(ssh_config, stream, handler) => {
+					if let Err(e) = result {
+						debug!("Connection from {} ended with an error:  {:?}", peer, e);
+					}
+				},
+				// Dropping the connection's future closes it
+				_ = closing.wait() => debug!("Dropping connection from {} for shutdown", peer)
+			};
+			connections.fetch_sub(1, Ordering::SeqCst);
+		});
+	}
+} // }}}
+
+// Closes every channel that still has a session on it.  Clients answer by closing their end, which is when
+//    `channel_close()` sends them a disconnect; unlike waiting for sessions to close channels themselves, that works
+//    even for one stuck in a backend call.
+async fn close_channels(server: &Server) /* {{{ */ {
+	let clients: Vec<_> = server.clients.lock().unwrap().iter().map(|(&(_, channel), handle)| (channel, handle.clone())).collect();
+	for (channel, mut handle) in clients {
+		let _ = handle.eof(channel).await;
+		let _ = handle.close(channel).await;
+	}
+} // }}}
+
+/// A running server, as returned by `ServerBuilder::start()`.  Dropping the handle shuts the server down.
+pub struct ServerHandle {
+	local_addrs: Vec<SocketAddr>,
+	server: Server,
+	// Triggered to stop the listeners; connections they've already accepted carry on
+	stopped: Signal,
+	connections: Arc<AtomicUsize>,
+	tasks: Vec<JoinHandle<()>>
+}
+
+impl ServerHandle {
+	// The address of the first listener, with the actual port if an ephemeral one was requested
+	pub fn local_addr(&self) -> SocketAddr /* {{{ */ {
+		self.local_addrs[0]
+	} // }}}
+
+	pub fn local_addrs(&self) -> &[SocketAddr] /* {{{ */ {
+		&self.local_addrs
+	} // }}}
+
+	pub fn sessions(&self) -> Vec<SessionInfo> /* {{{ */ {
+		self.server.sessions()
+	} // }}}
+
+	/// Stops accepting connections and closes every open one, abandoning any requests still outstanding
+	pub async fn shutdown(self) /* {{{ */ {
+		self.shutdown_gracefully(Duration::from_secs(0)).await;
+	} // }}}
+
+	/// Stops accepting connections, then gives open sessions up to `grace` to answer their outstanding requests and
+	///    close their files, after which each client is sent a disconnect.  Anything still running once the grace
+	///    period is up is cut off:  its channels are closed, so that its client can still be disconnected, then its
+	///    remaining requests are abandoned, its open files aborted, and its connection dropped.  Returns once every
+	///    cut-off session has aborted its files, or after a further few seconds if some can't.
+	pub async fn shutdown_gracefully(mut self, grace: Duration) -> ShutdownSummary /* {{{ */ {
+		self.stopped.trigger();
+		for task in self.tasks.drain(..) {
+			let _ = task.await;
+		}
+		self.server.draining.trigger();
+		let deadline = Instant::now() + grace;
+		while(self.connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline) {
+			tokio::time::delay_for(DRAIN_POLL_INTERVAL).await;
+		}
+		let cut_off = self.server.sessions();
+		close_channels(&self.server).await;
+		let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+		while(self.connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline) {
+			tokio::time::delay_for(DRAIN_POLL_INTERVAL).await;
+		}
+		let dropped_connections = self.connections.load(Ordering::SeqCst);
+		self.server.closing.trigger();
+		// Cut-off sessions still abort their open files on the way out; wait for that, but not on a stuck backend
+		let deadline = Instant::now() + ABORT_TIMEOUT;
+		while(!self.server.sessions().is_empty() && Instant::now() < deadline) {
+			tokio::time::delay_for(DRAIN_POLL_INTERVAL).await;
+		}
+		ShutdownSummary{
+			cut_off: cut_off,
+			dropped_connections: dropped_connections,
+			abandoned: self.server.sessions()
+		}
+	} // }}}
+
+	/// Serves until the process exits; for programs whose only job is to serve SFTP
+	pub async fn wait(mut self) /* {{{ */ {
+		for task in self.tasks.drain(..) {
+			let _ = task.await;
+		}
+	} // }}}
+}
+
+impl Drop for ServerHandle {
+	fn drop(&mut self) {
+		self.stopped.trigger();
+		self.server.draining.trigger();
+		self.server.closing.trigger();
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+	use std::time::Duration;
+
+	use tokio::io::AsyncReadExt;
+	use tokio::net::TcpStream;
+
+	use anyhow::Error;
+
+	use super::ServerBuilder;
+	use crate::auth::AcceptAll;
+	use crate::backend::Unassigned;
+
+	fn builder() -> ServerBuilder /* {{{ */ {
+		ServerBuilder::new().backend(Unassigned).authenticator(AcceptAll)
+	} // }}}
+
+	// The identification line an SSH server sends as soon as a client connects
+	async fn banner(addr: SocketAddr) -> Result<String, Error> /* {{{ */ {
+		let mut stream = TcpStream::connect(addr).await?;
+		let mut line = Vec::new();
+		loop {
+			match stream.read_u8().await? {
+				b'\n' => break,
+				byte => line.push(byte)
+			};
+		}
+		Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+	} // }}}
+
+	#[tokio::test]
+	async fn ephemeral_port() -> Result<(), Error> {
+		let handle = builder().listen("127.0.0.1:0").start().await?;
+		let addr = handle.local_addr();
+		assert_ne!(addr.port(), 0);
+		assert!(banner(addr).await?.starts_with("SSH-2.0-"));
+		assert!(handle.sessions().is_empty());
+		handle.shutdown().await;
+		assert!(TcpStream::connect(addr).await.is_err());
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn several_listeners() -> Result<(), Error> {
+		let handle = builder().listen("127.0.0.1:0").listen("127.0.0.1:0").start().await?;
+		let addrs = handle.local_addrs().to_vec();
+		assert_eq!(addrs.len(), 2);
+		assert_ne!(addrs[0], addrs[1]);
+		assert_eq!(handle.local_addr(), addrs[0]);
+		for addr in addrs.iter() {
+			assert!(banner(*addr).await?.starts_with("SSH-2.0-"));
+		}
+		handle.shutdown().await;
+		for addr in addrs.iter() {
+			assert!(TcpStream::connect(addr).await.is_err());
+		}
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn dropping_the_handle_stops_the_server() -> Result<(), Error> {
+		let handle = builder().listen("127.0.0.1:0").start().await?;
+		let addr = handle.local_addr();
+		drop(handle);
+		// The listener closes once its task sees the signal
+		for _ in 0..100 {
+			if(TcpStream::connect(addr).await.is_err()) {
+				return Ok(());
+			}
+			tokio::time::delay_for(Duration::from_millis(10)).await;
+		}
+		Err(Error::msg("Still listening after the handle was dropped"))
+	}
+
+	#[tokio::test]
+	async fn incomplete() {
+		assert!(ServerBuilder::new().backend(Unassigned).authenticator(AcceptAll).start().await.is_err());
+		assert!(ServerBuilder::new().listen("127.0.0.1:0").authenticator(AcceptAll).start().await.is_err());
+		assert!(ServerBuilder::new().listen("127.0.0.1:0").backend(Unassigned).start().await.is_err());
+		assert!(builder().listen("not an address").start().await.is_err());
+	}
+}
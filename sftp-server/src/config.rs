@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::stream::packet::open::OpenFlags;
+use sftp_protocol::Payload;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ModePolicy {
+	pub umask: u32,
+	// Modes used when the client doesn't request any permissions for a newly-created file or directory
+	pub file_mode: u32,
+	pub dir_mode: u32,
+	// Let clients set the setuid, setgid and sticky bits on what they create; otherwise only the permission bits are kept
+	pub allow_special_bits: bool
+}
+
+impl Default for ModePolicy {
+	fn default() -> Self /* {{{ */ {
+		Self{
+			umask: 0o022,
+			file_mode: 0o666,
+			dir_mode: 0o777,
+			allow_special_bits: false
+		}
+	} // }}}
+}
+
+impl ModePolicy {
+	pub fn new(umask: u32) -> Self /* {{{ */ {
+		Self{
+			umask: umask,
+			..Default::default()
+		}
+	} // }}}
+
+	/// Returns a copy of the client-requested attributes with the permissions resolved against this policy,
+	///    so that the backend always receives the exact mode the new file or directory should be created with.
+	pub fn apply(&self, attrs: &FileAttributes, is_dir: bool) -> FileAttributes /* {{{ */ {
+		let requested = match attrs.get_permissions() {
+			Some(v) => v,
+			None => match is_dir {
+				true => self.dir_mode,
+				false => self.file_mode
+			}
+		};
+		let mut attrs = attrs.clone();
+		attrs.set_permissions(requested & self.allowed_bits() & !self.umask);
+		attrs
+	} // }}}
+
+	/// The mode bits a client may give what it creates:  the permission bits, plus the setuid, setgid and sticky bits
+	///    if `allow_special_bits` is set
+	pub fn allowed_bits(&self) -> u32 /* {{{ */ {
+		match self.allow_special_bits {
+			true => 0o7777,
+			false => 0o777
+		}
+	} // }}}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+	// READ requests asking for more than this are clamped rather than rejected, as the spec allows short reads
+	pub max_read_len: u32,
+	pub max_write_len: u32,
+	pub max_open_files: usize,
+	pub max_open_dirs: usize,
+	// Requests processed at once; beyond this, the session stops reading from the client until some are answered
+	pub max_in_flight: usize,
+	// Maximum number of entries returned by a single READDIR
+	pub max_dir_batch: usize
+}
+
+impl Default for Limits {
+	fn default() -> Self /* {{{ */ {
+		Self{
+			max_read_len: 255 * 1024,
+			max_write_len: 255 * 1024,
+			max_open_files: 512,
+			max_open_dirs: 64,
+			max_in_flight: 64,
+			max_dir_batch: 100
+		}
+	} // }}}
+}
+
+impl Limits {
+	// Largest packet we're willing to buffer:  a maximum-size WRITE plus room for its header and handle
+	pub fn max_packet_len(&self) -> u32 /* {{{ */ {
+		self.max_write_len + 1024
+	} // }}}
+}
+
+/// Per-handle buffering of sequential transfers; a window of 0 disables that direction.  Off by default:  a handle's
+///    read-ahead window doesn't see writes made through other handles (or outside the server) while it's being read,
+///    so only enable it where files aren't changed while they're being downloaded.
+#[derive(Clone, Copy, Debug)]
+pub struct Buffering {
+	// How much to read from the backend at once when a handle is being read sequentially
+	pub read_ahead: u32,
+	// How many bytes of consecutive WRITEs to coalesce before passing them to the backend
+	pub write_behind: u32
+}
+
+impl Default for Buffering {
+	fn default() -> Self /* {{{ */ {
+		Self{
+			read_ahead: 0,
+			write_behind: 0
+		}
+	} // }}}
+}
+
+impl Buffering {
+	pub fn is_enabled(&self) -> bool /* {{{ */ {
+		self.read_ahead > 0 || self.write_behind > 0
+	} // }}}
+}
+
+/// Which combinations of open handles on the same file the server allows, across all sessions; see `LockKey` for how
+///    files are told apart
+#[derive(Clone, Copy, Debug)]
+pub struct LockPolicy {
+	// Refuse to open a file for writing (or to remove or rename it) while another handle is writing it
+	pub exclusive_writers: bool,
+	// Also refuse to open a file for reading while it's being written, and for writing while it's being read
+	pub exclude_readers: bool
+}
+
+impl Default for LockPolicy {
+	fn default() -> Self /* {{{ */ {
+		Self{
+			exclusive_writers: true,
+			exclude_readers: false
+		}
+	} // }}}
+}
+
+/// Where and how to record sessions for later replay; see `record::Recorder`
+#[derive(Clone, Debug)]
+pub struct Recording {
+	// Each session is written to its own file in this directory, which must already exist
+	pub dir: PathBuf,
+	// Leave the contents of WRITE and DATA packets out of recordings, keeping only their lengths
+	pub redact_data: bool
+}
+
+/// Limits on how long sessions may run and how many may run at once; None means unlimited
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SessionPolicy {
+	// End a session once the client has sent no requests for this long, whether or not it has handles open
+	pub idle_timeout: Option<Duration>,
+	// End a session this long after it started, no matter how busy it is
+	pub max_lifetime: Option<Duration>,
+	// Refuse new sessions for a user or source address that already has this many running
+	pub max_per_user: Option<usize>,
+	pub max_per_address: Option<usize>
+}
+
+/// Allow and deny lists of request names, as with OpenSSH sftp-server's -p and -P flags.  Names are those used by
+///    OpenSSH (e.g. "open", "setstat", "posix-rename"); extension requests are named without their "@openssh.com" suffix.
+#[derive(Clone, Debug, Default)]
+pub struct RequestFilter {
+	pub allowed: Option<HashSet<String>>,
+	pub denied: HashSet<String>
+}
+
+impl RequestFilter {
+	pub fn permits(&self, name: &str) -> bool /* {{{ */ {
+		if(self.denied.contains(name)) {
+			return false;
+		}
+		match &self.allowed {
+			Some(allowed) => allowed.contains(name),
+			None => true
+		}
+	} // }}}
+}
+
+/// Expands the escapes sftp-server accepts in its start directory:  "%d" for the user's home directory, "%u" for the
+///    username and "%%" for a literal "%"
+pub fn expand_start_dir(template: &str, user: &str, home: &str) -> Result<String, String> /* {{{ */ {
+	let mut result = String::new();
+	let mut chars = template.chars();
+	while let Some(c) = chars.next() {
+		if(c != '%') {
+			result.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('d') => result.push_str(home),
+			Some('u') => result.push_str(user),
+			Some('%') => result.push('%'),
+			Some(other) => return Err(format!("Unknown escape \"%{}\" in start directory", other)),
+			None => return Err("Start directory ends with \"%\"".to_string())
+		};
+	}
+	Ok(result)
+} // }}}
+
+pub fn request_name(payload: &Payload) -> Option<String> /* {{{ */ {
+	let name = match payload {
+		Payload::Open(_) => "open",
+		Payload::Close(_) => "close",
+		Payload::Read(_) => "read",
+		Payload::Write(_) => "write",
+		Payload::Lstat(_) => "lstat",
+		Payload::Fstat(_) => "fstat",
+		Payload::SetStat(_) => "setstat",
+		Payload::FSetStat(_) => "fsetstat",
+		Payload::OpenDir(_) => "opendir",
+		Payload::ReadDir(_) => "readdir",
+		Payload::Remove(_) => "remove",
+		Payload::MkDir(_) => "mkdir",
+		Payload::RmDir(_) => "rmdir",
+		Payload::RealPath(_) => "realpath",
+		Payload::Stat(_) => "stat",
+		Payload::Rename(_) => "rename",
+		Payload::ReadLink(_) => "readlink",
+		Payload::Symlink(_) => "symlink",
+		Payload::Extended(r) => return Some(r.request.trim_end_matches("@openssh.com").to_string()),
+		_ => return None
+	};
+	Some(name.to_string())
+} // }}}
+
+// Extension requests (by request_name()) that modify the filesystem
+const WRITE_EXTENSIONS: &[&str] = &["posix-rename", "hardlink", "fsync", "lsetstat", "copy-data"];
+
+pub fn is_write_request(payload: &Payload) -> bool /* {{{ */ {
+	match payload {
+		Payload::Open(r) => r.pflags.intersects(OpenFlags::Write | OpenFlags::Append | OpenFlags::Create | OpenFlags::Truncate),
+		Payload::Write(_) => true,
+		Payload::SetStat(_) => true,
+		Payload::FSetStat(_) => true,
+		Payload::Remove(_) => true,
+		Payload::MkDir(_) => true,
+		Payload::RmDir(_) => true,
+		Payload::Rename(_) => true,
+		Payload::Symlink(_) => true,
+		Payload::Extended(_) => request_name(payload).map(|n| WRITE_EXTENSIONS.contains(&n.as_str())).unwrap_or(false),
+		_ => false
+	}
+} // }}}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+	pub mode_policy: ModePolicy,
+	pub user_mode_policies: HashMap<String, ModePolicy>,
+	pub limits: Limits,
+	pub buffering: Buffering,
+	pub locking: LockPolicy,
+	pub sessions: SessionPolicy,
+	// Off unless set
+	pub recording: Option<Recording>,
+	// Subsystem names that will be served as SFTP; requests for any other subsystem are refused
+	pub sftp_subsystems: Vec<String>,
+	// Also serve legacy SCP ("scp -t" and "scp -f" exec requests) against the same backend
+	pub scp: bool,
+	pub read_only: bool,
+	pub request_filter: RequestFilter,
+	// Directory that relative paths are resolved against instead of the user's home, like sftp-server's -d; see
+	//    expand_start_dir() for the escapes it may contain
+	pub start_dir: Option<String>,
+	pub home_dirs: HashMap<String, String>
+}
+
+impl Default for Config {
+	fn default() -> Self /* {{{ */ {
+		Self{
+			mode_policy: ModePolicy::default(),
+			user_mode_policies: HashMap::new(),
+			limits: Limits::default(),
+			buffering: Buffering::default(),
+			locking: LockPolicy::default(),
+			sessions: SessionPolicy::default(),
+			recording: None,
+			sftp_subsystems: vec!["sftp".to_string()],
+			scp: false,
+			read_only: false,
+			request_filter: RequestFilter::default(),
+			start_dir: None,
+			home_dirs: HashMap::new()
+		}
+	} // }}}
+}
+
+impl Config {
+	pub fn mode_policy(&self, user: Option<&str>) -> &ModePolicy /* {{{ */ {
+		user.and_then(|u| self.user_mode_policies.get(u)).unwrap_or(&self.mode_policy)
+	} // }}}
+}
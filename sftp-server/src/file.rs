@@ -1,94 +1,259 @@
-use core::task::Context;
-use core::task::Poll;
-use core::task::Poll::Ready;
-
 use std::fmt;
-use std::pin::Pin;
 
 use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
 use tokio::io::AsyncSeek;
+use tokio::io::AsyncSeekExt;
 use tokio::io::AsyncWrite;
-use tokio::io::Error;
+use tokio::io::AsyncWriteExt;
 use tokio::io::SeekFrom;
+use tokio::sync::Mutex as AsyncMutex;
 
+use sftp_protocol::common::FsStats;
 use sftp_protocol::common::Metadata;
+use sftp_protocol::Error as ProtocolError;
 
+/// Stream-oriented file, for backends that can seek cheaply; these are served through `SeekableFile`.  Streams have
+///    no fstat() of their own, so backends that can do better should implement `PositionalFile` instead.
 pub trait File: AsyncRead + AsyncSeek + AsyncWrite + Send + Sync + Unpin + fmt::Debug {}
 impl<T> File for T where T: AsyncRead + AsyncSeek + AsyncWrite + Send + Sync + Unpin + fmt::Debug {}
 
+/// Positional file access, which is what the server uses to serve READ and WRITE.  There's no shared cursor, so
+///    implementations that can (e.g. with pread()/pwrite()) may serve concurrent requests on one handle in parallel.
+#[async_trait]
+pub trait PositionalFile: Send + Sync + fmt::Debug {
+	// Reads up to `len` bytes starting at `offset`; an empty result means `offset` is at or past the end of the file
+	async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>, ProtocolError>;
+	// Writing past the current end of the file must leave the gap reading back as zeros, either as a hole or by
+	//    explicitly filling it; clients pipeline WRITEs, so they can arrive out of order
+	async fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), ProtocolError>;
+	async fn len(&self) -> Result<u64, ProtocolError>;
+
+	// Pushes any writes buffered by the implementation through to the backing store
+	async fn flush(&self) -> Result<(), ProtocolError> {
+		Ok(())
+	}
+
+	// Equivalent of fsync(); implementations without durable storage of their own can rely on flush()
+	async fn sync(&self) -> Result<(), ProtocolError> {
+		self.flush().await
+	}
+
+	// Called when the client closes the handle; any error is reported as the result of the CLOSE
+	async fn close(&self) -> Result<(), ProtocolError> {
+		self.flush().await
+	}
+
+	// Called instead of close() when the session ends without the client having closed the handle
+	async fn abort(&self) -> Result<(), ProtocolError> {
+		self.flush().await
+	}
+
+	// Equivalent of fstat(); implementations should query the open descriptor rather than the path it was opened from.
+	//    Without it, FSTAT reports the metadata the backend returned from open().
+	async fn metadata(&self) -> Result<Metadata, ProtocolError> {
+		Err(ProtocolError::Unsupported)
+	}
+
+	// Equivalent of fchown()/fchmod()/futimens()
+	async fn set_metadata(&self, _uid_and_gid: Option<(u32, u32)>, _permissions: Option<u32>, _atime_and_mtime: Option<(u32, u32)>) -> Result<(), ProtocolError> {
+		Err(ProtocolError::Unsupported)
+	}
+
+	// Equivalent of fstatvfs(); serves fstatvfs@openssh.com
+	async fn statvfs(&self) -> Result<FsStats, ProtocolError> {
+		Err(ProtocolError::Unsupported)
+	}
+}
+
+// Lets middleware hold the file it's wrapping as-is
+#[async_trait]
+impl PositionalFile for Box<dyn PositionalFile> {
+	async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>, ProtocolError> {
+		(**self).read_at(offset, len).await
+	}
+
+	async fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), ProtocolError> {
+		(**self).write_at(offset, data).await
+	}
+
+	async fn len(&self) -> Result<u64, ProtocolError> {
+		(**self).len().await
+	}
+
+	async fn flush(&self) -> Result<(), ProtocolError> {
+		(**self).flush().await
+	}
+
+	async fn sync(&self) -> Result<(), ProtocolError> {
+		(**self).sync().await
+	}
+
+	async fn close(&self) -> Result<(), ProtocolError> {
+		(**self).close().await
+	}
+
+	async fn abort(&self) -> Result<(), ProtocolError> {
+		(**self).abort().await
+	}
+
+	async fn metadata(&self) -> Result<Metadata, ProtocolError> {
+		(**self).metadata().await
+	}
+
+	async fn set_metadata(&self, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<(), ProtocolError> {
+		(**self).set_metadata(uid_and_gid, permissions, atime_and_mtime).await
+	}
+
+	async fn statvfs(&self) -> Result<FsStats, ProtocolError> {
+		(**self).statvfs().await
+	}
+}
+
+// Largest buffer of zeros SeekableFile will allocate at once when filling a gap
+pub(crate) const ZERO_FILL_CHUNK: u64 = 64 * 1024;
+
+/// Adapts a seekable stream to `PositionalFile`; each call holds the stream exclusively while it seeks and transfers.
+///    Streams can't be relied on to create holes, so writes past the end are preceded by explicit zeros.
+#[derive(Debug)]
+pub struct SeekableFile<F> {
+	stream: AsyncMutex<F>
+}
+
+impl<F: File> SeekableFile<F> {
+	pub fn new(stream: F) -> Self /* {{{ */ {
+		Self{
+			stream: AsyncMutex::new(stream)
+		}
+	} // }}}
+}
+
+#[async_trait]
+impl<F: File> PositionalFile for SeekableFile<F> {
+	async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>, ProtocolError> {
+		let mut stream = self.stream.lock().await;
+		stream.seek(SeekFrom::Start(offset)).await?;
+		let mut data = vec![0u8; len as usize];
+		let mut filled = 0;
+		while(filled < data.len()) {
+			let count = stream.read(&mut data[filled..]).await?;
+			if(count == 0) {
+				break;
+			}
+			filled += count;
+		}
+		data.truncate(filled);
+		Ok(data)
+	}
+
+	async fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), ProtocolError> {
+		let mut stream = self.stream.lock().await;
+		let mut len = stream.seek(SeekFrom::End(0)).await?;
+		if(len < offset) {
+			let zeros = vec![0u8; (offset - len).min(ZERO_FILL_CHUNK) as usize];
+			while(len < offset) {
+				let count = (offset - len).min(zeros.len() as u64) as usize;
+				stream.write_all(&zeros[..count]).await?;
+				len += count as u64;
+			}
+		} else {
+			stream.seek(SeekFrom::Start(offset)).await?;
+		}
+		stream.write_all(data).await?;
+		Ok(())
+	}
+
+	async fn len(&self) -> Result<u64, ProtocolError> {
+		Ok(self.stream.lock().await.seek(SeekFrom::End(0)).await?)
+	}
+
+	async fn flush(&self) -> Result<(), ProtocolError> {
+		Ok(self.stream.lock().await.flush().await?)
+	}
+}
+
 pub struct OpenFile {
 	pub metadata: Metadata,
-	pub pos: u64,
-	pub fd: Pin<Box<dyn File>>,
+	pub fd: Box<dyn PositionalFile>
 }
 
 impl OpenFile {
 	pub fn new(metadata: Metadata, stream: impl File + 'static) -> Self {
+		Self::positional(metadata, SeekableFile::new(stream))
+	}
+
+	pub fn positional(metadata: Metadata, file: impl PositionalFile + 'static) -> Self {
 		Self{
 			metadata: metadata,
-			pos: 0,
-			fd: Box::pin(stream)
+			fd: Box::new(file)
 		}
 	}
-}
 
-impl fmt::Debug for OpenFile {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		f.debug_struct("OpenFile")
-			.field("pos", &self.pos)
-			.field("fd", &self.fd)
-			.finish()
+	/// Replaces the underlying file with one built around it, so that backend middleware can intercept reads and writes
+	pub fn wrap<F: PositionalFile + 'static>(self, f: impl FnOnce(Box<dyn PositionalFile>) -> F) -> Self {
+		Self{
+			metadata: self.metadata,
+			fd: Box::new(f(self.fd))
+		}
 	}
-}
 
-#[derive(thiserror::Error, Debug)]
-pub enum IOError {
-	#[error("not open for reading")]
-	NoRead,
-	#[error("not open for writing")]
-	NoWrite
-}
+	pub async fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>, ProtocolError> {
+		self.fd.read_at(offset, len).await
+	}
 
-impl AsyncRead for OpenFile {
-	fn poll_read(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
-		let result = self.fd.as_mut().poll_read(ctx, buf);
-		if let Ready(Ok(count)) = result {
-			self.pos += count as u64;
-		}
-		result
+	pub async fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), ProtocolError> {
+		self.fd.write_at(offset, data).await
 	}
-}
 
-impl AsyncWrite for OpenFile {
-	fn poll_write(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
-		let result = self.fd.as_mut().poll_write(ctx, buf);
-		if let Ready(Ok(count)) = result {
-			self.pos += count as u64;
-		}
-		result
+	pub async fn len(&self) -> Result<u64, ProtocolError> {
+		self.fd.len().await
 	}
 
-	fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-		self.fd.as_mut().poll_flush(ctx)
+	pub async fn flush(&self) -> Result<(), ProtocolError> {
+		self.fd.flush().await
 	}
 
-	fn poll_shutdown(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-		self.fd.as_mut().poll_shutdown(ctx)
+	pub async fn sync(&self) -> Result<(), ProtocolError> {
+		self.fd.sync().await
 	}
-}
 
-impl AsyncSeek for OpenFile {
-	fn start_seek(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, position: SeekFrom) -> Poll<Result<(), Error>> {
-		// TODO:  Short circuit if position is SeekFrom::Start(pos) where pos == self.pos
-		self.fd.as_mut().start_seek(ctx, position)
+	pub async fn close(&self) -> Result<(), ProtocolError> {
+		self.fd.close().await
 	}
 
-	fn poll_complete(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Result<u64, Error>> {
-		let result = self.fd.as_mut().poll_complete(ctx);
-		if let Ready(Ok(pos)) = result {
-			self.pos = pos;
+	pub async fn abort(&self) -> Result<(), ProtocolError> {
+		self.fd.abort().await
+	}
+
+	// Falls back to what the backend reported at open() for files that can't fstat() themselves
+	pub async fn metadata(&self) -> Result<Metadata, ProtocolError> {
+		match self.fd.metadata().await {
+			Err(ProtocolError::Unsupported) => Ok(self.metadata.clone()),
+			result => result
 		}
-		result
+	}
+
+	pub async fn set_metadata(&self, uid_and_gid: Option<(u32, u32)>, permissions: Option<u32>, atime_and_mtime: Option<(u32, u32)>) -> Result<(), ProtocolError> {
+		self.fd.set_metadata(uid_and_gid, permissions, atime_and_mtime).await
+	}
+
+	pub async fn statvfs(&self) -> Result<FsStats, ProtocolError> {
+		self.fd.statvfs().await
 	}
 }
 
+impl fmt::Debug for OpenFile {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("OpenFile")
+			.field("fd", &self.fd)
+			.finish()
+	}
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum IOError {
+	#[error("not open for reading")]
+	NoRead,
+	#[error("not open for writing")]
+	NoWrite
+}
@@ -2,24 +2,28 @@
 #![allow(non_upper_case_globals)]
 #[macro_use] extern crate async_trait;
 #[macro_use] extern crate lazy_static;
+#[macro_use] extern crate log;
 
 use std::collections::HashMap;
 use std::collections::VecDeque;
+#[cfg(feature = "standalone")]
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
+#[cfg(feature = "standalone")]
+use std::sync::atomic::AtomicUsize;
+#[cfg(feature = "standalone")]
+use std::sync::atomic::Ordering;
 
-use futures::executor::block_on;
+#[cfg(feature = "standalone")]
+use futures::future::BoxFuture;
 use futures::future::Ready;
 use futures::future::ready;
 
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncSeekExt;
-use tokio::io::AsyncWriteExt;
-use tokio::io::SeekFrom;
-use tokio::time::delay_for;
-use tokio::time::Duration;
-
 use anyhow::Error;
 
 use bincode::Options;
@@ -28,132 +32,474 @@ use bincode::Options;
 use thrussh::{
 	ChannelId,
 	CryptoVec,
+	Disconnect,
+	Pty,
 	server::{
 		Auth,
 		Handle,
 		Handler,
-		Response,
 		Session
 	}
 };
 
+use lexiclean::Lexiclean;
+
 use uuid::Uuid;
 
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::FsStats;
 use sftp_protocol::stream::packet;
+use sftp_protocol::stream::packet::extended::Request as ExtendedRequest;
+use sftp_protocol::stream::packet::extended::Response as ExtendedResponse;
 use sftp_protocol::stream::packet::name::File;
+use sftp_protocol::stream::packet::kind::PacketType;
 use sftp_protocol::stream::packet::open::OpenFlags;
 use sftp_protocol::stream::packet::PayloadTrait;
 use sftp_protocol::stream::packet::status::StatusType;
+use sftp_protocol::stream::packet::version::Version;
+use sftp_protocol::Error as ProtocolError;
 use sftp_protocol::Packet;
 use sftp_protocol::Payload;
 
+#[cfg(feature = "standalone")]
+pub mod auth;
+#[cfg(feature = "standalone")]
+use auth::Authenticator;
 pub mod backend;
-use backend::Backend;
+#[cfg(feature = "standalone")]
+pub mod builder;
+#[cfg(feature = "standalone")]
+pub use builder::ServerBuilder;
+mod buffer;
+use buffer::BufferedFile;
+use backend::BackendFactory;
+use backend::DynBackend;
+use backend::OpenOptions;
+pub mod config;
+use config::Config;
+use config::expand_start_dir;
 pub mod file;
 use file::OpenFile;
+pub mod lock;
+use lock::LockGuard;
+use lock::LockKey;
+use lock::LockManager;
+use lock::LockMode;
+pub mod record;
+use record::Recorder;
+pub mod scp;
+pub mod session;
+pub use session::Hooks;
+pub use session::SessionContext;
+pub use session::SessionInfo;
+pub use session::SessionLimit;
+use session::SessionRegistry;
+mod shutdown;
+pub use shutdown::ShutdownSummary;
+use shutdown::Signal;
+#[cfg(feature = "test-support")]
+pub mod testing;
+pub mod transport;
+
+// Extensions advertised in VERSION, as (name, version) pairs
+const EXTENSIONS: &[(&str, &str)] = &[
+	("fsync@openssh.com", "1"),
+	("posix-rename@openssh.com", "1"),
+	("hardlink@openssh.com", "1"),
+	("statvfs@openssh.com", "2"),
+	("fstatvfs@openssh.com", "2"),
+	("space-available", "1")
+];
+
+// Requests (as config::request_name() names them) that aren't extensions and are actually served
+const REQUESTS: &[&str] = &["open", "close", "read", "write", "lstat", "fstat", "setstat", "fsetstat", "opendir", "readdir", "remove", "mkdir", "rmdir", "realpath", "stat", "rename"];
+
+/// Names of every request the server serves, including extensions, as used by `RequestFilter`
+pub fn supported_requests() -> Vec<String> /* {{{ */ {
+	let extensions = EXTENSIONS.iter().map(|(name, _)| name.trim_end_matches("@openssh.com").to_string());
+	REQUESTS.iter().map(|s| s.to_string()).chain(extensions).collect()
+} // }}}
+
+// Length, type and request ID:  enough of a packet to answer it without parsing the rest
+const PACKET_HEADER_LEN: usize = 9;
 
+/// A packet that was taken off the transport but can't be served.  It's already been consumed, so the session can
+///    answer it with `status` and carry on; only a packet without a request ID to answer (i.e. a bad INIT) is fatal.
+#[derive(thiserror::Error, Debug)]
+#[error("{message}")]
+pub struct RejectedPacket {
+	pub id: Option<u32>,
+	pub status: StatusType,
+	pub message: String
+}
+
+// Accumulates bytes from the transport until one or more complete packets are available
 #[derive(Clone, Debug)]
 pub struct PartialPacket {
-	target_len: Option<u32>,
-	buffer: Vec<u8>
+	buffer: Vec<u8>,
+	// What's still to arrive of an oversized packet that's already been rejected, and is thrown away as it does
+	skip: usize
 }
 
 impl PartialPacket {
+	pub fn new() -> Self /* {{{ */ {
+		Self{
+			buffer: Vec::new(),
+			skip: 0
+		}
+	} // }}}
+
+	pub fn push(&mut self, data: &[u8]) /* {{{ */ {
+		let skipped = self.skip.min(data.len());
+		self.skip -= skipped;
+		self.buffer.extend_from_slice(&data[skipped..]);
+	} // }}}
+
+	/// Removes the next complete packet from the buffer, if there is one
+	pub fn next_packet(&mut self, max_len: u32) -> Result<Option<Packet>, RejectedPacket> /* {{{ */ {
+		if(self.buffer.len() < 4) {
+			return Ok(None);
+		}
+		let len = u32::from_be_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]]);
+		let total = 4 + len as usize;
+		if(len > max_len) {
+			// Wait for enough of it to know which request is being refused
+			if(self.buffer.len() < PACKET_HEADER_LEN) {
+				return Ok(None);
+			}
+			warn!("Refusing {}-byte packet; limit is {} bytes", len, max_len);
+			let id = raw_request_id(&self.buffer);
+			let buffered = total.min(self.buffer.len());
+			self.buffer.drain(..buffered);
+			self.skip = total - buffered;
+			return Err(RejectedPacket{
+				id: id,
+				status: StatusType::Failure,
+				message: format!("Packet length {} exceeds limit of {}", len, max_len)
+			});
+		}
+		if(self.buffer.len() < total) {
+			return Ok(None);
+		}
+		let result = match Packet::parse(&self.buffer[..total]) {
+			Ok((_, v)) => Ok(Some(v)),
+			Err(e) => Err(RejectedPacket{
+				id: raw_request_id(&self.buffer[..total]),
+				status: StatusType::BadMessage,
+				message: format!("Failed to parse packet:  {}", e)
+			})
+		};
+		self.buffer.drain(..total);
+		result
+	} // }}}
+}
+
+// Request ID of a packet that hasn't been (or can't be) parsed; INIT and VERSION don't have one
+fn raw_request_id(packet: &[u8]) -> Option<u32> /* {{{ */ {
+	if(packet.len() < PACKET_HEADER_LEN || packet[4] == PacketType::Init as u8 || packet[4] == PacketType::Version as u8) {
+		return None;
+	}
+	Some(u32::from_be_bytes([packet[5], packet[6], packet[7], packet[8]]))
+} // }}}
+
+#[cfg(feature = "standalone")]
+#[derive(Clone, Debug)]
+struct ChannelState {
+	// Set once the client has started an SFTP subsystem or an SCP transfer on this channel; no data is processed before then
+	input: Option<Arc<transport::PipeSender>>,
+	// What the session running on the channel has written that hasn't been passed on to the client yet
+	output: Option<transport::Backlog>
+}
+
+#[cfg(feature = "standalone")]
+impl ChannelState {
 	fn new() -> Self {
 		Self{
-			target_len: None,
-			buffer: Vec::new()
+			input: None,
+			output: None
 		}
 	}
 }
 
 #[derive(Clone)]
-pub struct Server<B: Backend + Send> {
-	backend: Arc<Mutex<B>>,
+pub struct Server {
+	// The backend for this session, once one has been chosen by the factory
+	backend: Arc<dyn DynBackend>,
+	backends: Arc<dyn BackendFactory>,
+	config: Arc<Config>,
+	// Shared by every session, so that locks taken by one are seen by the others
+	locks: Arc<LockManager>,
+	sessions: Arc<SessionRegistry>,
+	hooks: Arc<dyn Hooks>,
+	// Once triggered, sessions stop reading new requests and end once their outstanding ones are answered
+	draining: Signal,
+	// Once triggered, sessions abandon outstanding requests and end immediately
+	closing: Signal,
+	// Refuses every login until one is set
+	#[cfg(feature = "standalone")]
+	authenticator: Arc<dyn Authenticator>,
+	// Source of connection IDs, shared so that servers accepting on several listeners don't hand out duplicates
+	#[cfg(feature = "standalone")]
+	next_id: Arc<AtomicUsize>,
+	user: Option<String>,
+	peer: Option<SocketAddr>,
+	// Absolute path that "~"-prefixed paths are resolved against
+	home: PathBuf,
+	// Absolute path that relative paths are resolved against:  the start directory if there is one, or else the home
+	cwd: PathBuf,
 
 	#[cfg(feature = "standalone")]
 	pub clients: Arc<Mutex<HashMap<(usize, ChannelId), Handle>>>,
 	pub id: usize,
 
 	// In order to support large directories without blowing up, this may end up needing to hold a Stream<Item=File> instead of VecDeque<File>; for now this is fine.
-	open_dirs: Arc<Mutex<HashMap<Uuid, (VecDeque<File>, usize)>>>,
-	open_files: Arc<Mutex<HashMap<Uuid, OpenFile>>>,
+	open_dirs: Arc<Mutex<HashMap<Uuid, VecDeque<File>>>>,
+	open_files: Arc<Mutex<HashMap<Uuid, Arc<OpenFile>>>>,
+	file_locks: Arc<Mutex<HashMap<Uuid, LockGuard>>>,
+	recorder: Option<Arc<Recorder>>,
+	#[cfg(feature = "standalone")]
+	channels: HashMap<ChannelId, ChannelState>,
+	// Set when one of the connection's sessions hits a SessionPolicy limit, so that the client is disconnected with that reason
 	#[cfg(feature = "standalone")]
-	partial_packets: HashMap<ChannelId, PartialPacket>,
+	hangup: Arc<Mutex<Option<SessionLimit>>>,
 }
 
-fn parse_packet(data: &[u8], partial_packet: &mut PartialPacket) -> Result<Option<Packet>, Error> /* {{{ */ {
-	let packet;
-	if let Some(target_len) = partial_packet.target_len {
-		partial_packet.buffer.extend_from_slice(data);
-		if(partial_packet.buffer.len() < target_len as usize) {
-			return Ok(None);
-		}
-		packet = match Packet::parse(&partial_packet.buffer) {
-			Ok((_, v)) => v,
-			Err(e) => return Err(Error::msg(format!("Failed to parse packet:  {}", e)))
-		};
-	} else {
-		packet = match Packet::parse(&data) {
-			Ok((_, v)) => v,
-			Err(e) => return Err(Error::msg(format!("Failed to parse packet:  {}", e)))
-		};
-		if(data.len() < packet.header.length as usize) {
-			partial_packet.target_len = Some(packet.header.length);
-			partial_packet.buffer = Vec::from(data);
-			return Ok(None);
-		}
-	}
-	Ok(Some(packet))
-} // }}}
+impl Server {
+	pub fn new(backend: impl DynBackend + 'static, id: usize) -> Self /* {{{ */ {
+		Self::from_arc(Arc::new(backend), id)
+	} // }}}
+
+	// For backends that have already been shared, such as the result of `BackendBuilder::build()`
+	pub fn from_arc(backend: Arc<dyn DynBackend>, id: usize) -> Self /* {{{ */ {
+		let shared = backend.clone();
+		Self::from_parts(backend, Arc::new(move |_: &SessionContext| -> Result<Arc<dyn DynBackend>, Error> { Ok(shared.clone()) }), id)
+	} // }}}
 
-impl<B: Backend + Send> Server<B> {
-	pub fn new(backend: B, id: usize) -> Self /* {{{ */ {
+	// For servers that only ever use a per-session backend from the factory
+	pub fn from_factory(factory: Arc<dyn BackendFactory>, id: usize) -> Self /* {{{ */ {
+		Self::from_parts(Arc::new(backend::Unassigned), factory, id)
+	} // }}}
+
+	fn from_parts(backend: Arc<dyn DynBackend>, backends: Arc<dyn BackendFactory>, id: usize) -> Self /* {{{ */ {
 		Self{
-			backend: Arc::new(Mutex::new(backend)),
+			backend: backend,
+			backends: backends,
+			config: Arc::new(Config::default()),
+			locks: Arc::new(LockManager::new(Default::default())),
+			sessions: Arc::new(SessionRegistry::default()),
+			hooks: Arc::new(()),
+			draining: Signal::new(),
+			closing: Signal::new(),
+			#[cfg(feature = "standalone")]
+			authenticator: Arc::new(()),
+			#[cfg(feature = "standalone")]
+			next_id: Arc::new(AtomicUsize::new(id)),
+			user: None,
+			peer: None,
+			home: PathBuf::from("/"),
+			cwd: PathBuf::from("/"),
 			#[cfg(feature = "standalone")]
 			clients: Arc::new(Mutex::new(HashMap::new())),
 			id: id,
 			open_dirs: Arc::new(Mutex::new(HashMap::new())),
 			open_files: Arc::new(Mutex::new(HashMap::new())),
+			file_locks: Arc::new(Mutex::new(HashMap::new())),
+			recorder: None,
 			#[cfg(feature = "standalone")]
-			partial_packets: HashMap::new(),
+			channels: HashMap::new(),
+			#[cfg(feature = "standalone")]
+			hangup: Arc::new(Mutex::new(None)),
+		}
+	} // }}}
+
+	// Clone of this server sharing its backend and configuration, but with none of its per-session state
+	fn new_session(&self, ctx: SessionContext) -> Self /* {{{ */ {
+		let mut session = self.clone();
+		let user = ctx.user.as_deref();
+		let home = ctx.home.clone()
+			.or_else(|| user.and_then(|u| self.config.home_dirs.get(u).cloned()))
+			.unwrap_or_else(|| "/".to_string());
+		let home = PathBuf::from("/").join(home).lexiclean();
+		// As with sftp-server's -d, a relative start directory is relative to the home directory
+		let start = self.config.start_dir.as_ref().and_then(|template| match expand_start_dir(template, user.unwrap_or(""), &home.to_string_lossy()) {
+			Ok(v) => Some(home.join(v).lexiclean()),
+			Err(e) => {
+				warn!("Session {}:  ignoring start directory:  {}", ctx.id, e);
+				None
+			}
+		});
+		session.cwd = start.unwrap_or_else(|| home.clone());
+		session.home = home;
+		session.id = ctx.id;
+		session.user = ctx.user;
+		session.peer = ctx.peer;
+		session.open_dirs = Arc::new(Mutex::new(HashMap::new()));
+		session.open_files = Arc::new(Mutex::new(HashMap::new()));
+		session.file_locks = Arc::new(Mutex::new(HashMap::new()));
+		session.recorder = None;
+		#[cfg(feature = "standalone")]
+		{
+			session.channels = HashMap::new();
+			session.hangup = Arc::new(Mutex::new(None));
+		}
+		session
+	} // }}}
+
+	// Turns a client-supplied path into an absolute one, relative to the session's start directory
+	fn resolve_path(&self, path: &str) -> PathBuf /* {{{ */ {
+		let path = match path {
+			"~" => self.home.clone(),
+			_ => match path.strip_prefix("~/") {
+				Some(rest) => self.home.join(rest),
+				// join() replaces the start directory entirely if the path is already absolute
+				None => self.cwd.join(path)
+			}
+		};
+		PathBuf::from("/").join(path).lexiclean()
+	} // }}}
+
+	// What the backend identifies the file at `path` by, so that every name for it and every session's view of it
+	//    locks the same thing.  Paths the backend can't make sense of are locked as they are; using them will fail anyway.
+	pub(crate) async fn lock_key(&self, path: &Path) -> LockKey /* {{{ */ {
+		match self.backend.lock_key(path).await {
+			Ok(v) => v,
+			Err(e) => {
+				debug!("Session {}:  no lock key for {:?}:  {}", self.id, path, e);
+				LockKey::Path(path.to_path_buf())
+			}
+		}
+	} // }}}
+
+	fn lock(&self, id: u32, key: LockKey, mode: LockMode) -> Result<LockGuard, Packet> /* {{{ */ {
+		match self.locks.try_lock(key.clone(), mode) {
+			Some(v) => Ok(v),
+			None => {
+				info!("Session {} (user {:?}):  {:?} is locked by another client", self.id, self.user, key);
+				Err(Payload::status(id, StatusType::PermissionDenied, "File is locked by another client").into_packet())
+			}
+		}
+	} // }}}
+
+	// A file that didn't exist when it was locked was locked by path; once it's been created, moves the lock onto
+	//    whatever the backend identifies it by now, so that names linked to it later still conflict.  Returns false if
+	//    another client got there first through one of those names.
+	pub(crate) async fn relock(&self, lock: &mut LockGuard, path: &Path) -> bool /* {{{ */ {
+		if let LockKey::File{..} = lock.key() {
+			return true;
+		}
+		let key = self.lock_key(path).await;
+		if(&key == lock.key()) {
+			return true;
+		}
+		match self.locks.try_lock(key, lock.mode()) {
+			Some(v) => {
+				*lock = v;
+				true
+			},
+			None => false
+		}
+	} // }}}
+
+	fn limit_exceeded(&self, id: u32, message: &str) -> Packet /* {{{ */ {
+		warn!("Session {} (user {:?}) hit a resource limit:  {}", self.id, self.user, message);
+		Payload::status(id, StatusType::Failure, message).into_packet()
+	} // }}}
+
+	pub fn with_config(mut self, config: Config) -> Self /* {{{ */ {
+		self.locks = Arc::new(LockManager::new(config.locking));
+		self.config = Arc::new(config);
+		self
+	} // }}}
+
+	// Chooses each session's backend when the session starts, instead of sharing the one passed to new()
+	pub fn with_backend_factory(mut self, factory: impl BackendFactory + 'static) -> Self /* {{{ */ {
+		self.backends = Arc::new(factory);
+		self
+	} // }}}
+
+	pub fn with_hooks(mut self, hooks: impl Hooks + 'static) -> Self /* {{{ */ {
+		self.hooks = Arc::new(hooks);
+		self
+	} // }}}
+
+	#[cfg(feature = "standalone")]
+	pub fn with_authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self /* {{{ */ {
+		self.authenticator = Arc::new(authenticator);
+		self
+	} // }}}
+
+	// Returns the refusal to send if the request isn't permitted by the server's read-only flag or request filter
+	fn check_policy(&self, payload: &Payload) -> Option<Packet> /* {{{ */ {
+		let id = payload.request_id()?;
+		if(self.config.read_only && config::is_write_request(payload)) {
+			info!("Session {}:  refusing write request {} on read-only server", self.id, id);
+			return Some(Payload::status(id, StatusType::PermissionDenied, "Server is read-only").into_packet());
+		}
+		if let Some(name) = config::request_name(payload) {
+			if(!self.config.request_filter.permits(&name)) {
+				info!("Session {}:  refusing {} request {}", self.id, name, id);
+				return Some(Payload::status(id, StatusType::PermissionDenied, format!("{} requests are not permitted", name)).into_packet());
+			}
 		}
+		None
 	} // }}}
 
 	async fn process_request(&self, input: Packet) -> Result<Packet, Error> /* {{{ */ {
+		if let Some(refusal) = self.check_policy(&input.payload) {
+			return Ok(refusal);
+		}
 		let output = match input.payload {
-			Payload::Init(_) => Payload::version(3, vec![]).into_packet(),
-			Payload::Version(_) => unreachable!(),
+			Payload::Init(_) => Payload::Version(Version::with_extensions(3, EXTENSIONS)).into_packet(),
 			Payload::Open(r) => /* {{{ */ {
-				let path = PathBuf::from(r.path);
-				let result = self.backend.lock().unwrap().open(
-					&path,
-					r.pflags.contains(OpenFlags::Read),
-					r.pflags.contains(OpenFlags::Write),
-					r.pflags.contains(OpenFlags::Append),
-					r.pflags.contains(OpenFlags::Create),
-					r.pflags.contains(OpenFlags::Truncate),
-					r.pflags.contains(OpenFlags::Exclude)
-				).await;
+				if(self.open_files.lock().unwrap().len() >= self.config.limits.max_open_files) {
+					return Ok(self.limit_exceeded(r.id, "Too many open files"));
+				}
+				let path = self.resolve_path(&r.path);
+				let mode = match r.pflags.intersects(OpenFlags::Write | OpenFlags::Append | OpenFlags::Create | OpenFlags::Truncate) {
+					true => LockMode::Write,
+					false => LockMode::Read
+				};
+				// Locked before opening, so that a second writer's O_TRUNC can't clobber the first one's upload
+				let mut lock = match self.lock(r.id, self.lock_key(&path).await, mode) {
+					Ok(v) => v,
+					Err(refusal) => return Ok(refusal)
+				};
+				let attrs = self.config.mode_policy(self.user.as_deref()).apply(&r.attrs, false);
+				let result = self.backend.open(&path, OpenOptions::from_flags(r.pflags, attrs)).await;
 				let response = match result {
 					Ok(v) => {
+						if(!self.relock(&mut lock, &path).await) {
+							if let Err(e) = v.abort().await {
+								debug!("Session {}:  failed to close {:?} after losing its lock:  {}", self.id, path, e);
+							}
+							return Ok(Payload::status(r.id, StatusType::PermissionDenied, "File is locked by another client").into_packet());
+						}
+						let v = match self.config.buffering.is_enabled() {
+							true => v.wrap(|fd| BufferedFile::new(fd, &self.config.buffering)),
+							false => v
+						};
 						let response = Payload::handle(r.id);
 						let mut state = self.open_files.lock().unwrap();
-						state.insert(response.handle.clone(), v);
+						state.insert(response.handle.clone(), Arc::new(v));
+						self.file_locks.lock().unwrap().insert(response.handle.clone(), lock);
 						Payload::Handle(response)
 					},
 					Err(e) => {
-						eprintln!("!!! Failed to open file: {:?}", e);
-						Payload::status(r.id, StatusType::Failure, format!("Failed to open file: {}", e))
+						warn!("Session {}:  failed to open {:?}:  {}", self.id, path, e);
+						Payload::status(r.id, e.status_type(), format!("Failed to open file: {}", e))
 					}
 				};
 				response.into_packet()
 			}, // }}}
 			Payload::Close(r) => /* {{{ */ {
-				let mut files = self.open_files.lock().unwrap();
-				let response = match files.remove(&r.handle) {
-					Some(_) => Payload::status(r.id, StatusType::OK, "OK"),
+				let file = self.open_files.lock().unwrap().remove(&r.handle);
+				// Held until the flush below is done, so another writer can't open the file before our data lands
+				let _lock = self.file_locks.lock().unwrap().remove(&r.handle);
+				let response = match file {
+					Some(file) => match file.close().await {
+						Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
+						Err(e) => Payload::status(r.id, e.status_type(), format!("Failed to close file: {}", e))
+					},
 					None => {
 						let mut dirs = self.open_dirs.lock().unwrap();
 						match dirs.remove(&r.handle) {
@@ -165,39 +511,26 @@ impl<B: Backend + Send> Server<B> {
 				response.into_packet()
 			}, // }}}
 			Payload::Read(r) => /* {{{ */ {
-				let mut state = self.open_files.lock().unwrap();
-				let response = match state.get_mut(&r.handle) {
-					Some(ref mut file) => {
-						let mut packet = Payload::data_with_size(r.id, r.len);
-						file.seek(SeekFrom::Start(r.offset)).await?;
-						let count = file.read(&mut packet.data).await?;
-						if(count == 0) {
-							Payload::status(r.id, StatusType::EOF, "EOF")
-						} else {
-							packet.data.truncate(count);
-							Payload::Data(packet)
-						}
+				let file = self.open_files.lock().unwrap().get(&r.handle).cloned();
+				let response = match file {
+					Some(file) => match file.read_at(r.offset, r.len.min(self.config.limits.max_read_len)).await {
+						Ok(data) if data.is_empty() => Payload::status(r.id, StatusType::EOF, "EOF"),
+						Ok(data) => Payload::Data(Payload::data(r.id, data)),
+						Err(e) => Payload::status(r.id, e.status_type(), format!("Failed to read: {}", e))
 					},
 					None => Payload::status(r.id, StatusType::NoSuchFile, "No such file")
 				};
 				response.into_packet()
 			}, // }}}
 			Payload::Write(r) => /* {{{ */ {
-				let mut state = self.open_files.lock().unwrap();
-				let response = match state.get_mut(&r.handle) {
-					Some(ref mut file) => {
-						// TODO:  When attempting to seek past the end of the (existing), zerofill the gap
-						file.seek(SeekFrom::Start(r.offset)).await?;
-						let mut written = 0;
-						while(written < r.data.len()) {
-							let count = file.write(&r.data[written..]).await?;
-							written += count;
-						}
-						if(written < r.data.len()) {
-							Payload::status(r.id, StatusType::EOF, "EOF")
-						} else {
-							Payload::status(r.id, StatusType::OK, "OK")
-						}
+				if(r.data.len() > self.config.limits.max_write_len as usize) {
+					return Ok(self.limit_exceeded(r.id, "Write payload too large"));
+				}
+				let file = self.open_files.lock().unwrap().get(&r.handle).cloned();
+				let response = match file {
+					Some(file) => match file.write_at(r.offset, &r.data).await {
+						Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
+						Err(e) => Payload::status(r.id, e.status_type(), format!("Failed to write: {}", e))
 					},
 					None => Payload::status(r.id, StatusType::NoSuchFile, "No such file")
 				};
@@ -205,40 +538,44 @@ impl<B: Backend + Send> Server<B> {
 			}, // }}}
 			Payload::Lstat(r) => /* {{{ */ {
 				// TODO:  Don't follow symlinks
-				let mut attrs = Payload::attrs(r.id);
-				attrs.attrs = self.backend.lock().unwrap().metadata(&r.path).await?.into();
-				attrs.into_packet()
+				self.stat(r.id, &r.path).await
 			}, // }}}
 			Payload::Fstat(r) => /* {{{ */ {
-				let open_files = self.open_files.lock().unwrap();
-				let response = match open_files.get(&r.handle) {
-					Some(v) => {
-						let mut attrs = Payload::attrs(r.id);
-						attrs.attrs = v.metadata.clone().into();
-						Payload::Attrs(attrs)
+				let file = self.open_files.lock().unwrap().get(&r.handle).cloned();
+				let response = match file {
+					Some(v) => match v.metadata().await {
+						Ok(metadata) => {
+							let mut attrs = Payload::attrs(r.id);
+							attrs.attrs = metadata.into();
+							Payload::Attrs(attrs)
+						},
+						Err(e) => {
+							warn!("Session {}:  failed to get metadata on handle {}:  {}", self.id, &r.handle, e);
+							Payload::status(r.id, e.status_type(), format!("Failed to get metadata: {}", e))
+						}
 					},
 					None => Payload::status(r.id, StatusType::NoSuchFile, "Handle not found")
 				};
 				response.into_packet()
 			}, // }}}
 			Payload::SetStat(r) => /* {{{ */ {
-				let response = match self.backend.lock().unwrap().set_metadata(&r.path, r.attrs.get_uid_gid(), r.attrs.get_permissions(), r.attrs.get_atime_mtime()).await {
+				let response = match self.backend.set_metadata(&self.resolve_path(&r.path), r.attrs.get_uid_gid(), r.attrs.get_permissions(), r.attrs.get_atime_mtime()).await {
 					Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
 					Err(e) => {
-						eprintln!("!!! Failed to set metadata on {}:  {:?}", &r.path, e);
-						Payload::status(r.id, StatusType::Failure, format!("Failed to set metadata: {}", e))
+						warn!("Session {}:  failed to set metadata on {}:  {}", self.id, &r.path, e);
+						Payload::status(r.id, e.status_type(), format!("Failed to set metadata: {}", e))
 					}
 				};
 				response.into_packet()
 			}, // }}}
 			Payload::FSetStat(r) => /* {{{ */ {
-				let open_files = self.open_files.lock().unwrap();
-				let response = match open_files.get(&r.handle) {
-					Some(v) => match self.backend.lock().unwrap().set_metadata(&v.metadata.path, r.attrs.get_uid_gid(), r.attrs.get_permissions(), r.attrs.get_atime_mtime()).await {
+				let file = self.open_files.lock().unwrap().get(&r.handle).cloned();
+				let response = match file {
+					Some(v) => match v.set_metadata(r.attrs.get_uid_gid(), r.attrs.get_permissions(), r.attrs.get_atime_mtime()).await {
 						Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
 						Err(e) => {
-							eprintln!("!!! Failed to set metadata on {}:  {}", &v.metadata.path, e);
-							Payload::status(r.id, StatusType::Failure, format!("Failed to set metadata: {}", e))
+							warn!("Session {}:  failed to set metadata on handle {}:  {}", self.id, &r.handle, e);
+							Payload::status(r.id, e.status_type(), format!("Failed to set metadata: {}", e))
 						}
 					},
 					None => Payload::status(r.id, StatusType::NoSuchFile, "Handle not found")
@@ -246,33 +583,35 @@ impl<B: Backend + Send> Server<B> {
 				response.into_packet()
 			}, // }}}
 			Payload::OpenDir(r) => /* {{{ */ {
+				if(self.open_dirs.lock().unwrap().len() >= self.config.limits.max_open_dirs) {
+					return Ok(self.limit_exceeded(r.id, "Too many open directories"));
+				}
 				let response = Payload::handle(r.id);
-				let contents = self.backend.lock().unwrap().list(&r.path).await?;
+				let path = self.resolve_path(&r.path);
+				let contents = match self.backend.list(&path).await {
+					Ok(v) => v,
+					Err(e) => return Ok(Payload::status(r.id, e.status_type(), format!("Failed to open directory: {}", e)).into_packet())
+				};
 				self.open_dirs.lock().unwrap().insert(
-					response.handle.clone(), (
-						contents.into_iter().map(|f| File{
-							longname: PathBuf::from(&r.path).join(&f.path).to_string_lossy().to_string(),
-							filename: f.path.clone(),
-							attrs: f.into()
-						}).collect(),
-						0
-					)
+					response.handle.clone(),
+					contents.into_iter().map(|f| File{
+						longname: path.join(&f.path).to_string_lossy().to_string(),
+						filename: f.path.clone(),
+						attrs: f.into()
+					}).collect()
 				);
 				response.into_packet()
 			}, // }}}
 			Payload::ReadDir(r) => /* {{{ */ {
 				let mut state = self.open_dirs.lock().unwrap();
 				match state.get_mut(&r.handle) {
-					Some((ref mut files, ref mut index)) => {
-						// TODO:  I would imagine that there's a limit on the response size.  We'll end up needing to chunk.
-						if(*index >= files.len()) {
+					Some(ref mut files) => {
+						if(files.is_empty()) {
 							Payload::status(r.id, StatusType::EOF, "EOF").into_packet()
 						} else {
 							let mut payload = Payload::name(r.id);
-							if(files.len() > 0) {
-								payload.files = files.clone().into();
-							}
-							*index += payload.files.len();
+							let count = files.len().min(self.config.limits.max_dir_batch);
+							payload.files = files.drain(..count).collect();
 							payload.into_packet()
 						}
 					},
@@ -280,120 +619,278 @@ impl<B: Backend + Send> Server<B> {
 				}
 			}, // }}}
 			Payload::Remove(r) => /* {{{ */ {
-				let response = match self.backend.lock().unwrap().delete_file(&r.path).await {
+				let path = self.resolve_path(&r.path);
+				let _lock = match self.lock(r.id, self.lock_key(&path).await, LockMode::Write) {
+					Ok(v) => v,
+					Err(refusal) => return Ok(refusal)
+				};
+				let response = match self.backend.delete_file(&path).await {
 					Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
-					Err(e) => Payload::status(r.id, StatusType::Failure, format!("Failed to delete file: {}", e))
+					Err(e) => Payload::status(r.id, e.status_type(), format!("Failed to delete file: {}", e))
 				};
 				response.into_packet()
 			}, // }}}
 			Payload::MkDir(r) => /* {{{ */ {
-				let response = match self.backend.lock().unwrap().mkdir(&r.path).await {
+				let attrs = self.config.mode_policy(self.user.as_deref()).apply(&r.attrs, true);
+				let response = match self.backend.mkdir(&self.resolve_path(&r.path), attrs).await {
 					Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
-					Err(e) => Payload::status(r.id, StatusType::Failure, format!("Failed to create directory: {}", e))
+					Err(e) => Payload::status(r.id, e.status_type(), format!("Failed to create directory: {}", e))
 				};
 				response.into_packet()
 			}, // }}}
 			Payload::RmDir(r) => /* {{{ */ {
-				let response = match self.backend.lock().unwrap().rmdir(&r.path).await {
+				let response = match self.backend.rmdir(&self.resolve_path(&r.path)).await {
 					Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
-					Err(e) => Payload::status(r.id, StatusType::Failure, format!("Failed to delete directory: {}", e))
+					Err(e) => Payload::status(r.id, e.status_type(), format!("Failed to delete directory: {}", e))
 				};
 				response.into_packet()
 			}, // }}}
 			Payload::RealPath(r) => /* {{{ */ {
+				let path = self.resolve_path(&r.path);
+				let resolved = match self.backend.realpath(&path).await {
+					Ok(v) => v,
+					// Clients canonicalize upload targets before creating them, so a missing target isn't an error
+					Err(ProtocolError::IO(ref e)) if e.kind() == io::ErrorKind::NotFound => path,
+					Err(e) => return Ok(Payload::status(r.id, e.status_type(), format!("Failed to resolve path: {}", e)).into_packet())
+				};
+				let attrs = match self.backend.metadata(&resolved).await {
+					Ok(v) => v.into(),
+					Err(_) => FileAttributes::new()
+				};
+				let resolved = resolved.to_string_lossy().to_string();
 				let mut name = packet::name::Name::new(r.id);
-				let backend = self.backend.lock().unwrap();
-				let normalized = backend.normalize_path(&r.path)?.to_string_lossy().to_string();
-				name.append_file(
-					&normalized,
-					&normalized,
-					backend.metadata(&r.path).await?.into()
-				);
+				name.append_file(&resolved, &resolved, attrs);
 				name.into_packet()
 			}, // }}}
 			Payload::Stat(r) => /* {{{ */ {
 				// TODO:  Follow symlinks
-				let mut attrs = Payload::attrs(r.id);
-				attrs.attrs = self.backend.lock().unwrap().metadata(&r.path).await?.into();
-				attrs.into_packet()
+				self.stat(r.id, &r.path).await
 			}, // }}}
-			Payload::Rename(r) => /* {{{ */ {
-				let response = match self.backend.lock().unwrap().rename(&r.oldpath, &r.newpath).await {
-					Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
-					Err(e) => Payload::status(r.id, StatusType::Failure, format!("Failed to rename: {}", e))
-				};
-				response.into_packet()
-			}, // }}}
-			Payload::ReadLink(_) => unimplemented!(),
-			Payload::Symlink(_) => unimplemented!(),
-			Payload::Status(_) => unreachable!(),
-			Payload::Handle(_) => unreachable!(),
-			Payload::Data(_) => unreachable!(),
-			Payload::Name(_) => unreachable!(),
-			Payload::Attrs(_) => unreachable!(),
-			Payload::Extended(_) => unimplemented!(),
-			Payload::ExtendedReply(_) => unreachable!()
+			Payload::Rename(r) => self.rename(r.id, &r.oldpath, &r.newpath, false).await,
+			Payload::ReadLink(r) => Payload::status(r.id, StatusType::OpUnsupported, "READLINK is not supported").into_packet(),
+			Payload::Symlink(r) => Payload::status(r.id, StatusType::OpUnsupported, "SYMLINK is not supported").into_packet(),
+			Payload::Extended(r) => self.process_extended(r).await?,
+			// Responses, which only a confused client would send
+			payload @ Payload::Version(_) |
+			payload @ Payload::Status(_) |
+			payload @ Payload::Handle(_) |
+			payload @ Payload::Data(_) |
+			payload @ Payload::Name(_) |
+			payload @ Payload::Attrs(_) |
+			payload @ Payload::ExtendedReply(_) => {
+				let id = payload.request_id().unwrap_or(0);
+				warn!("Session {}:  client sent a response packet as request {}", self.id, id);
+				Payload::status(id, StatusType::BadMessage, "Unexpected response packet").into_packet()
+			}
 		};
 		Ok(output)
 	} // }}}
 
-	async fn process_packet(&mut self, packet: Packet) -> Result<Option<Vec<u8>>, Error> /* {{{ */ {
-		let se = bincode::DefaultOptions::new().with_big_endian().with_fixint_encoding();
-		let response = self.process_request(packet).await?;
-		let response_bytes = se.serialize(&response)?;
-		Ok(Some(response_bytes))
+	async fn stat(&self, id: u32, path: &str) -> Packet /* {{{ */ {
+		match self.backend.metadata(&self.resolve_path(path)).await {
+			Ok(metadata) => {
+				let mut attrs = Payload::attrs(id);
+				attrs.attrs = metadata.into();
+				attrs.into_packet()
+			},
+			Err(e) => Payload::status(id, e.status_type(), format!("Failed to stat: {}", e)).into_packet()
+		}
 	} // }}}
 
-	#[cfg(feature = "legacy")]
-	pub async fn run(&mut self) -> Result<(), Error> /* {{{ */ {
-		let mut buf = [0u8; 8192];
-		let mut partial_packet = PartialPacket::new();
-		let mut stdin = tokio::io::stdin();
-		let mut stdout = tokio::io::stdout();
-		// TODO:  This needs a way to be terminated
-		loop {
-			let count = stdin.read(&mut buf).await?;
-			if(count == 0) {
-				delay_for(Duration::from_millis(10)).await;
-				continue;
+	// Serves both SSH_FXP_RENAME, which must not replace an existing target, and posix-rename@openssh.com, which does
+	async fn rename(&self, id: u32, oldpath: &str, newpath: &str, posix: bool) -> Packet /* {{{ */ {
+		let from = self.resolve_path(oldpath);
+		let to = self.resolve_path(newpath);
+		let from_key = self.lock_key(&from).await;
+		let to_key = self.lock_key(&to).await;
+		let _from_lock = match self.lock(id, from_key.clone(), LockMode::Write) {
+			Ok(v) => v,
+			Err(refusal) => return refusal
+		};
+		// Renaming one name for a file onto another is the same file on both sides
+		let _to_lock = match from_key == to_key {
+			true => None,
+			false => match self.lock(id, to_key, LockMode::Write) {
+				Ok(v) => Some(v),
+				Err(refusal) => return refusal
 			}
-			let packet = match parse_packet(&buf[0..count], &mut partial_packet) {
-				Ok(Some(v)) => v,
-				Ok(None) => continue,
-				Err(e) => {
-					eprintln!("!!! run():  Failed to parse packet:  {:?}", e);
-					continue;
+		};
+		let result = match posix {
+			true => self.backend.posix_rename(&from, &to).await,
+			false => self.backend.rename(&from, &to).await
+		};
+		let response = match result {
+			Ok(_) => Payload::status(id, StatusType::OK, "OK"),
+			Err(e) => Payload::status(id, e.status_type(), format!("Failed to rename: {}", e))
+		};
+		response.into_packet()
+	} // }}}
+
+	// Answers statvfs@openssh.com, fstatvfs@openssh.com and space-available, which differ only in how the reply is encoded
+	fn statvfs_reply(&self, id: u32, stats: Result<FsStats, ProtocolError>, space_available: bool) -> Result<Packet, Error> /* {{{ */ {
+		let stats = match stats {
+			Ok(v) => v,
+			Err(e) => return Ok(Payload::status(id, e.status_type(), format!("Failed to get filesystem statistics: {}", e)).into_packet())
+		};
+		let se = bincode::DefaultOptions::new().with_big_endian().with_fixint_encoding();
+		let data = match space_available {
+			true => se.serialize(&packet::extended::SpaceAvailableReply::from(&stats))?,
+			false => se.serialize(&packet::extended::StatvfsReply::from(&stats))?
+		};
+		Ok(Payload::ExtendedReply(ExtendedResponse{id: id, data: data}).into_packet())
+	} // }}}
+
+	async fn process_extended(&self, r: ExtendedRequest) -> Result<Packet, Error> /* {{{ */ {
+		let response = match r.request.as_str() {
+			"posix-rename@openssh.com" => {
+				let request = match packet::extended::PosixRename::parse(&r.data) {
+					Ok((_, v)) => v,
+					Err(_) => return Ok(Payload::status(r.id, StatusType::BadMessage, "Malformed posix-rename request").into_packet())
+				};
+				return Ok(self.rename(r.id, &request.oldpath, &request.newpath, true).await);
+			},
+			"hardlink@openssh.com" => {
+				let request = match packet::extended::Hardlink::parse(&r.data) {
+					Ok((_, v)) => v,
+					Err(_) => return Ok(Payload::status(r.id, StatusType::BadMessage, "Malformed hardlink request").into_packet())
+				};
+				let from = self.resolve_path(&request.oldpath);
+				let to = self.resolve_path(&request.newpath);
+				// Only the new name needs locking; linking doesn't touch the contents a writer of `from` is changing
+				let _to_lock = match self.lock(r.id, self.lock_key(&to).await, LockMode::Write) {
+					Ok(v) => v,
+					Err(refusal) => return Ok(refusal)
+				};
+				match self.backend.hardlink(&from, &to).await {
+					Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
+					Err(e) => Payload::status(r.id, e.status_type(), format!("Failed to create hard link: {}", e))
 				}
-			};
-			let response = match block_on(self.process_packet(packet)) {
-				Ok(Some(v)) => v,
-				Ok(None) => continue,
-				Err(e) => {
-					eprintln!("!!! run():  Failed to process packet:  {:?}", e);
-					continue;
+			},
+			"statvfs@openssh.com" => {
+				let request = match packet::extended::Statvfs::parse(&r.data) {
+					Ok((_, v)) => v,
+					Err(_) => return Ok(Payload::status(r.id, StatusType::BadMessage, "Malformed statvfs request").into_packet())
+				};
+				return self.statvfs_reply(r.id, self.backend.statvfs(&self.resolve_path(&request.path)).await, false);
+			},
+			"fstatvfs@openssh.com" => {
+				let request = match packet::extended::Fstatvfs::parse(&r.data) {
+					Ok((_, v)) => v,
+					Err(_) => return Ok(Payload::status(r.id, StatusType::BadMessage, "Malformed fstatvfs request").into_packet())
+				};
+				// Ask the open file rather than going by a path, which a RENAME could have pointed elsewhere since
+				let file = self.open_files.lock().unwrap().get(&request.handle).cloned();
+				match file {
+					Some(file) => return self.statvfs_reply(r.id, file.statvfs().await, false),
+					None => Payload::status(r.id, StatusType::NoSuchFile, "Handle not found")
+				}
+			},
+			"space-available" => {
+				let request = match packet::extended::SpaceAvailable::parse(&r.data) {
+					Ok((_, v)) => v,
+					Err(_) => return Ok(Payload::status(r.id, StatusType::BadMessage, "Malformed space-available request").into_packet())
+				};
+				return self.statvfs_reply(r.id, self.backend.statvfs(&self.resolve_path(&request.path)).await, true);
+			},
+			"fsync@openssh.com" => {
+				let request = match packet::extended::Fsync::parse(&r.data) {
+					Ok((_, v)) => v,
+					Err(_) => return Ok(Payload::status(r.id, StatusType::BadMessage, "Malformed fsync request").into_packet())
+				};
+				let file = self.open_files.lock().unwrap().get(&request.handle).cloned();
+				match file {
+					Some(file) => match file.sync().await {
+						Ok(_) => Payload::status(r.id, StatusType::OK, "OK"),
+						Err(e) => Payload::status(r.id, e.status_type(), format!("Failed to sync file: {}", e))
+					},
+					None => Payload::status(r.id, StatusType::NoSuchFile, "Handle not found")
 				}
+			},
+			_ => Payload::status(r.id, StatusType::OpUnsupported, format!("Unsupported extension {}", r.request))
+		};
+		Ok(response.into_packet())
+	} // }}}
+
+	// Aborts and drops every file the session still has open, for when the client goes away without closing them
+	pub(crate) async fn close_all(&self) /* {{{ */ {
+		let files: Vec<_> = self.open_files.lock().unwrap().drain().collect();
+		for (handle, file) in files {
+			if let Err(e) = file.abort().await {
+				warn!("Session {}:  failed to abort handle {} on teardown:  {:?}", self.id, handle, e);
+			}
+		}
+		self.file_locks.lock().unwrap().clear();
+		self.open_dirs.lock().unwrap().clear();
+	} // }}}
+
+	async fn process_packet(&self, packet: Packet) -> Result<Option<Vec<u8>>, Error> /* {{{ */ {
+		let id = packet.payload.request_id().unwrap_or(0);
+		if let Some(recorder) = &self.recorder {
+			recorder.request(&packet);
+		}
+		let result = self.process_request(packet).await;
+		// A request that fails is the client's problem, not the session's
+		let response = result.unwrap_or_else(|e| {
+			warn!("Session {}:  request {} failed:  {:?}", self.id, id, e);
+			let status = match e.downcast_ref::<ProtocolError>() {
+				Some(e) => e.status_type(),
+				None => StatusType::Failure
 			};
-			stdout.write(&response).await?;
-			stdout.flush().await?;
+			Payload::status(id, status, format!("Request failed: {}", e)).into_packet()
+		});
+		self.respond(&response).map(Some)
+	} // }}}
+
+	// Answers a packet that couldn't be parsed or was too large, if it can be answered at all
+	pub(crate) fn reject_packet(&self, rejected: RejectedPacket) -> Result<Vec<u8>, Error> /* {{{ */ {
+		let id = match rejected.id {
+			Some(v) => v,
+			None => return Err(rejected.into())
+		};
+		warn!("Session {}:  rejecting request {}:  {}", self.id, id, rejected.message);
+		if let Some(recorder) = &self.recorder {
+			recorder.rejected(id, &rejected.message);
 		}
-		Ok(())
+		self.respond(&Payload::status(id, rejected.status, rejected.message).into_packet())
+	} // }}}
+
+	fn respond(&self, response: &Packet) -> Result<Vec<u8>, Error> /* {{{ */ {
+		if let Some(recorder) = &self.recorder {
+			recorder.response(response);
+		}
+		let se = bincode::DefaultOptions::new().with_big_endian().with_fixint_encoding();
+		Ok(se.serialize(response)?)
+	} // }}}
+
+	#[cfg(feature = "legacy")]
+	pub async fn run(&self) -> Result<(), Error> /* {{{ */ {
+		let ctx = SessionContext{
+			id: self.id,
+			user: std::env::var("USER").ok(),
+			peer: None,
+			home: None
+		};
+		self.serve_session(transport::Joined::new(tokio::io::stdin(), tokio::io::stdout()), ctx).await
 	} // }}}
 }
 
 #[cfg(feature = "standalone")]
-impl<B: Backend + Send> thrussh::server::Server for Server<B> {
+impl thrussh::server::Server for Server {
 	type Handler = Self;
-	fn new(&mut self, _: Option<std::net::SocketAddr>) -> Self /* {{{ */ {
-		let s = self.clone();
-		self.id += 1;
-		s
+	fn new(&mut self, peer: Option<SocketAddr>) -> Self /* {{{ */ {
+		self.new_session(SessionContext{
+			id: self.next_id.fetch_add(1, Ordering::SeqCst),
+			user: None,
+			peer: peer,
+			home: None
+		})
 	} // }}}
 }
 
 #[cfg(feature = "standalone")]
-impl<B: Backend + Send> Handler for Server<B> {
+impl Handler for Server {
 	type FutureAuth = Ready<Result<(Self, Auth), Error>>;
-	type FutureUnit = Ready<Result<(Self, Session), Error>>;
+	type FutureUnit = BoxFuture<'static, Result<(Self, Session), Error>>;
 	type FutureBool = Ready<Result<(Self, Session, bool), Error>>;
 
 	fn finished_auth(self, auth: Auth) -> Self::FutureAuth /* {{{ */ {
@@ -405,56 +902,205 @@ impl<B: Backend + Send> Handler for Server<B> {
 	} // }}}
 
 	fn finished(self, session: Session) -> Self::FutureUnit /* {{{ */ {
-		ready(Ok((self, session)))
+		Box::pin(ready(Ok((self, session))))
 	} // }}}
 
-	fn channel_open_session(self, channel: ChannelId, session: Session) -> Self::FutureUnit /* {{{ */ {
+	fn channel_open_session(mut self, channel: ChannelId, mut session: Session) -> Self::FutureUnit /* {{{ */ {
+		if(self.draining.is_triggered()) {
+			info!("Session {}:  refusing channel {:?} while shutting down", self.id, channel);
+			session.disconnect(Disconnect::ByApplication, "Server is shutting down", "en");
+			return self.finished(session);
+		}
 		{
 			let mut clients = self.clients.lock().unwrap();
 			clients.insert((self.id, channel), session.handle());
 		}
+		self.channels.insert(channel, ChannelState::new());
 		self.finished(session)
 	} // }}}
 
-	fn auth_publickey(self, _: &str, _: &thrussh_keys::key::PublicKey) -> Self::FutureAuth /* {{{ */ {
-		// TODO:  Actually validate authenticaiton.
-		eprintln!("auth key success");
+	fn channel_eof(mut self, channel: ChannelId, session: Session) -> Self::FutureUnit /* {{{ */ {
+		// Dropping the sender ends the session's input stream; it'll finish outstanding requests and then close the channel
+		if let Some(state) = self.channels.get_mut(&channel) {
+			state.input = None;
+		}
+		self.finished(session)
+	} // }}}
+
+	fn channel_close(mut self, channel: ChannelId, mut session: Session) -> Self::FutureUnit /* {{{ */ {
+		self.clients.lock().unwrap().remove(&(self.id, channel));
+		self.channels.remove(&channel);
+		// A session that's been ended by the server closes its channel once it's done; the client acknowledging that
+		//    is our cue to hang up
+		let hangup = self.hangup.lock().unwrap().take();
+		if let Some(limit) = hangup {
+			session.disconnect(limit.disconnect_reason(), &limit.to_string(), "en");
+		} else if(self.draining.is_triggered() && self.channels.is_empty()) {
+			session.disconnect(Disconnect::ByApplication, "Server is shutting down", "en");
+		}
+		self.finished(session)
+	} // }}}
+
+	fn channel_open_x11(self, channel: ChannelId, _: &str, _: u32, mut session: Session) -> Self::FutureUnit /* {{{ */ {
+		warn!("Session {}:  refusing X11 channel {:?}", self.id, channel);
+		session.close(channel);
+		self.finished(session)
+	} // }}}
+
+	fn channel_open_direct_tcpip(self, channel: ChannelId, host: &str, port: u32, _: &str, _: u32, mut session: Session) -> Self::FutureUnit /* {{{ */ {
+		warn!("Session {}:  refusing direct TCP/IP channel {:?} to {}:{}", self.id, channel, host, port);
+		session.close(channel);
+		self.finished(session)
+	} // }}}
+
+	fn tcpip_forward(self, address: &str, port: u32, session: Session) -> Self::FutureBool /* {{{ */ {
+		warn!("Session {}:  refusing TCP/IP forwarding from {}:{}", self.id, address, port);
+		self.finished_bool(false, session)
+	} // }}}
+
+	fn cancel_tcpip_forward(self, _: &str, _: u32, session: Session) -> Self::FutureBool /* {{{ */ {
+		self.finished_bool(false, session)
+	} // }}}
+
+	fn pty_request(self, channel: ChannelId, _: &str, _: u32, _: u32, _: u32, _: u32, _: &[(Pty, u32)], session: Session) -> Self::FutureUnit /* {{{ */ {
+		self.reject_channel_request(channel, "pty", session)
+	} // }}}
+
+	fn x11_request(self, channel: ChannelId, _: bool, _: &str, _: &str, _: u32, session: Session) -> Self::FutureUnit /* {{{ */ {
+		self.reject_channel_request(channel, "x11", session)
+	} // }}}
+
+	fn env_request(self, channel: ChannelId, _: &str, _: &str, session: Session) -> Self::FutureUnit /* {{{ */ {
+		self.reject_channel_request(channel, "env", session)
+	} // }}}
+
+	fn shell_request(self, channel: ChannelId, session: Session) -> Self::FutureUnit /* {{{ */ {
+		self.reject_channel_request(channel, "shell", session)
+	} // }}}
+
+	fn exec_request(self, channel: ChannelId, data: &[u8], session: Session) -> Self::FutureUnit /* {{{ */ {
+		let command = String::from_utf8_lossy(data).to_string();
+		let scp = match self.config.scp {
+			true => scp::Command::parse(&command),
+			false => None
+		};
+		match scp {
+			Some(scp) => self.start_channel(channel, &format!("exec {:?}", command), session, move |server, io, ctx| async move {
+				server.serve_scp(io, ctx, &scp).await
+			}),
+			None => self.reject_channel_request(channel, &format!("exec {:?}", command), session)
+		}
+	} // }}}
+
+	fn auth_publickey(mut self, user: &str, key: &thrussh_keys::key::PublicKey) -> Self::FutureAuth /* {{{ */ {
+		if(!self.authenticator.public_key(user, key)) {
+			info!("Session {}:  rejected public key for user {}", self.id, user);
+			return self.finished_auth(Auth::Reject);
+		}
+		self.user = Some(user.to_string());
 		self.finished_auth(Auth::Accept)
 	} // }}}
 
-	fn auth_keyboard_interactive(self, user: &str, submethods: &str, response: Option<Response>) -> Self::FutureAuth /* {{{ */ {
-		// TODO:  Actually validate authentication.
-		eprintln!("auth_keyboard_interactive('{}', '{}', {:?})", user, submethods, response);
-		eprintln!("auth int success");
+	fn auth_password(mut self, user: &str, password: &str) -> Self::FutureAuth /* {{{ */ {
+		if(!self.authenticator.password(user, password)) {
+			info!("Session {}:  rejected password for user {}", self.id, user);
+			return self.finished_auth(Auth::Reject);
+		}
+		self.user = Some(user.to_string());
 		self.finished_auth(Auth::Accept)
 	} // }}}
 
-	fn data(mut self, channel: ChannelId, data: &[u8], mut session: Session) -> Self::FutureUnit /* {{{ */ {
-		let mut partial_packet = self.partial_packets.entry(channel).or_insert_with(|| PartialPacket::new());
-		let packet = match parse_packet(data, &mut partial_packet) {
-			Ok(Some(v)) => v,
-			Ok(None) => return self.finished(session),
-			Err(e) => {
-				eprintln!("!!! data():  Failed to parse packet in channel {:?}:  {:?}", channel, e);
-				return ready(Err(e));
+	fn data(self, channel: ChannelId, data: &[u8], session: Session) -> Self::FutureUnit /* {{{ */ {
+		let (input, output) = match self.channels.get(&channel).map(|c| (c.input.clone(), c.output.clone())) {
+			Some((Some(input), Some(output))) => (input, output),
+			_ => {
+				warn!("Session {}:  discarding {} bytes on channel {:?}, which has no session running", self.id, data.len(), channel);
+				return self.finished(session);
 			}
 		};
-		let response = match block_on(self.process_packet(packet)) {
-			Ok(Some(v)) => v,
-			Ok(None) => return self.finished(session),
-			Err(e) => {
-				eprintln!("!!! data():  Failed to process packet in channel {:?}:  {:?}", channel, e);
-				return ready(Err(e));
+		let data = data.to_vec();
+		Box::pin(async move {
+			// thrussh reads nothing more from the connection until this returns, so waiting here for the session to
+			//    catch up is what holds back a client that sends requests faster than they're answered.  The session's
+			//    output also goes out through the connection, though; if that's backed up, the session may be waiting
+			//    on us in turn, so take the data anyway rather than deadlock.
+			input.ready_unless(&output).await;
+			if let Err(data) = input.send_now(data) {
+				warn!("Session {}:  session on channel {:?} has already ended; discarding {} bytes", self.id, channel, data.len());
 			}
+			Ok((self, session))
+		})
+	} // }}}
+
+	fn subsystem_request(self, channel: ChannelId, name: &str, session: Session) -> Self::FutureUnit /* {{{ */ {
+		if(!self.config.sftp_subsystems.iter().any(|s| s == name)) {
+			return self.reject_channel_request(channel, &format!("subsystem {}", name), session);
+		}
+		self.start_channel(channel, &format!("subsystem {}", name), session, |server, io, ctx| async move {
+			server.serve_session(io, ctx).await
+		})
+	} // }}}
+}
+
+#[cfg(feature = "standalone")]
+impl Server {
+	// Runs `serve` on the channel, over a pipe carrying the channel's data; once it returns, its result is sent to the
+	//    client as the channel's exit status and the channel is closed
+	fn start_channel<F, Fut>(mut self, channel: ChannelId, request: &str, mut session: Session, serve: F) -> <Self as Handler>::FutureUnit /* {{{ */
+	where
+		F: FnOnce(Server, transport::Pipe, SessionContext) -> Fut,
+		Fut: Future<Output = Result<(), Error>> + Send + 'static
+	{
+		// Refuse if the channel is unknown or already has something running, or if the server is on its way down
+		if(self.draining.is_triggered() || self.channels.get(&channel).map(|c| c.input.is_some()).unwrap_or(true)) {
+			return self.reject_channel_request(channel, request, session);
+		}
+
+		let (local, remote) = transport::pipe();
+		let (input, mut output) = remote.into_parts();
+		let state = self.channels.get_mut(&channel).unwrap();
+		state.input = Some(Arc::new(input));
+		state.output = Some(output.backlog());
+
+		let ctx = SessionContext{
+			id: self.id,
+			user: self.user.clone(),
+			peer: self.peer,
+			home: None
 		};
-		session.data(channel, response.into());
+		let task = tokio::spawn(serve(self.clone(), local, ctx));
+		let id = self.id;
+		let request = request.to_string();
+		let mut handle = session.handle();
+		tokio::spawn(async move {
+			while let Some(response) = output.recv().await {
+				if(handle.data(channel, CryptoVec::from_slice(&response)).await.is_err()) {
+					break;
+				}
+			}
+			let status = match task.await {
+				Ok(Ok(_)) => 0,
+				Ok(Err(e)) => {
+					warn!("Session {}:  {} on channel {:?} failed:  {:?}", id, request, channel, e);
+					1
+				},
+				Err(e) => {
+					warn!("Session {}:  {} on channel {:?} panicked:  {:?}", id, request, channel, e);
+					1
+				}
+			};
+			let _ = handle.exit_status_request(channel, status).await;
+			let _ = handle.eof(channel).await;
+			let _ = handle.close(channel).await;
+		});
+
+		session.channel_success(channel);
 		self.finished(session)
 	} // }}}
 
-	fn subsystem_request(self, channel: ChannelId, name: &str, session: Session) -> Self::FutureUnit /* {{{ */ {
-		// TODO:  We should keep state at the Server level for whether or not
-		//    the SFTP subsystem has indeed been requested for a given channel
-		//    ID.
+	fn reject_channel_request(self, channel: ChannelId, request: &str, mut session: Session) -> <Self as Handler>::FutureUnit /* {{{ */ {
+		warn!("Session {}:  refusing {} request on channel {:?}", self.id, request, channel);
+		session.channel_failure(channel);
 		self.finished(session)
 	} // }}}
 }
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use super::config::LockPolicy;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockMode {
+	Read,
+	Write
+}
+
+/// What a lock is held on, as chosen by `Backend::lock_key()`.  Sessions only exclude each other when they arrive at
+///    the same key, so the closer it comes to identifying the stored file rather than a name for it, the better.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum LockKey {
+	/// An existing file, by something that stays the same whichever name it's reached by (e.g. device and inode)
+	File{device: u64, inode: u64},
+	/// A file by its full path in the backend's own storage
+	Path(PathBuf)
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+	readers: usize,
+	writers: usize
+}
+
+/// Tracks which files are open for reading and writing, keyed by `LockKey`.  Locks are advisory and only apply to
+///    clients of this server; they're held by `LockGuard`s and released when the guard is dropped.
+#[derive(Debug)]
+pub struct LockManager {
+	policy: LockPolicy,
+	locks: Mutex<HashMap<LockKey, LockState>>
+}
+
+impl LockManager {
+	pub fn new(policy: LockPolicy) -> Self /* {{{ */ {
+		Self{
+			policy: policy,
+			locks: Mutex::new(HashMap::new())
+		}
+	} // }}}
+
+	/// Takes a lock on `key`, or returns None if that would conflict with a lock already held
+	pub fn try_lock(self: &Arc<Self>, key: LockKey, mode: LockMode) -> Option<LockGuard> /* {{{ */ {
+		let mut locks = self.locks.lock().unwrap();
+		let state = locks.entry(key.clone()).or_default();
+		let conflict = match mode {
+			LockMode::Read => self.policy.exclude_readers && state.writers > 0,
+			LockMode::Write => (self.policy.exclusive_writers && state.writers > 0) || (self.policy.exclude_readers && state.readers > 0)
+		};
+		if(conflict) {
+			return None;
+		}
+		match mode {
+			LockMode::Read => state.readers += 1,
+			LockMode::Write => state.writers += 1
+		};
+		Some(LockGuard{
+			manager: self.clone(),
+			key: key,
+			mode: mode
+		})
+	} // }}}
+
+	fn release(&self, key: &LockKey, mode: LockMode) /* {{{ */ {
+		let mut locks = self.locks.lock().unwrap();
+		let empty = match locks.get_mut(key) {
+			Some(state) => {
+				match mode {
+					LockMode::Read => state.readers -= 1,
+					LockMode::Write => state.writers -= 1
+				};
+				state.readers == 0 && state.writers == 0
+			},
+			None => false
+		};
+		if(empty) {
+			locks.remove(key);
+		}
+	} // }}}
+}
+
+#[derive(Debug)]
+pub struct LockGuard {
+	manager: Arc<LockManager>,
+	key: LockKey,
+	mode: LockMode
+}
+
+impl LockGuard {
+	pub fn key(&self) -> &LockKey /* {{{ */ {
+		&self.key
+	} // }}}
+
+	pub fn mode(&self) -> LockMode /* {{{ */ {
+		self.mode
+	} // }}}
+}
+
+impl Drop for LockGuard {
+	fn drop(&mut self) {
+		self.manager.release(&self.key, self.mode);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+	use std::sync::Arc;
+
+	use super::LockKey;
+	use super::LockManager;
+	use super::LockMode;
+	use super::LockPolicy;
+
+	fn manager(exclusive_writers: bool, exclude_readers: bool) -> Arc<LockManager> /* {{{ */ {
+		Arc::new(LockManager::new(LockPolicy{exclusive_writers: exclusive_writers, exclude_readers: exclude_readers}))
+	} // }}}
+
+	fn key(name: &str) -> LockKey /* {{{ */ {
+		LockKey::Path(PathBuf::from(name))
+	} // }}}
+
+	#[test]
+	fn writers_exclude_writers() {
+		let locks = manager(true, false);
+		let _writer = locks.try_lock(key("/a"), LockMode::Write).unwrap();
+		assert!(locks.try_lock(key("/a"), LockMode::Write).is_none());
+		// Readers aren't affected under this policy, whichever order they come in
+		let _reader = locks.try_lock(key("/a"), LockMode::Read).unwrap();
+		let _other = locks.try_lock(key("/b"), LockMode::Write).unwrap();
+	}
+
+	#[test]
+	fn readers_share() {
+		let locks = manager(true, true);
+		let _first = locks.try_lock(key("/a"), LockMode::Read).unwrap();
+		let _second = locks.try_lock(key("/a"), LockMode::Read).unwrap();
+		assert!(locks.try_lock(key("/a"), LockMode::Write).is_none());
+	}
+
+	#[test]
+	fn writers_exclude_readers() {
+		let locks = manager(true, true);
+		let _writer = locks.try_lock(key("/a"), LockMode::Write).unwrap();
+		assert!(locks.try_lock(key("/a"), LockMode::Read).is_none());
+		assert!(locks.try_lock(key("/a"), LockMode::Write).is_none());
+	}
+
+	#[test]
+	fn nothing_excluded() {
+		let locks = manager(false, false);
+		let _first = locks.try_lock(key("/a"), LockMode::Write).unwrap();
+		let _second = locks.try_lock(key("/a"), LockMode::Write).unwrap();
+		let _reader = locks.try_lock(key("/a"), LockMode::Read).unwrap();
+	}
+
+	#[test]
+	fn keys_are_separate() {
+		let locks = manager(true, true);
+		let _path = locks.try_lock(key("/a"), LockMode::Write).unwrap();
+		let _file = locks.try_lock(LockKey::File{device: 1, inode: 2}, LockMode::Write).unwrap();
+		let _other = locks.try_lock(LockKey::File{device: 1, inode: 3}, LockMode::Write).unwrap();
+		assert!(locks.try_lock(LockKey::File{device: 1, inode: 2}, LockMode::Read).is_none());
+	}
+
+	#[test]
+	fn released_on_drop() {
+		let locks = manager(true, true);
+		let writer = locks.try_lock(key("/a"), LockMode::Write).unwrap();
+		assert_eq!(writer.key(), &key("/a"));
+		assert_eq!(writer.mode(), LockMode::Write);
+		drop(writer);
+		let first = locks.try_lock(key("/a"), LockMode::Read).unwrap();
+		let second = locks.try_lock(key("/a"), LockMode::Read).unwrap();
+		drop(first);
+		// One reader is still there
+		assert!(locks.try_lock(key("/a"), LockMode::Write).is_none());
+		drop(second);
+		let _writer = locks.try_lock(key("/a"), LockMode::Write).unwrap();
+	}
+
+	#[test]
+	fn released_entries_are_forgotten() {
+		let locks = manager(true, false);
+		let guards: Vec<_> = (0..10).map(|i| locks.try_lock(key(&format!("/{}", i)), LockMode::Write).unwrap()).collect();
+		assert_eq!(locks.locks.lock().unwrap().len(), 10);
+		drop(guards);
+		assert!(locks.locks.lock().unwrap().is_empty());
+	}
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Error;
+
+use bincode::Options;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use sftp_protocol::Packet;
+use sftp_protocol::Payload;
+
+use super::Server;
+use super::SessionContext;
+use super::SessionInfo;
+use super::config::Recording;
+
+/// One entry in a session recording.  A recording is a sequence of these, bincode-encoded back to back, starting with
+///    a `Session` and normally ending with an `End`.  Packets are stored exactly as they went over the wire, except
+///    that redacted ones have their trailing data removed; `redacted` then holds its original length.  A packet that
+///    was rejected without being parsed (see `RejectedPacket`) is recorded as a `Rejected` in place of its request,
+///    followed by the response it was given.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Record {
+	Session{
+		key: usize,
+		id: usize,
+		user: Option<String>,
+		peer: Option<SocketAddr>,
+		started: SystemTime
+	},
+	// Timestamps are microseconds since the session started
+	Request{
+		elapsed: u64,
+		packet: Vec<u8>,
+		redacted: Option<u32>
+	},
+	Response{
+		elapsed: u64,
+		packet: Vec<u8>,
+		redacted: Option<u32>
+	},
+	End{
+		elapsed: u64,
+		error: Option<String>
+	},
+	// After End, so that recordings made before it existed still read
+	Rejected{
+		elapsed: u64,
+		id: u32,
+		message: String
+	}
+}
+
+impl Record {
+	/// Reads the next record of a recording; None at its end
+	pub fn read(input: &mut impl io::Read) -> Result<Option<Self>, Error> /* {{{ */ {
+		match options().deserialize_from(input) {
+			Ok(v) => Ok(Some(v)),
+			Err(e) => match *e {
+				bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+				_ => Err(e.into())
+			}
+		}
+	} // }}}
+}
+
+fn options() -> impl Options /* {{{ */ {
+	bincode::DefaultOptions::new()
+} // }}}
+
+fn serialize_packet(packet: &Packet) -> Result<Vec<u8>, Error> /* {{{ */ {
+	Ok(bincode::DefaultOptions::new().with_big_endian().with_fixint_encoding().serialize(packet)?)
+} // }}}
+
+// Length of the data carried by a WRITE or DATA packet; both keep it at the very end, so it can be cut off when redacting
+fn data_len(packet: &Packet) -> Option<u32> /* {{{ */ {
+	match &packet.payload {
+		Payload::Write(w) => Some(w.data.len() as u32),
+		Payload::Data(d) => Some(d.data.len() as u32),
+		_ => None
+	}
+} // }}}
+
+/// Writes one session's traffic to a file under `Recording::dir`, readable only by the server's own user since it can
+///    hold whole uploads.  Records are handed to a writer thread of the recording's own, so that the session never
+///    waits on the disk; the queue isn't bounded, so a disk that can't keep up costs memory rather than records.  A
+///    recording that fails to write is abandoned without affecting the session.
+pub struct Recorder {
+	path: PathBuf,
+	queue: Mutex<mpsc::Sender<Record>>,
+	started: Instant,
+	redact: bool,
+	failed: Arc<AtomicBool>
+}
+
+impl Recorder {
+	pub fn create(config: &Recording, info: &SessionInfo) -> Result<Self, Error> /* {{{ */ {
+		let since_epoch = info.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+		let path = config.dir.join(format!("{}-{}.sftprec", since_epoch.as_millis(), info.key));
+		let out = BufWriter::new(fs::OpenOptions::new().create_new(true).write(true).mode(0o600).open(&path)?);
+		let (queue, records) = mpsc::channel();
+		let failed = Arc::new(AtomicBool::new(false));
+		{
+			let path = path.clone();
+			let failed = failed.clone();
+			thread::Builder::new().name(format!("sftp-recorder-{}", info.key)).spawn(move || write_records(path, out, records, failed))?;
+		}
+		let this = Self{
+			path: path,
+			queue: Mutex::new(queue),
+			started: Instant::now(),
+			redact: config.redact_data,
+			failed: failed
+		};
+		this.write(Record::Session{
+			key: info.key,
+			id: info.ctx.id,
+			user: info.ctx.user.clone(),
+			peer: info.ctx.peer,
+			started: info.started
+		});
+		Ok(this)
+	} // }}}
+
+	fn elapsed(&self) -> u64 /* {{{ */ {
+		self.started.elapsed().as_micros() as u64
+	} // }}}
+
+	fn write(&self, record: Record) /* {{{ */ {
+		if(self.failed.load(Ordering::Relaxed)) {
+			return;
+		}
+		// The writer only goes away after failing, and it's already said why
+		if(self.queue.lock().unwrap().send(record).is_err()) {
+			self.failed.store(true, Ordering::Relaxed);
+		}
+	} // }}}
+
+	fn packet(&self, packet: &Packet) -> Option<(Vec<u8>, Option<u32>)> /* {{{ */ {
+		let mut bytes = match serialize_packet(packet) {
+			Ok(v) => v,
+			Err(e) => {
+				warn!("Failed to serialize {:?} for session recording {:?}:  {:?}", packet.header.kind, self.path, e);
+				return None;
+			}
+		};
+		let redacted = data_len(packet).filter(|_| self.redact);
+		if let Some(len) = redacted {
+			bytes.truncate(bytes.len() - len as usize);
+		}
+		Some((bytes, redacted))
+	} // }}}
+
+	pub fn request(&self, packet: &Packet) /* {{{ */ {
+		if let Some((bytes, redacted)) = self.packet(packet) {
+			self.write(Record::Request{
+				elapsed: self.elapsed(),
+				packet: bytes,
+				redacted: redacted
+			});
+		}
+	} // }}}
+
+	pub fn rejected(&self, id: u32, message: &str) /* {{{ */ {
+		self.write(Record::Rejected{
+			elapsed: self.elapsed(),
+			id: id,
+			message: message.to_string()
+		});
+	} // }}}
+
+	pub fn response(&self, packet: &Packet) /* {{{ */ {
+		if let Some((bytes, redacted)) = self.packet(packet) {
+			self.write(Record::Response{
+				elapsed: self.elapsed(),
+				packet: bytes,
+				redacted: redacted
+			});
+		}
+	} // }}}
+
+	pub fn finish(&self, result: &Result<(), Error>) /* {{{ */ {
+		self.write(Record::End{
+			elapsed: self.elapsed(),
+			error: result.as_ref().err().map(|e| format!("{:?}", e))
+		});
+	} // }}}
+}
+
+// Body of a recording's writer thread; runs until the recorder is dropped, flushing at the end of the session
+fn write_records(path: PathBuf, mut out: BufWriter<fs::File>, records: mpsc::Receiver<Record>, failed: Arc<AtomicBool>) /* {{{ */ {
+	for record in records {
+		let mut result = options().serialize_into(&mut out, &record).map_err(Error::from);
+		if let (Ok(_), Record::End{..}) = (&result, &record) {
+			result = out.flush().map_err(Error::from);
+		}
+		if let Err(e) = result {
+			warn!("Abandoning session recording {:?}:  {:?}", path, e);
+			failed.store(true, Ordering::Relaxed);
+			return;
+		}
+	}
+	if let Err(e) = out.flush() {
+		warn!("Failed to flush session recording {:?}:  {:?}", path, e);
+	}
+} // }}}
+
+/// A response from the replay that didn't match the recorded one
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+	pub session: usize,
+	pub request_id: u32,
+	pub expected: String,
+	pub actual: String
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+	pub sessions: usize,
+	pub requests: usize,
+	// Packets the recorded session rejected unparsed, which aren't replayed
+	pub rejected: usize,
+	pub mismatches: Vec<Mismatch>
+}
+
+impl fmt::Display for ReplayReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "replayed {} request(s) in {} session(s), skipping {} rejected packet(s); {} mismatch(es)", self.requests, self.sessions, self.rejected, self.mismatches.len())?;
+		for m in &self.mismatches {
+			write!(f, "\n\tsession {}, request {}:\n\t\texpected {}\n\t\tactual   {}", m.session, m.request_id, m.expected, m.actual)?;
+		}
+		Ok(())
+	}
+}
+
+fn describe(bytes: &[u8]) -> String /* {{{ */ {
+	match Packet::parse(bytes) {
+		Ok((_, packet)) => format!("{:?}", packet.payload),
+		Err(_) => format!("<{} unparseable bytes>", bytes.len())
+	}
+} // }}}
+
+// Handles are UUIDs sent as 36-character strings, so they can be swapped for one another in place
+fn replace_handles(bytes: &mut [u8], handles: &HashMap<String, String>) /* {{{ */ {
+	for (from, to) in handles {
+		let (from, to) = (from.as_bytes(), to.as_bytes());
+		let mut i = 0;
+		while(i + from.len() <= bytes.len()) {
+			if(&bytes[i..i + from.len()] == from) {
+				bytes[i..i + from.len()].copy_from_slice(to);
+				i += from.len();
+			} else {
+				i += 1;
+			}
+		}
+	}
+} // }}}
+
+fn handle_of(bytes: &[u8]) -> Option<String> /* {{{ */ {
+	match Packet::parse(bytes) {
+		Ok((_, Packet{payload: Payload::Handle(h), ..})) => Some(h.handle.to_string()),
+		_ => None
+	}
+} // }}}
+
+// State for the session currently being replayed
+struct Replaying {
+	key: usize,
+	server: Server,
+	// Recorded handle -> the handle the replay was given in its place, and the reverse
+	handles: HashMap<String, String>,
+	reverse: HashMap<String, String>,
+	// Request IDs of rejected packets whose responses are still to come, and aren't compared
+	rejected: HashSet<u32>,
+	// Replay responses not yet compared, by request ID, with the length of any data they carry
+	responses: HashMap<u32, Result<(Vec<u8>, Option<u32>), String>>
+}
+
+impl Server {
+	/// Feeds a recording made under `Config::recording` back through this server's request handling, one request at
+	///    a time in the order they were received, using the backend its factory gives the recorded user.  Handles
+	///    are mapped from the recorded ones to those the replay was given, and responses are compared byte for byte
+	///    (or, for redacted DATA, by everything except the data itself).
+	pub async fn replay(&self, mut input: impl io::Read) -> Result<ReplayReport, Error> /* {{{ */ {
+		let mut report = ReplayReport::default();
+		let mut current: Option<Replaying> = None;
+		loop {
+			let record = match Record::read(&mut input)? {
+				Some(v) => v,
+				None => break
+			};
+			match record {
+				Record::Session{key, id, user, peer, ..} => {
+					if let Some(previous) = current.take() {
+						self.finish_replay(previous, &mut report).await;
+					}
+					let ctx = SessionContext{
+						id: id,
+						user: user,
+						peer: peer,
+						home: None
+					};
+					let mut server = self.new_session(ctx.clone());
+					server.backend = self.backends.backend(&ctx)?;
+					report.sessions += 1;
+					current = Some(Replaying{
+						key: key,
+						server: server,
+						handles: HashMap::new(),
+						reverse: HashMap::new(),
+						rejected: HashSet::new(),
+						responses: HashMap::new()
+					});
+				},
+				Record::Request{mut packet, redacted, ..} => {
+					let session = current.as_mut().ok_or_else(|| Error::msg("Recording has a request before its session record"))?;
+					if let Some(len) = redacted {
+						packet.resize(packet.len() + len as usize, 0);
+					}
+					replace_handles(&mut packet, &session.handles);
+					let request = match Packet::parse(&packet) {
+						Ok((_, v)) => v,
+						Err(e) => return Err(Error::msg(format!("Failed to parse recorded request:  {}", e)))
+					};
+					let id = request.payload.request_id().unwrap_or(0);
+					report.requests += 1;
+					let response = match session.server.process_request(request).await {
+						Ok(response) => match serialize_packet(&response) {
+							Ok(bytes) => Ok((bytes, data_len(&response))),
+							Err(e) => Err(format!("{:?}", e))
+						},
+						Err(e) => Err(format!("{:?}", e))
+					};
+					session.responses.insert(id, response);
+				},
+				Record::Response{packet, redacted, ..} => {
+					let session = current.as_mut().ok_or_else(|| Error::msg("Recording has a response before its session record"))?;
+					let id = match Packet::parse(&packet) {
+						Ok((_, v)) => v.payload.request_id().unwrap_or(0),
+						Err(e) => return Err(Error::msg(format!("Failed to parse recorded response:  {}", e)))
+					};
+					if(session.rejected.remove(&id)) {
+						continue;
+					}
+					let actual = match session.responses.remove(&id) {
+						Some(v) => v,
+						None => Err("no response".to_string())
+					};
+					let matched = match &actual {
+						Ok((bytes, actual_len)) => match (handle_of(&packet), handle_of(bytes)) {
+							(Some(recorded), Some(replayed)) => {
+								session.reverse.insert(replayed.clone(), recorded.clone());
+								session.handles.insert(recorded, replayed);
+								true
+							},
+							_ => {
+								let mut bytes = bytes.clone();
+								replace_handles(&mut bytes, &session.reverse);
+								// Redacted recordings don't have the data to compare, only its length
+								if let (Some(_), Some(len)) = (redacted, actual_len) {
+									bytes.truncate(bytes.len() - *len as usize);
+								}
+								bytes == packet && (redacted.is_none() || redacted == *actual_len)
+							}
+						},
+						Err(_) => false
+					};
+					if(!matched) {
+						report.mismatches.push(Mismatch{
+							session: session.key,
+							request_id: id,
+							expected: describe(&packet),
+							actual: match actual {
+								Ok((bytes, _)) => describe(&bytes),
+								Err(e) => format!("error:  {}", e)
+							}
+						});
+					}
+				},
+				// There's nothing to replay, since the packet never got as far as being parsed
+				Record::Rejected{id, ..} => {
+					let session = current.as_mut().ok_or_else(|| Error::msg("Recording has a rejected packet before its session record"))?;
+					session.rejected.insert(id);
+					report.rejected += 1;
+				},
+				Record::End{..} => {
+					if let Some(previous) = current.take() {
+						self.finish_replay(previous, &mut report).await;
+					}
+				}
+			};
+		}
+		if let Some(previous) = current.take() {
+			self.finish_replay(previous, &mut report).await;
+		}
+		Ok(report)
+	} // }}}
+
+	async fn finish_replay(&self, session: Replaying, report: &mut ReplayReport) /* {{{ */ {
+		// Responses the recorded session never sent, because it failed partway or was cut off
+		let mut extra: Vec<_> = session.responses.into_iter().collect();
+		extra.sort_by_key(|(id, _)| *id);
+		for (id, actual) in extra {
+			report.mismatches.push(Mismatch{
+				session: session.key,
+				request_id: id,
+				expected: "no response".to_string(),
+				actual: match actual {
+					Ok((bytes, _)) => describe(&bytes),
+					Err(e) => format!("error:  {}", e)
+				}
+			});
+		}
+		session.server.close_all().await;
+	} // }}}
+}
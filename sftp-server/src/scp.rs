@@ -0,0 +1,796 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::time::Instant;
+
+use anyhow::Error;
+
+use sftp_protocol::Error as ProtocolError;
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::common::Metadata;
+
+use super::Server;
+use super::backend::OpenOptions;
+use super::file::OpenFile;
+use super::lock::LockGuard;
+use super::lock::LockMode;
+use super::session::SessionContext;
+use super::session::SessionLimit;
+use super::session::expire_at;
+
+// How much file data is moved between the client and the backend at once
+const CHUNK: usize = 64 * 1024;
+// Longest protocol line (a record header or an error message) we'll accept from the client
+const MAX_LINE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Direction {
+	// "scp -t":  the client is uploading to us
+	Sink,
+	// "scp -f":  the client is downloading from us
+	Source
+}
+
+/// An SCP invocation, as sent by the client in an exec request (e.g. "scp -r -t -- /incoming")
+#[derive(Clone, Debug)]
+pub struct Command {
+	direction: Direction,
+	recursive: bool,
+	preserve: bool,
+	// -d:  the target of an upload must be an existing directory
+	target_dir: bool,
+	paths: Vec<String>
+}
+
+impl Command {
+	/// Parses an exec request's command line, returning None unless it runs scp as a source or sink.  Quoting is
+	///    undone the way a shell would; nothing else a shell would do (globbing, variables) is.
+	pub fn parse(command: &str) -> Option<Self> /* {{{ */ {
+		let mut words = split_words(command)?.into_iter();
+		let program = words.next()?;
+		if(program != "scp" && !program.ends_with("/scp")) {
+			return None;
+		}
+		let mut direction = None;
+		let mut recursive = false;
+		let mut preserve = false;
+		let mut target_dir = false;
+		let mut paths = Vec::new();
+		let mut options_done = false;
+		for word in words {
+			if(options_done || !word.starts_with('-') || word == "-") {
+				options_done = true;
+				paths.push(word);
+				continue;
+			}
+			if(word == "--") {
+				options_done = true;
+				continue;
+			}
+			for flag in word[1..].chars() {
+				match flag {
+					't' | 'f' => {
+						let this = if(flag == 't') { Direction::Sink } else { Direction::Source };
+						if(direction.replace(this).map(|d| d != this).unwrap_or(false)) {
+							return None;
+						}
+					},
+					'r' => recursive = true,
+					'p' => preserve = true,
+					'd' => target_dir = true,
+					// Verbosity and the like only affect the client's side
+					'v' | 'q' | 'E' => (),
+					_ => return None
+				};
+			}
+		}
+		let direction = direction?;
+		match (direction, paths.len()) {
+			(_, 0) => return None,
+			(Direction::Sink, n) if n > 1 => return None,
+			_ => ()
+		};
+		Some(Self{
+			direction: direction,
+			recursive: recursive,
+			preserve: preserve,
+			target_dir: target_dir,
+			paths: paths
+		})
+	} // }}}
+}
+
+// Splits a command line into words, undoing single quotes, double quotes and backslashes as sh does
+fn split_words(command: &str) -> Option<Vec<String>> /* {{{ */ {
+	let mut words = Vec::new();
+	let mut word: Option<String> = None;
+	let mut chars = command.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			' ' | '\t' | '\n' => words.extend(word.take()),
+			'\'' => {
+				let word = word.get_or_insert_with(String::new);
+				loop {
+					match chars.next()? {
+						'\'' => break,
+						c => word.push(c)
+					};
+				}
+			},
+			'"' => {
+				let word = word.get_or_insert_with(String::new);
+				loop {
+					match chars.next()? {
+						'"' => break,
+						'\\' => {
+							// Inside double quotes, a backslash only escapes characters that would otherwise be special
+							let c = chars.next()?;
+							if(!matches!(c, '"' | '\\' | '$' | '`')) {
+								word.push('\\');
+							}
+							word.push(c);
+						},
+						c => word.push(c)
+					};
+				}
+			},
+			'\\' => word.get_or_insert_with(String::new).push(chars.next()?),
+			c => word.get_or_insert_with(String::new).push(c)
+		};
+	}
+	words.extend(word);
+	Some(words)
+} // }}}
+
+// Parses the rest of a C or D record ("0644 1234 name") into mode, size and name
+fn parse_record(record: &str) -> Option<(u32, u64, String)> /* {{{ */ {
+	let mut parts = record.splitn(3, ' ');
+	let mode = u32::from_str_radix(parts.next()?, 8).ok()?;
+	let size = parts.next()?.parse().ok()?;
+	let name = parts.next()?.to_string();
+	Some((mode, size, name))
+} // }}}
+
+// Parses the rest of a T record ("<mtime> 0 <atime> 0") into (atime, mtime), the order set_metadata() takes them in
+fn parse_times(record: &str) -> Option<(u32, u32)> /* {{{ */ {
+	let fields: Vec<u32> = record.split(' ').map(|f| f.parse().ok()).collect::<Option<_>>()?;
+	match fields.as_slice() {
+		[mtime, _, atime, _] => Some((*atime, *mtime)),
+		_ => None
+	}
+} // }}}
+
+// Names in records are a single path component; anything else could climb out of the directory being written to
+fn valid_name(name: &str) -> bool /* {{{ */ {
+	!name.is_empty() && name != "." && name != ".." && !name.contains('/')
+} // }}}
+
+// Name to send for `path` in a record, if it can be represented in one
+fn record_name(path: &Path) -> Option<String> /* {{{ */ {
+	let name = path.file_name()?.to_string_lossy().to_string();
+	match name.contains('\n') {
+		true => None,
+		false => Some(name)
+	}
+} // }}}
+
+// The I/O error itself says more than ProtocolError's description of it
+fn describe(e: &ProtocolError) -> String /* {{{ */ {
+	match e {
+		ProtocolError::IO(e) => e.to_string(),
+		e => e.to_string()
+	}
+} // }}}
+
+// Waits for `f`, unless the session expires first
+async fn within<F: Future>(deadline: Option<(Instant, SessionLimit)>, f: F) -> Result<F::Output, SessionLimit> /* {{{ */ {
+	tokio::select! {
+		output = f => Ok(output),
+		limit = expire_at(deadline) => Err(limit)
+	}
+} // }}}
+
+struct Transfer<'a, R, W> {
+	server: &'a Server,
+	command: &'a Command,
+	reader: BufReader<R>,
+	writer: W,
+	// Files that couldn't be transferred; each is reported to the client as it happens, and the total decides the exit status
+	errors: usize,
+	started: Instant,
+	// When the client last sent us anything
+	last_activity: Instant
+}
+
+impl<'a, R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Transfer<'a, R, W> {
+	async fn run(&mut self) -> Result<(), Error> /* {{{ */ {
+		match self.command.direction {
+			Direction::Sink => self.sink().await,
+			Direction::Source => self.source().await
+		}
+	} // }}}
+
+	// When the session expires if the client keeps us waiting; as with SFTP, time spent on the backend isn't idle time
+	fn deadline(&self) -> Option<(Instant, SessionLimit)> /* {{{ */ {
+		self.server.deadline(self.started, self.last_activity, true)
+	} // }}}
+
+	// Whether the request filter allows the SFTP request that a step of the transfer stands in for
+	fn permits(&self, request: &str) -> bool /* {{{ */ {
+		self.server.config.request_filter.permits(request)
+	} // }}}
+
+	async fn send(&mut self, data: &[u8]) -> Result<(), Error> /* {{{ */ {
+		self.writer.write_all(data).await?;
+		self.writer.flush().await?;
+		Ok(())
+	} // }}}
+
+	async fn ok(&mut self) -> Result<(), Error> /* {{{ */ {
+		self.send(&[0]).await
+	} // }}}
+
+	// Reports a problem with one file; the client prints it and carries on with the next one
+	async fn warn(&mut self, message: String) -> Result<(), Error> /* {{{ */ {
+		info!("Session {} (user {:?}):  scp:  {}", self.server.id, self.server.user, message);
+		self.errors += 1;
+		self.send(format!("\x01scp: {}\n", message).as_bytes()).await
+	} // }}}
+
+	// Reports a problem that ends the whole transfer, returning the error to end it with
+	async fn fatal(&mut self, message: String) -> Error /* {{{ */ {
+		warn!("Session {} (user {:?}):  scp:  {}", self.server.id, self.server.user, message);
+		if let Err(e) = self.send(format!("\x02scp: {}\n", message).as_bytes()).await {
+			debug!("Session {}:  failed to send scp error to client:  {:?}", self.server.id, e);
+		}
+		Error::msg(message)
+	} // }}}
+
+	// Reads a line from the client, without its newline; None if the client closed the stream instead
+	async fn read_line(&mut self) -> Result<Option<Vec<u8>>, Error> /* {{{ */ {
+		let mut line = Vec::new();
+		loop {
+			let byte = match within(self.deadline(), self.reader.read_u8()).await? {
+				Ok(v) => v,
+				Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && line.is_empty() => return Ok(None),
+				Err(e) => return Err(e.into())
+			};
+			self.last_activity = Instant::now();
+			if(byte == b'\n') {
+				return Ok(Some(line));
+			}
+			if(line.len() >= MAX_LINE) {
+				return Err(Error::msg("scp client sent an overlong line"));
+			}
+			line.push(byte);
+		}
+	} // }}}
+
+	// Reads the client's reply to something we sent:  true to carry on, false if it refused (having said why)
+	async fn response(&mut self) -> Result<bool, Error> /* {{{ */ {
+		let code = within(self.deadline(), self.reader.read_u8()).await??;
+		self.last_activity = Instant::now();
+		if(code == 0) {
+			return Ok(true);
+		}
+		let line = self.read_line().await?.unwrap_or_default();
+		let message = String::from_utf8_lossy(&line);
+		match code {
+			1 => {
+				info!("Session {}:  scp client reported:  {}", self.server.id, message);
+				Ok(false)
+			},
+			2 => Err(Error::msg(format!("scp client aborted the transfer:  {}", message))),
+			_ => Err(Error::msg(format!("Unexpected response {} from scp client", code)))
+		}
+	} // }}}
+
+	// Reads the next record of an upload, unless the server has started shutting down:  like an SFTP session that stops
+	//    reading requests but answers those it has, a file already under way is finished, but no new one is started
+	async fn next_record(&mut self) -> Result<Option<Vec<u8>>, Error> /* {{{ */ {
+		let server = self.server;
+		if(!server.draining.is_triggered()) {
+			tokio::select! {
+				line = self.read_line() => return line,
+				_ = server.draining.wait() => ()
+			};
+		}
+		Err(self.fatal("Server is shutting down".to_string()).await)
+	} // }}}
+
+	// The same for downloads, between one file or directory and the next
+	async fn check_draining(&mut self) -> Result<(), Error> /* {{{ */ {
+		match self.server.draining.is_triggered() {
+			true => Err(self.fatal("Server is shutting down".to_string()).await),
+			false => Ok(())
+		}
+	} // }}}
+
+	async fn send_times(&mut self, metadata: &Metadata) -> Result<bool, Error> /* {{{ */ {
+		self.send(format!("T{} 0 {} 0\n", metadata.mtime.timestamp(), metadata.atime.timestamp()).as_bytes()).await?;
+		self.response().await
+	} // }}}
+
+	async fn sink(&mut self) -> Result<(), Error> /* {{{ */ {
+		if(self.server.config.read_only || !self.permits("write")) {
+			return Err(self.fatal("Uploads are not permitted".to_string()).await);
+		}
+		let target = self.server.resolve_path(&self.command.paths[0]);
+		let target_is_dir = self.server.backend.metadata(&target).await.map(|m| m.is_dir).unwrap_or(false);
+		if(self.command.target_dir && !target_is_dir) {
+			return Err(self.fatal(format!("{}: Not a directory", target.display())).await);
+		}
+		self.ok().await?;
+
+		// Directories entered with D records and not yet left, with the mode and times to give each once it's complete
+		let mut dirs: Vec<(PathBuf, u32, Option<(u32, u32)>)> = Vec::new();
+		let mut times = None;
+		// A target that isn't a directory can only receive one file; any more would each overwrite the last
+		let mut target_used = false;
+		while let Some(line) = self.next_record().await? {
+			let (kind, record) = match line.split_first() {
+				Some((kind, record)) => (*kind, String::from_utf8_lossy(record).to_string()),
+				None => return Err(self.fatal("Protocol error:  empty record".to_string()).await)
+			};
+			match kind {
+				// Problems on the client's end, such as a file it couldn't read
+				1 => info!("Session {}:  scp client reported:  {}", self.server.id, record),
+				2 => return Err(Error::msg(format!("scp client aborted the transfer:  {}", record))),
+				b'T' => {
+					times = match parse_times(&record) {
+						Some(v) => Some(v),
+						None => return Err(self.fatal(format!("Protocol error:  malformed T record {:?}", record)).await)
+					};
+					self.ok().await?;
+				},
+				b'E' => {
+					let (dir, mode, times) = match dirs.pop() {
+						Some(v) => v,
+						None => return Err(self.fatal("Protocol error:  unexpected E record".to_string()).await)
+					};
+					if(self.command.preserve) {
+						if let Err(e) = self.preserve(&dir, mode, times).await {
+							warn!("Session {}:  failed to preserve mode and times of {:?}:  {:?}", self.server.id, dir, e);
+						}
+					}
+					self.ok().await?;
+				},
+				b'C' | b'D' => {
+					let (mode, size, name) = match parse_record(&record) {
+						Some(v) => v,
+						None => return Err(self.fatal(format!("Protocol error:  malformed record {:?}", record)).await)
+					};
+					if(!valid_name(&name)) {
+						return Err(self.fatal(format!("Unexpected filename {:?}", name)).await);
+					}
+					// Inside the directory being received, inside the target if it's a directory, or else the target itself
+					let path = match dirs.last() {
+						Some((dir, _, _)) => dir.join(&name),
+						None if target_is_dir => target.join(&name),
+						None if target_used => return Err(self.fatal(format!("{}: Not a directory", target.display())).await),
+						None => {
+							target_used = true;
+							target.clone()
+						}
+					};
+					let times = times.take();
+					if(kind == b'C') {
+						self.receive_file(&path, mode, size, times).await?;
+						continue;
+					}
+					if(!self.command.recursive) {
+						return Err(self.fatal(format!("{}: received a directory without -r", path.display())).await);
+					}
+					match self.make_dir(&path, mode).await {
+						Ok(_) => dirs.push((path, mode, times)),
+						Err(e) => return Err(self.fatal(format!("{}: {}", path.display(), describe(&e))).await)
+					};
+					self.ok().await?;
+				},
+				_ => return Err(self.fatal(format!("Protocol error:  unexpected record {:?}", String::from_utf8_lossy(&line))).await)
+			};
+		}
+		Ok(())
+	} // }}}
+
+	async fn make_dir(&self, path: &Path, mode: u32) -> Result<(), ProtocolError> /* {{{ */ {
+		match self.server.backend.metadata(path).await {
+			Ok(metadata) if metadata.is_dir => Ok(()),
+			Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, "Not a directory").into()),
+			Err(_) if !self.permits("mkdir") => Err(ProtocolError::PermissionDenied),
+			Err(_) => {
+				// Writable by us until it's complete, whatever the client asked for; -p puts the exact mode back afterwards
+				let mut attrs = FileAttributes::new();
+				attrs.set_permissions(mode | 0o700);
+				let attrs = self.server.config.mode_policy(self.server.user.as_deref()).apply(&attrs, true);
+				self.server.backend.mkdir(path, attrs).await
+			}
+		}
+	} // }}}
+
+	// Gives a received file or directory the mode and times the client sent with -p
+	async fn preserve(&self, path: &Path, mode: u32, times: Option<(u32, u32)>) -> Result<(), ProtocolError> /* {{{ */ {
+		if(!self.permits("setstat")) {
+			return Err(ProtocolError::PermissionDenied);
+		}
+		// Not the umask, which -p asks to ignore, but the same special bits as anything else created
+		let mode = mode & self.server.config.mode_policy(self.server.user.as_deref()).allowed_bits();
+		self.server.backend.set_metadata(path, None, Some(mode), times).await
+	} // }}}
+
+	async fn open_for_upload(&self, path: &Path, mode: u32) -> Result<(LockGuard, OpenFile), String> /* {{{ */ {
+		if(!self.permits("open")) {
+			return Err(describe(&ProtocolError::PermissionDenied));
+		}
+		let mut lock = self.server.locks.try_lock(self.server.lock_key(path).await, LockMode::Write).ok_or_else(|| "File is locked by another client".to_string())?;
+		let mut attrs = FileAttributes::new();
+		attrs.set_permissions(mode);
+		let options = OpenOptions{
+			write: true,
+			create: true,
+			truncate: true,
+			attrs: self.server.config.mode_policy(self.server.user.as_deref()).apply(&attrs, false),
+			..Default::default()
+		};
+		let file = self.server.backend.open(path, options).await.map_err(|e| describe(&e))?;
+		if(!self.server.relock(&mut lock, path).await) {
+			if let Err(e) = file.abort().await {
+				debug!("Session {}:  failed to close {:?} after losing its lock:  {:?}", self.server.id, path, e);
+			}
+			return Err("File is locked by another client".to_string());
+		}
+		Ok((lock, file))
+	} // }}}
+
+	async fn receive_file(&mut self, path: &Path, mode: u32, size: u64, times: Option<(u32, u32)>) -> Result<(), Error> /* {{{ */ {
+		let (_lock, file) = match self.open_for_upload(path, mode).await {
+			Ok(v) => v,
+			// Refusing the C record tells the client to skip the file without sending its contents
+			Err(message) => return self.warn(format!("{}: {}", path.display(), message)).await
+		};
+		self.ok().await?;
+
+		let mut buf = vec![0u8; CHUNK];
+		let mut offset = 0;
+		let mut failure = None;
+		while(offset < size) {
+			let count = (size - offset).min(CHUNK as u64) as usize;
+			within(self.deadline(), self.reader.read_exact(&mut buf[..count])).await??;
+			self.last_activity = Instant::now();
+			// Keep reading after a failed write, so that we stay in step with the client
+			if(failure.is_none()) {
+				if let Err(e) = file.write_at(offset, &buf[..count]).await {
+					failure = Some(e);
+				}
+			}
+			offset += count as u64;
+		}
+		// The client's verdict on its side of the transfer; if it couldn't read the whole file, it's already said so
+		self.response().await?;
+
+		let result = match failure {
+			Some(e) => {
+				let _ = file.abort().await;
+				Err(e)
+			},
+			None => file.close().await
+		};
+		let result = match (result, self.command.preserve) {
+			(Ok(_), true) => self.preserve(path, mode, times).await,
+			(result, _) => result
+		};
+		match result {
+			Ok(_) => self.ok().await,
+			Err(e) => self.warn(format!("{}: {}", path.display(), describe(&e))).await
+		}
+	} // }}}
+
+	async fn source(&mut self) -> Result<(), Error> /* {{{ */ {
+		if(!self.permits("read")) {
+			return Err(self.fatal("Downloads are not permitted".to_string()).await);
+		}
+		// The client starts by saying it's ready
+		if(!self.response().await?) {
+			return Ok(());
+		}
+		let command = self.command;
+		for path in &command.paths {
+			self.check_draining().await?;
+			let path = self.server.resolve_path(path);
+			match self.server.backend.metadata(&path).await {
+				Ok(metadata) if metadata.is_dir => match command.recursive {
+					true => self.send_tree(path, &metadata).await?,
+					false => self.warn(format!("{}: not a regular file", path.display())).await?
+				},
+				Ok(metadata) => self.send_file(&path, &metadata).await?,
+				Err(e) => self.warn(format!("{}: {}", path.display(), describe(&e))).await?
+			};
+		}
+		Ok(())
+	} // }}}
+
+	async fn send_file(&mut self, path: &Path, metadata: &Metadata) -> Result<(), Error> /* {{{ */ {
+		let name = match record_name(path) {
+			Some(v) if metadata.is_file => v,
+			_ => return self.warn(format!("{}: not a regular file", path.display())).await
+		};
+		if(!self.permits("open")) {
+			return self.warn(format!("{}: {}", path.display(), describe(&ProtocolError::PermissionDenied))).await;
+		}
+		let _lock = match self.server.locks.try_lock(self.server.lock_key(path).await, LockMode::Read) {
+			Some(v) => v,
+			None => return self.warn(format!("{}: File is locked by another client", path.display())).await
+		};
+		let file = match self.server.backend.open(path, OpenOptions{read: true, ..Default::default()}).await {
+			Ok(v) => v,
+			Err(e) => return self.warn(format!("{}: {}", path.display(), describe(&e))).await
+		};
+		let result = self.send_contents(&name, &file).await;
+		if let Err(e) = file.close().await {
+			debug!("Session {}:  failed to close {:?} after sending it:  {:?}", self.server.id, path, e);
+		}
+		result
+	} // }}}
+
+	async fn send_contents(&mut self, name: &str, file: &OpenFile) -> Result<(), Error> /* {{{ */ {
+		let metadata = &file.metadata;
+		if(self.command.preserve && !self.send_times(metadata).await?) {
+			return Ok(());
+		}
+		let size = metadata.size;
+		self.send(format!("C{:04o} {} {}\n", metadata.permissions & 0o7777, size, name).as_bytes()).await?;
+		if(!self.response().await?) {
+			return Ok(());
+		}
+
+		let mut offset = 0;
+		let mut failure = None;
+		while(offset < size) {
+			let count = (size - offset).min(CHUNK as u64) as u32;
+			let data = match failure {
+				Some(_) => Vec::new(),
+				None => match file.read_at(offset, count).await {
+					Ok(v) if !v.is_empty() => v,
+					Ok(_) => {
+						failure = Some("file shrank while it was being sent".to_string());
+						Vec::new()
+					},
+					Err(e) => {
+						failure = Some(describe(&e));
+						Vec::new()
+					}
+				}
+			};
+			// The client expects exactly as many bytes as we announced, so make up any the file couldn't supply
+			let data = match data.is_empty() {
+				true => vec![0u8; count as usize],
+				false => data
+			};
+			self.writer.write_all(&data).await?;
+			offset += data.len() as u64;
+		}
+		match failure {
+			Some(message) => self.warn(format!("{}: {}", name, message)).await?,
+			None => self.ok().await?
+		};
+		self.response().await?;
+		Ok(())
+	} // }}}
+
+	async fn send_tree(&mut self, root: PathBuf, metadata: &Metadata) -> Result<(), Error> /* {{{ */ {
+		// Directories we've sent a D record for, each with the entries in it that are still to be sent
+		let mut stack: Vec<(PathBuf, VecDeque<Metadata>)> = Vec::new();
+		self.enter_dir(root, metadata, &mut stack).await?;
+		while(!stack.is_empty()) {
+			self.check_draining().await?;
+			let next = stack.last_mut().and_then(|(dir, entries)| entries.pop_front().map(|e| (dir.join(&e.path), e)));
+			match next {
+				Some((path, entry)) if entry.is_dir => self.enter_dir(path, &entry, &mut stack).await?,
+				Some((path, entry)) => self.send_file(&path, &entry).await?,
+				None => {
+					stack.pop();
+					self.send(b"E\n").await?;
+					self.response().await?;
+				}
+			};
+		}
+		Ok(())
+	} // }}}
+
+	async fn enter_dir(&mut self, path: PathBuf, metadata: &Metadata, stack: &mut Vec<(PathBuf, VecDeque<Metadata>)>) -> Result<(), Error> /* {{{ */ {
+		let name = match record_name(&path) {
+			Some(v) => v,
+			None => return self.warn(format!("{}: cannot be sent", path.display())).await
+		};
+		if(!self.permits("opendir") || !self.permits("readdir")) {
+			return self.warn(format!("{}: {}", path.display(), describe(&ProtocolError::PermissionDenied))).await;
+		}
+		let entries = match self.server.backend.list(&path).await {
+			Ok(v) => v,
+			Err(e) => return self.warn(format!("{}: {}", path.display(), describe(&e))).await
+		};
+		if(self.command.preserve && !self.send_times(metadata).await?) {
+			return Ok(());
+		}
+		self.send(format!("D{:04o} 0 {}\n", metadata.permissions & 0o7777, name).as_bytes()).await?;
+		if(self.response().await?) {
+			stack.push((path, entries));
+		}
+		Ok(())
+	} // }}}
+}
+
+impl Server {
+	/// Runs one SCP transfer over `io`, as requested by an exec of `command`, returning once it's complete.  Each
+	///    transfer is a session of its own, subject to the same backend choice, SessionPolicy (timeouts included),
+	///    request filter, shutdown and hooks as SFTP sessions, with paths resolved against the user's home directory in
+	///    the same way.  Each step of a transfer is filtered as the SFTP request it stands in for ("open", "write",
+	///    "mkdir", "setstat" for -p, and so on).
+	pub async fn serve_scp<S: AsyncRead + AsyncWrite + Unpin>(&self, io: S, ctx: SessionContext, command: &Command) -> Result<(), Error> /* {{{ */ {
+		let (session, entry) = self.start_session(&ctx, "SCP")?;
+		let (reader, writer) = tokio::io::split(io);
+		let mut transfer = Transfer{
+			server: &session,
+			command: command,
+			reader: BufReader::new(reader),
+			writer: writer,
+			errors: 0,
+			started: Instant::now(),
+			last_activity: Instant::now()
+		};
+		let result = tokio::select! {
+			result = transfer.run() => result,
+			_ = session.closing.wait() => {
+				warn!("Session {}:  server is shutting down; abandoning SCP transfer", self.id);
+				Err(Error::msg("Session cut off by server shutdown"))
+			}
+		};
+		if let Some(limit) = result.as_ref().err().and_then(|e| e.downcast_ref::<SessionLimit>()) {
+			info!("Session {}:  ending SCP session for user {:?}:  {}", self.id, ctx.user, limit);
+			transfer.fatal(limit.to_string()).await;
+			self.limit_reached(&ctx, *limit);
+		}
+		let result = match (result, transfer.errors) {
+			(Ok(_), 0) => Ok(()),
+			(Ok(_), n) => Err(Error::msg(format!("{} file(s) could not be transferred", n))),
+			(Err(e), _) => Err(e)
+		};
+		self.hooks.session_ended(&entry.info, &result);
+		result
+	} // }}}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Command;
+	use super::Direction;
+	use super::parse_record;
+	use super::parse_times;
+	use super::split_words;
+	use super::valid_name;
+
+	fn words(v: &[&str]) -> Vec<String> /* {{{ */ {
+		v.iter().map(|w| w.to_string()).collect()
+	} // }}}
+
+	#[test]
+	fn parse_sink() {
+		let command = Command::parse("scp -t /incoming").unwrap();
+		assert_eq!(command.direction, Direction::Sink);
+		assert!(!command.recursive && !command.preserve && !command.target_dir);
+		assert_eq!(command.paths, words(&["/incoming"]));
+	}
+
+	#[test]
+	fn parse_source() {
+		let command = Command::parse("scp -r -p -f a 'b c'").unwrap();
+		assert_eq!(command.direction, Direction::Source);
+		assert!(command.recursive && command.preserve);
+		assert_eq!(command.paths, words(&["a", "b c"]));
+	}
+
+	#[test]
+	fn parse_combined_flags() {
+		let command = Command::parse("/usr/bin/scp -vrdt -- -dashed").unwrap();
+		assert_eq!(command.direction, Direction::Sink);
+		assert!(command.recursive && command.target_dir);
+		assert_eq!(command.paths, words(&["-dashed"]));
+	}
+
+	#[test]
+	fn parse_refusals() {
+		for command in &[
+			"ls -t a",
+			"scpx -t a",
+			"scp a",
+			"scp -t",
+			"scp -t a b",
+			"scp -t -f a",
+			"scp -x -t a",
+			"scp -t 'unterminated"
+		] {
+			assert!(Command::parse(command).is_none(), "{:?} was accepted", command);
+		}
+	}
+
+	#[test]
+	fn split_plain() {
+		assert_eq!(split_words("  a \tb\n").unwrap(), words(&["a", "b"]));
+		assert_eq!(split_words("a  b\\tc").unwrap(), words(&["a", "btc"]));
+		assert_eq!(split_words("").unwrap(), words(&[]));
+	}
+
+	#[test]
+	fn split_quotes() {
+		assert_eq!(split_words("'a b' \"c d\"").unwrap(), words(&["a b", "c d"]));
+		assert_eq!(split_words("'' x").unwrap(), words(&["", "x"]));
+		assert_eq!(split_words("a'b'\"c\"").unwrap(), words(&["abc"]));
+		// Nothing is special inside single quotes
+		assert_eq!(split_words("'a\\b \"c'").unwrap(), words(&["a\\b \"c"]));
+	}
+
+	#[test]
+	fn split_escapes() {
+		assert_eq!(split_words("a\\ b").unwrap(), words(&["a b"]));
+		assert_eq!(split_words("\\'").unwrap(), words(&["'"]));
+		// In double quotes, only characters that would otherwise be special lose their backslash
+		assert_eq!(split_words("\"\\\" \\\\ \\$ \\x\"").unwrap(), words(&["\" \\ $ \\x"]));
+	}
+
+	#[test]
+	fn split_unterminated() {
+		assert!(split_words("'a").is_none());
+		assert!(split_words("\"a").is_none());
+		assert!(split_words("\"a\\").is_none());
+		assert!(split_words("a\\").is_none());
+	}
+
+	#[test]
+	fn record() {
+		assert_eq!(parse_record("0644 12 name"), Some((0o644, 12, "name".to_string())));
+		assert_eq!(parse_record("0755 0 name with spaces"), Some((0o755, 0, "name with spaces".to_string())));
+		assert_eq!(parse_record("0644 18446744073709551615 big"), Some((0o644, u64::MAX, "big".to_string())));
+	}
+
+	#[test]
+	fn record_malformed() {
+		for record in &[
+			"",
+			"0644",
+			"0644 12",
+			"0948 12 bad-mode",
+			"rwx 12 bad-mode",
+			"0644 -1 negative",
+			"0644 18446744073709551616 too-big",
+			"0644 1x2 garbage"
+		] {
+			assert!(parse_record(record).is_none(), "{:?} was accepted", record);
+		}
+	}
+
+	#[test]
+	fn record_names() {
+		assert!(valid_name("file.txt"));
+		assert!(valid_name("..hidden"));
+		for name in &["", ".", "..", "a/b", "/abs", "../up"] {
+			assert!(!valid_name(name), "{:?} was accepted", name);
+		}
+	}
+
+	#[test]
+	fn times() {
+		// Sent as mtime then atime; returned in the order set_metadata() takes them
+		assert_eq!(parse_times("1000 0 2000 0"), Some((2000, 1000)));
+		for record in &["", "1000 0 2000", "1000 0 2000 0 0", "a 0 2000 0", "-1 0 2000 0", "1000  0 2000 0"] {
+			assert!(parse_times(record).is_none(), "{:?} was accepted", record);
+		}
+	}
+}
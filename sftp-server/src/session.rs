@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::future;
+use futures::stream::FuturesUnordered;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufWriter;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use anyhow::Error;
+
+use uuid::Uuid;
+
+use sftp_protocol::Packet;
+use sftp_protocol::Payload;
+use sftp_protocol::stream::packet;
+
+#[cfg(feature = "standalone")]
+use thrussh::Disconnect;
+
+use super::PartialPacket;
+use super::Server;
+use super::config::SessionPolicy;
+use super::record::Recorder;
+
+/// Describes the client on the other end of a session, as established by whatever transport is carrying it
+#[derive(Clone, Debug, Default)]
+pub struct SessionContext {
+	pub id: usize,
+	pub user: Option<String>,
+	pub peer: Option<SocketAddr>,
+	// Overrides the home directory the server configuration would otherwise give this user
+	pub home: Option<String>
+}
+
+/// Callbacks for embedders to observe the server; every method defaults to doing nothing
+pub trait Hooks : Send + Sync {
+	fn session_started(&self, _session: &SessionInfo) {}
+	fn session_ended(&self, _session: &SessionInfo, _result: &Result<(), Error>) {}
+	// A session was refused or ended because of the server's SessionPolicy
+	fn session_limited(&self, _ctx: &SessionContext, _limit: &SessionLimit) {}
+}
+
+impl Hooks for () {}
+
+/// Why a session was refused or ended early under the server's `SessionPolicy`
+#[derive(thiserror::Error, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionLimit {
+	#[error("session idle for too long")]
+	IdleTimeout,
+	#[error("maximum session lifetime reached")]
+	MaxLifetime,
+	#[error("too many sessions for this user")]
+	TooManyForUser,
+	#[error("too many sessions from this address")]
+	TooManyForAddress
+}
+
+impl SessionLimit {
+	#[cfg(feature = "standalone")]
+	pub fn disconnect_reason(&self) -> Disconnect /* {{{ */ {
+		match self {
+			Self::IdleTimeout | Self::MaxLifetime => Disconnect::ByApplication,
+			Self::TooManyForUser | Self::TooManyForAddress => Disconnect::TooManyConnections
+		}
+	} // }}}
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+	// Unique among all sessions the server has run, unlike SessionContext::id, which is shared by every channel on a connection
+	pub key: usize,
+	pub ctx: SessionContext,
+	pub started: SystemTime
+}
+
+/// The SFTP sessions a server is currently running, across all of its connections
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+	next_key: AtomicUsize,
+	sessions: Mutex<HashMap<usize, SessionInfo>>
+}
+
+impl SessionRegistry {
+	// Checked and inserted under one lock, so that concurrent logins can't both squeeze under a cap
+	fn register(self: &Arc<Self>, ctx: SessionContext, policy: &SessionPolicy) -> Result<SessionEntry, SessionLimit> /* {{{ */ {
+		let mut sessions = self.sessions.lock().unwrap();
+		if let (Some(max), Some(user)) = (policy.max_per_user, ctx.user.as_ref()) {
+			if(sessions.values().filter(|s| s.ctx.user.as_ref() == Some(user)).count() >= max) {
+				return Err(SessionLimit::TooManyForUser);
+			}
+		}
+		if let (Some(max), Some(peer)) = (policy.max_per_address, ctx.peer) {
+			if(sessions.values().filter(|s| s.ctx.peer.map(|p| p.ip()) == Some(peer.ip())).count() >= max) {
+				return Err(SessionLimit::TooManyForAddress);
+			}
+		}
+		let info = SessionInfo{
+			key: self.next_key.fetch_add(1, Ordering::SeqCst),
+			ctx: ctx,
+			started: SystemTime::now()
+		};
+		sessions.insert(info.key, info.clone());
+		Ok(SessionEntry{
+			registry: self.clone(),
+			info: info
+		})
+	} // }}}
+
+	pub fn list(&self) -> Vec<SessionInfo> /* {{{ */ {
+		let mut sessions: Vec<_> = self.sessions.lock().unwrap().values().cloned().collect();
+		sessions.sort_by_key(|s| s.key);
+		sessions
+	} // }}}
+}
+
+// Keeps a session listed in its registry for as long as it's alive
+pub(crate) struct SessionEntry {
+	registry: Arc<SessionRegistry>,
+	pub(crate) info: SessionInfo
+}
+
+impl Drop for SessionEntry {
+	fn drop(&mut self) {
+		self.registry.sessions.lock().unwrap().remove(&self.info.key);
+	}
+}
+
+impl Server {
+	pub fn sessions(&self) -> Vec<SessionInfo> /* {{{ */ {
+		self.sessions.list()
+	} // }}}
+
+	/// Runs one complete SFTP session over `io`, returning once the client closes its end of the stream.  Requests
+	///    are processed concurrently (bounded by `Limits::max_in_flight`), so responses may be written out of order;
+	///    requests on the same handle are still processed one at a time, in the order they arrived.
+	pub async fn serve_session<S: AsyncRead + AsyncWrite + Unpin>(&self, io: S, ctx: SessionContext) -> Result<(), Error> /* {{{ */ {
+		let (mut session, entry) = self.start_session(&ctx, "SFTP")?;
+		if let Some(recording) = &self.config.recording {
+			match Recorder::create(recording, &entry.info) {
+				Ok(v) => session.recorder = Some(Arc::new(v)),
+				Err(e) => warn!("Session {}:  failed to start recording:  {:?}", self.id, e)
+			};
+		}
+		let result = session.pump(io).await;
+		// Handles the client never closed are aborted; by default that still flushes their buffered writes to the backend
+		session.close_all().await;
+		if let Some(limit) = result.as_ref().err().and_then(|e| e.downcast_ref::<SessionLimit>()) {
+			info!("Session {}:  ending SFTP session for user {:?}:  {}", self.id, ctx.user, limit);
+			self.limit_reached(&ctx, *limit);
+		}
+		if let Some(recorder) = &session.recorder {
+			recorder.finish(&result);
+		}
+		self.hooks.session_ended(&entry.info, &result);
+		result
+	} // }}}
+
+	// Sets up the per-session clone of this server that serves `ctx`, with its backend, and registers it under the
+	//    server's SessionPolicy; the session is listed until the returned entry is dropped
+	pub(crate) fn start_session(&self, ctx: &SessionContext, kind: &str) -> Result<(Self, SessionEntry), Error> /* {{{ */ {
+		let mut session = self.new_session(ctx.clone());
+		session.backend = self.backends.backend(ctx)?;
+		let entry = match self.sessions.register(ctx.clone(), &self.config.sessions) {
+			Ok(v) => v,
+			Err(limit) => {
+				info!("Session {}:  refusing {} session for user {:?} from {:?}:  {}", self.id, kind, ctx.user, ctx.peer, limit);
+				self.limit_reached(ctx, limit);
+				return Err(limit.into());
+			}
+		};
+		self.hooks.session_started(&entry.info);
+		Ok((session, entry))
+	} // }}}
+
+	pub(crate) fn limit_reached(&self, ctx: &SessionContext, limit: SessionLimit) /* {{{ */ {
+		self.hooks.session_limited(ctx, &limit);
+		// Picked up by the connection's handler once the client has acknowledged the channel closing
+		#[cfg(feature = "standalone")]
+		{
+			*self.hangup.lock().unwrap() = Some(limit);
+		}
+	} // }}}
+
+	// When the session will next expire if nothing happens in the meantime, and which limit that would be
+	pub(crate) fn deadline(&self, started: Instant, last_request: Instant, idle: bool) -> Option<(Instant, SessionLimit)> /* {{{ */ {
+		let policy = &self.config.sessions;
+		let lifetime = policy.max_lifetime.map(|d| (started + d, SessionLimit::MaxLifetime));
+		// A session waiting on its own outstanding requests isn't idle, however long the backend takes
+		let idle = policy.idle_timeout.filter(|_| idle).map(|d| (last_request + d, SessionLimit::IdleTimeout));
+		match (lifetime, idle) {
+			(Some(a), Some(b)) => Some(if(a.0 <= b.0) { a } else { b }),
+			(a, b) => a.or(b)
+		}
+	} // }}}
+
+	async fn pump<S: AsyncRead + AsyncWrite + Unpin>(&self, io: S) -> Result<(), Error> /* {{{ */ {
+		let max_len = self.config.limits.max_packet_len();
+		let max_in_flight = self.config.limits.max_in_flight.max(1);
+		let (mut reader, writer) = tokio::io::split(io);
+		let mut writer = BufWriter::new(writer);
+		let mut partial_packet = PartialPacket::new();
+		let mut buf = vec![0u8; 64 * 1024];
+		let mut pending = FuturesUnordered::new();
+		// For each handle with requests outstanding, when the latest of them is done
+		let mut handle_queues: HashMap<Uuid, oneshot::Receiver<()>> = HashMap::new();
+		let mut eof = false;
+		let started = Instant::now();
+		let mut last_request = started;
+		let mut expired = None;
+		loop {
+			// Clients pipeline requests, so a single read may contain any number of packets; take as many as there's
+			//    room for, and leave the rest buffered until some of those are answered
+			let mut rejected_any = false;
+			while(pending.len() < max_in_flight) {
+				match partial_packet.next_packet(max_len) {
+					Ok(Some(packet)) => {
+						let (after, done) = queue_on_handle(&mut handle_queues, &packet.payload);
+						pending.push(self.process_in_order(packet, after, done));
+					},
+					Ok(None) => break,
+					Err(rejected) => {
+						writer.write_all(&self.reject_packet(rejected)?).await?;
+						rejected_any = true;
+					}
+				};
+			}
+			if(rejected_any) {
+				writer.flush().await?;
+			}
+			if(eof && pending.is_empty()) {
+				break;
+			}
+			let deadline = self.deadline(started, last_request, pending.is_empty());
+			tokio::select! {
+				// Not reading while at the limit is what pushes back on the client:  over SSH, the channel's pipe fills up and
+				//    the connection stops taking data from the client until there's room again (see Handler::data())
+				count = reader.read(&mut buf), if !eof && pending.len() < max_in_flight => {
+					let count = count?;
+					if(count == 0) {
+						debug!("Session {}:  client closed the stream", self.id);
+						eof = true;
+						continue;
+					}
+					last_request = Instant::now();
+					partial_packet.push(&buf[..count]);
+				},
+				Some(response) = pending.next(), if !pending.is_empty() => {
+					if let Some(response) = response? {
+						writer.write_all(&response).await?;
+					}
+					// Batch up any other responses that are already available before flushing
+					while let Some(Some(response)) = pending.next().now_or_never() {
+						if let Some(response) = response? {
+							writer.write_all(&response).await?;
+						}
+					}
+					writer.flush().await?;
+				},
+				limit = expire_at(deadline), if !eof => {
+					// Like a drain:  stop reading, but answer whatever the client has already sent
+					expired = Some(limit);
+					eof = true;
+				},
+				_ = self.draining.wait(), if !eof => {
+					info!("Session {}:  server is shutting down; finishing {} outstanding request(s)", self.id, pending.len());
+					eof = true;
+				},
+				_ = self.closing.wait() => {
+					warn!("Session {}:  server is shutting down; abandoning {} outstanding request(s)", self.id, pending.len());
+					return Err(Error::msg("Session cut off by server shutdown"));
+				}
+			};
+		}
+		writer.flush().await?;
+		match expired {
+			Some(limit) => Err(limit.into()),
+			None => Ok(())
+		}
+	} // }}}
+
+	// Processes a request once the one before it on the same handle (`after`) is done, then signals the next one by
+	//    dropping `done`
+	async fn process_in_order(&self, packet: Packet, after: Option<oneshot::Receiver<()>>, done: Option<oneshot::Sender<()>>) -> Result<Option<Vec<u8>>, Error> /* {{{ */ {
+		if let Some(after) = after {
+			// Resolves (with an error) once the earlier request drops its sender
+			let _ = after.await;
+		}
+		let result = self.process_packet(packet).await;
+		drop(done);
+		result
+	} // }}}
+}
+
+// Queues a request behind whatever is outstanding on the handle it uses, so that e.g. a CLOSE can't overtake the WRITEs
+//    before it; returns what to wait for, and what to drop once done
+fn queue_on_handle(queues: &mut HashMap<Uuid, oneshot::Receiver<()>>, payload: &Payload) -> (Option<oneshot::Receiver<()>>, Option<oneshot::Sender<()>>) /* {{{ */ {
+	let handle = match request_handle(payload) {
+		Some(v) => v,
+		None => return (None, None)
+	};
+	let (done, next) = oneshot::channel();
+	let after = match payload {
+		// Nothing valid can follow a CLOSE on its handle, so there's no need to keep its queue
+		Payload::Close(_) => queues.remove(&handle),
+		_ => queues.insert(handle, next)
+	};
+	(after, Some(done))
+} // }}}
+
+// The handle a request operates on, if any
+fn request_handle(payload: &Payload) -> Option<Uuid> /* {{{ */ {
+	match payload {
+		Payload::Close(r) => Some(r.handle),
+		Payload::Read(r) => Some(r.handle),
+		Payload::Write(r) => Some(r.handle),
+		Payload::Fstat(r) => Some(r.handle),
+		Payload::FSetStat(r) => Some(r.handle),
+		Payload::ReadDir(r) => Some(r.handle),
+		Payload::Extended(r) => match r.request.as_str() {
+			"fsync@openssh.com" => packet::extended::Fsync::parse(&r.data).ok().map(|(_, v)| v.handle),
+			"fstatvfs@openssh.com" => packet::extended::Fstatvfs::parse(&r.data).ok().map(|(_, v)| v.handle),
+			_ => None
+		},
+		_ => None
+	}
+} // }}}
+
+pub(crate) async fn expire_at(deadline: Option<(Instant, SessionLimit)>) -> SessionLimit /* {{{ */ {
+	match deadline {
+		Some((at, limit)) => {
+			tokio::time::delay_until(at).await;
+			limit
+		},
+		None => future::pending().await
+	}
+} // }}}
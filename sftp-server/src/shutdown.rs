@@ -0,0 +1,72 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use futures::FutureExt;
+use futures::channel::oneshot;
+use futures::future::Shared;
+
+use super::SessionInfo;
+
+/// One-shot signal shared by everything that needs to react to a shutdown; once triggered, it stays triggered
+#[derive(Clone)]
+pub(crate) struct Signal {
+	triggered: Arc<AtomicBool>,
+	sender: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+	receiver: Shared<oneshot::Receiver<()>>
+}
+
+impl Signal {
+	pub fn new() -> Self /* {{{ */ {
+		let (sender, receiver) = oneshot::channel();
+		Self{
+			triggered: Arc::new(AtomicBool::new(false)),
+			sender: Arc::new(Mutex::new(Some(sender))),
+			receiver: receiver.shared()
+		}
+	} // }}}
+
+	pub fn trigger(&self) /* {{{ */ {
+		self.triggered.store(true, Ordering::SeqCst);
+		if let Some(sender) = self.sender.lock().unwrap().take() {
+			let _ = sender.send(());
+		}
+	} // }}}
+
+	pub fn is_triggered(&self) -> bool /* {{{ */ {
+		self.triggered.load(Ordering::SeqCst)
+	} // }}}
+
+	pub async fn wait(&self) /* {{{ */ {
+		let _ = self.receiver.clone().await;
+	} // }}}
+}
+
+/// What was still running when a graceful shutdown's grace period ran out
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownSummary {
+	// SFTP sessions that hadn't finished their outstanding requests
+	pub cut_off: Vec<SessionInfo>,
+	// SSH connections that were dropped without a disconnect, including those that never started an SFTP session
+	pub dropped_connections: usize,
+	// Cut-off sessions that still hadn't finished aborting their open files when shutdown stopped waiting for them
+	pub abandoned: Vec<SessionInfo>
+}
+
+impl fmt::Display for ShutdownSummary {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if(self.cut_off.is_empty() && self.dropped_connections == 0) {
+			return write!(f, "all sessions finished cleanly");
+		}
+		write!(f, "{} session(s) cut off, {} connection(s) dropped", self.cut_off.len(), self.dropped_connections)?;
+		if(!self.abandoned.is_empty()) {
+			write!(f, ", {} session(s) abandoned while aborting their files", self.abandoned.len())?;
+		}
+		for session in &self.cut_off {
+			write!(f, "\n\tsession {} (connection {}):  user {:?} from {:?}", session.key, session.ctx.id, session.ctx.user, session.ctx.peer)?;
+		}
+		Ok(())
+	}
+}
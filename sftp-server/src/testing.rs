@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+
+use anyhow::Error;
+
+use bincode::Options;
+
+use uuid::Uuid;
+
+use sftp_protocol::common::FileAttributes;
+use sftp_protocol::stream::packet::close::Close;
+use sftp_protocol::stream::packet::init::Init;
+use sftp_protocol::stream::packet::mkdir::MkDir;
+use sftp_protocol::stream::packet::open::Open;
+use sftp_protocol::stream::packet::open::OpenFlags;
+use sftp_protocol::stream::packet::opendir::OpenDir;
+use sftp_protocol::stream::packet::read::Read;
+use sftp_protocol::stream::packet::readdir::ReadDir;
+use sftp_protocol::stream::packet::remove::Remove;
+use sftp_protocol::stream::packet::rename::Rename;
+use sftp_protocol::stream::packet::rmdir::RmDir;
+use sftp_protocol::stream::packet::stat::Stat;
+use sftp_protocol::stream::packet::status::Status;
+use sftp_protocol::stream::packet::status::StatusType;
+use sftp_protocol::stream::packet::version::Version;
+use sftp_protocol::stream::packet::write::Write;
+use sftp_protocol::stream::packet::PayloadTrait;
+use sftp_protocol::Packet;
+use sftp_protocol::Payload;
+
+use super::PartialPacket;
+use super::Server;
+use super::SessionContext;
+use super::transport;
+use super::transport::Pipe;
+
+// Size of the READs and WRITEs that get() and put() split transfers into
+const CHUNK: u32 = 32 * 1024;
+
+/// A scripted SFTP client talking to a `Server` session over an in-memory pipe, for end-to-end tests of backends and
+///    protocol handling without any networking:
+///
+/// ```ignore
+/// let mut client = TestClient::connect(&Server::new(MyBackend::new(), 0)).await?;
+/// client.put("/hello.txt", b"hello").await?;
+/// assert_eq!(client.get("/hello.txt").await?, b"hello");
+/// client.finish().await?;
+/// ```
+///
+/// The helpers return an error for any status other than OK; use `request()` or `expect_status()` to look at
+///    failures themselves.
+pub struct TestClient {
+	io: Pipe,
+	partial_packet: PartialPacket,
+	next_id: u32,
+	// Responses that arrived while waiting for a different one
+	stashed: HashMap<u32, Packet>,
+	session: JoinHandle<Result<(), Error>>
+}
+
+fn status_error(status: &Status) -> Error /* {{{ */ {
+	Error::msg(format!("{:?}:  {}", status.status, status.message))
+} // }}}
+
+fn unexpected(response: &Packet) -> Error /* {{{ */ {
+	Error::msg(format!("Unexpected response {:?}", response.payload))
+} // }}}
+
+fn path_str(path: impl AsRef<Path>) -> String /* {{{ */ {
+	path.as_ref().to_string_lossy().to_string()
+} // }}}
+
+impl TestClient {
+	/// Starts a session as an anonymous user and completes the INIT/VERSION exchange
+	pub async fn connect(server: &Server) -> Result<Self, Error> /* {{{ */ {
+		Self::connect_as(server, SessionContext::default()).await
+	} // }}}
+
+	pub async fn connect_as(server: &Server, ctx: SessionContext) -> Result<Self, Error> /* {{{ */ {
+		let mut this = Self::connect_raw(server, ctx);
+		this.init().await?;
+		Ok(this)
+	} // }}}
+
+	/// Starts a session without sending INIT, for tests of the handshake itself
+	pub fn connect_raw(server: &Server, ctx: SessionContext) -> Self /* {{{ */ {
+		let (local, remote) = transport::pipe();
+		let server = server.clone();
+		Self{
+			io: remote,
+			partial_packet: PartialPacket::new(),
+			next_id: 1,
+			stashed: HashMap::new(),
+			session: tokio::spawn(async move { server.serve_session(local, ctx).await })
+		}
+	} // }}}
+
+	/// Closes the client's end of the session and returns however the server's side of it ended
+	pub async fn finish(self) -> Result<(), Error> /* {{{ */ {
+		drop(self.io);
+		self.session.await?
+	} // }}}
+
+	/// Bytes sent that the server hasn't read yet
+	pub fn backlog(&self) -> usize /* {{{ */ {
+		self.io.backlog().bytes()
+	} // }}}
+
+	// Request ID for the next request built by hand
+	pub fn next_id(&mut self) -> u32 /* {{{ */ {
+		let id = self.next_id;
+		self.next_id = self.next_id.wrapping_add(1);
+		id
+	} // }}}
+
+	pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<(), Error> /* {{{ */ {
+		self.io.write_all(bytes).await?;
+		Ok(())
+	} // }}}
+
+	pub async fn send(&mut self, packet: &Packet) -> Result<(), Error> /* {{{ */ {
+		let bytes = bincode::DefaultOptions::new().with_big_endian().with_fixint_encoding().serialize(packet)?;
+		self.send_raw(&bytes).await
+	} // }}}
+
+	/// Receives the next packet from the server, whatever it is
+	pub async fn recv(&mut self) -> Result<Packet, Error> /* {{{ */ {
+		let mut buf = vec![0u8; 64 * 1024];
+		loop {
+			if let Some(packet) = self.partial_packet.next_packet(u32::MAX)? {
+				return Ok(packet);
+			}
+			let count = self.io.read(&mut buf).await?;
+			if(count == 0) {
+				return Err(Error::msg("Server ended the session"));
+			}
+			self.partial_packet.push(&buf[..count]);
+		}
+	} // }}}
+
+	/// Receives the response to request `id`, holding on to any others that arrive first
+	pub async fn expect(&mut self, id: u32) -> Result<Packet, Error> /* {{{ */ {
+		if let Some(packet) = self.stashed.remove(&id) {
+			return Ok(packet);
+		}
+		loop {
+			let packet = self.recv().await?;
+			match packet.payload.request_id() {
+				Some(v) if v == id => return Ok(packet),
+				Some(v) => {
+					self.stashed.insert(v, packet);
+				},
+				None => return Err(unexpected(&packet))
+			};
+		}
+	} // }}}
+
+	/// Sends a request and waits for its response
+	pub async fn request(&mut self, request: impl PayloadTrait) -> Result<Packet, Error> /* {{{ */ {
+		let packet = request.into_packet();
+		let id = packet.payload.request_id().ok_or_else(|| Error::msg("Request has no ID"))?;
+		self.send(&packet).await?;
+		self.expect(id).await
+	} // }}}
+
+	/// Sends a request and fails unless it's answered with a STATUS of the given type
+	pub async fn expect_status(&mut self, request: impl PayloadTrait, expected: StatusType) -> Result<Status, Error> /* {{{ */ {
+		let response = self.request(request).await?;
+		match response.payload {
+			Payload::Status(status) if status.status as u32 == expected as u32 => Ok(status),
+			Payload::Status(status) => Err(Error::msg(format!("Expected {:?}, got {:?}:  {}", expected, status.status, status.message))),
+			_ => Err(unexpected(&response))
+		}
+	} // }}}
+
+	async fn expect_ok(&mut self, request: impl PayloadTrait) -> Result<(), Error> /* {{{ */ {
+		self.expect_status(request, StatusType::OK).await?;
+		Ok(())
+	} // }}}
+
+	async fn expect_handle(&mut self, request: impl PayloadTrait) -> Result<Uuid, Error> /* {{{ */ {
+		let response = self.request(request).await?;
+		match response.payload {
+			Payload::Handle(h) => Ok(h.handle),
+			Payload::Status(ref status) => Err(status_error(status)),
+			_ => Err(unexpected(&response))
+		}
+	} // }}}
+
+	pub async fn init(&mut self) -> Result<Version, Error> /* {{{ */ {
+		let init = Init{
+			version: 3,
+			extension_data: Vec::new()
+		};
+		self.send(&init.into_packet()).await?;
+		let response = self.recv().await?;
+		match response.payload {
+			Payload::Version(v) => Ok(v),
+			_ => Err(unexpected(&response))
+		}
+	} // }}}
+
+	pub async fn open(&mut self, path: impl AsRef<Path>, flags: OpenFlags) -> Result<Uuid, Error> /* {{{ */ {
+		let id = self.next_id();
+		self.expect_handle(Open{
+			id: id,
+			path: path_str(path),
+			pflags: flags,
+			attrs: FileAttributes::new()
+		}).await
+	} // }}}
+
+	pub async fn close(&mut self, handle: Uuid) -> Result<(), Error> /* {{{ */ {
+		let id = self.next_id();
+		self.expect_ok(Close{
+			id: id,
+			handle: handle
+		}).await
+	} // }}}
+
+	// None at end of file
+	pub async fn read(&mut self, handle: Uuid, offset: u64, len: u32) -> Result<Option<Vec<u8>>, Error> /* {{{ */ {
+		let id = self.next_id();
+		let response = self.request(Read{
+			id: id,
+			handle: handle,
+			offset: offset,
+			len: len
+		}).await?;
+		match response.payload {
+			Payload::Data(d) => Ok(Some(d.data)),
+			Payload::Status(ref status) if status.status as u32 == StatusType::EOF as u32 => Ok(None),
+			Payload::Status(ref status) => Err(status_error(status)),
+			_ => Err(unexpected(&response))
+		}
+	} // }}}
+
+	pub async fn write(&mut self, handle: Uuid, offset: u64, data: &[u8]) -> Result<(), Error> /* {{{ */ {
+		let id = self.next_id();
+		self.expect_ok(Write{
+			id: id,
+			handle: handle,
+			offset: offset,
+			data: data.to_vec()
+		}).await
+	} // }}}
+
+	/// Uploads `data` to `path`, replacing anything already there
+	pub async fn put(&mut self, path: impl AsRef<Path>, data: &[u8]) -> Result<(), Error> /* {{{ */ {
+		let handle = self.open(path, OpenFlags::Write | OpenFlags::Create | OpenFlags::Truncate).await?;
+		for (i, chunk) in data.chunks(CHUNK as usize).enumerate() {
+			self.write(handle, i as u64 * CHUNK as u64, chunk).await?;
+		}
+		self.close(handle).await
+	} // }}}
+
+	pub async fn get(&mut self, path: impl AsRef<Path>) -> Result<Vec<u8>, Error> /* {{{ */ {
+		let handle = self.open(path, OpenFlags::Read).await?;
+		let mut data = Vec::new();
+		while let Some(chunk) = self.read(handle, data.len() as u64, CHUNK).await? {
+			data.extend_from_slice(&chunk);
+		}
+		self.close(handle).await?;
+		Ok(data)
+	} // }}}
+
+	/// Names of everything in the directory at `path`, in the order the server listed them
+	pub async fn ls(&mut self, path: impl AsRef<Path>) -> Result<Vec<String>, Error> /* {{{ */ {
+		let id = self.next_id();
+		let handle = self.expect_handle(OpenDir{
+			id: id,
+			path: path_str(path)
+		}).await?;
+		let mut names = Vec::new();
+		loop {
+			let id = self.next_id();
+			let response = self.request(ReadDir{
+				id: id,
+				handle: handle
+			}).await?;
+			match response.payload {
+				Payload::Name(name) => names.extend(name.files.into_iter().map(|f| f.filename)),
+				Payload::Status(ref status) if status.status as u32 == StatusType::EOF as u32 => break,
+				Payload::Status(ref status) => return Err(status_error(status)),
+				_ => return Err(unexpected(&response))
+			};
+		}
+		self.close(handle).await?;
+		Ok(names)
+	} // }}}
+
+	pub async fn stat(&mut self, path: impl AsRef<Path>) -> Result<FileAttributes, Error> /* {{{ */ {
+		let id = self.next_id();
+		let response = self.request(Stat{
+			id: id,
+			path: path_str(path)
+		}).await?;
+		match response.payload {
+			Payload::Attrs(a) => Ok(a.attrs),
+			Payload::Status(ref status) => Err(status_error(status)),
+			_ => Err(unexpected(&response))
+		}
+	} // }}}
+
+	pub async fn remove(&mut self, path: impl AsRef<Path>) -> Result<(), Error> /* {{{ */ {
+		let id = self.next_id();
+		self.expect_ok(Remove{
+			id: id,
+			path: path_str(path)
+		}).await
+	} // }}}
+
+	pub async fn rename(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> /* {{{ */ {
+		let id = self.next_id();
+		self.expect_ok(Rename{
+			id: id,
+			oldpath: path_str(from),
+			newpath: path_str(to)
+		}).await
+	} // }}}
+
+	pub async fn mkdir(&mut self, path: impl AsRef<Path>) -> Result<(), Error> /* {{{ */ {
+		let id = self.next_id();
+		self.expect_ok(MkDir{
+			id: id,
+			path: path_str(path),
+			attrs: FileAttributes::new()
+		}).await
+	} // }}}
+
+	pub async fn rmdir(&mut self, path: impl AsRef<Path>) -> Result<(), Error> /* {{{ */ {
+		let id = self.next_id();
+		self.expect_ok(RmDir{
+			id: id,
+			path: path_str(path)
+		}).await
+	} // }}}
+}
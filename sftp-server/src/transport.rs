@@ -0,0 +1,275 @@
+use core::task::Context;
+use core::task::Poll;
+use core::task::Poll::Pending;
+use core::task::Poll::Ready;
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use futures::channel::mpsc::unbounded;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::poll_fn;
+use futures::stream::Stream;
+use futures::task::AtomicWaker;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::sync::Notify;
+
+/// Bytes each direction of a pipe holds before its writer has to wait for the reader to catch up
+pub const PIPE_CAPACITY: usize = 256 * 1024;
+
+// What's queued in one direction of a pipe, shared by the two ends; the messages themselves go through an unbounded
+//    channel, and it's the byte count here that bounds it
+#[derive(Debug)]
+struct Queue {
+	bytes: AtomicUsize,
+	// The writer, waiting for room
+	writer: AtomicWaker,
+	// Notified each time the writer finds the queue full
+	full: Notify
+}
+
+impl Queue {
+	fn new() -> Self /* {{{ */ {
+		Self{
+			bytes: AtomicUsize::new(0),
+			writer: AtomicWaker::new(),
+			full: Notify::new()
+		}
+	} // }}}
+
+	fn is_full(&self) -> bool /* {{{ */ {
+		self.bytes.load(Ordering::SeqCst) >= PIPE_CAPACITY
+	} // }}}
+
+	// Ready once there's room for another message; a single message may take the queue past its capacity, so at
+	//    most one more can be queued than fits
+	fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<()> /* {{{ */ {
+		if(!self.is_full()) {
+			return Ready(());
+		}
+		self.writer.register(ctx.waker());
+		// The reader may have taken something between the check above and registering
+		if(!self.is_full()) {
+			return Ready(());
+		}
+		self.full.notify();
+		Pending
+	} // }}}
+
+	fn taken(&self, len: usize) /* {{{ */ {
+		self.bytes.fetch_sub(len, Ordering::SeqCst);
+		self.writer.wake();
+	} // }}}
+}
+
+/// The writing half of one direction of a pipe
+#[derive(Debug)]
+pub struct PipeSender {
+	tx: UnboundedSender<Vec<u8>>,
+	queue: Arc<Queue>
+}
+
+impl PipeSender {
+	fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<()> /* {{{ */ {
+		self.queue.poll_ready(ctx)
+	} // }}}
+
+	/// Queues `data` whether or not there's room for it; an error if the other end has been dropped
+	pub fn send_now(&self, data: Vec<u8>) -> Result<(), Vec<u8>> /* {{{ */ {
+		let len = data.len();
+		self.tx.unbounded_send(data).map_err(|e| e.into_inner())?;
+		self.queue.bytes.fetch_add(len, Ordering::SeqCst);
+		Ok(())
+	} // }}}
+
+	/// Waits for room, then queues `data`
+	pub async fn send(&self, data: Vec<u8>) -> Result<(), Vec<u8>> /* {{{ */ {
+		poll_fn(|ctx| self.poll_ready(ctx)).await;
+		self.send_now(data)
+	} // }}}
+
+	/// Waits until there's room for another message or `unless` reports that it's full, whichever is first
+	pub async fn ready_unless(&self, unless: &Backlog) /* {{{ */ {
+		while(self.queue.is_full() && !unless.is_full()) {
+			tokio::select! {
+				_ = poll_fn(|ctx| self.poll_ready(ctx)) => (),
+				_ = unless.0.full.notified() => ()
+			};
+		}
+	} // }}}
+
+	pub fn backlog(&self) -> Backlog /* {{{ */ {
+		Backlog(self.queue.clone())
+	} // }}}
+}
+
+/// The reading half of one direction of a pipe
+#[derive(Debug)]
+pub struct PipeReceiver {
+	rx: UnboundedReceiver<Vec<u8>>,
+	queue: Arc<Queue>
+}
+
+impl PipeReceiver {
+	fn poll_recv(&mut self, ctx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> /* {{{ */ {
+		let result = Pin::new(&mut self.rx).poll_next(ctx);
+		if let Ready(Some(data)) = &result {
+			self.queue.taken(data.len());
+		}
+		result
+	} // }}}
+
+	/// The next message, or None once the writing half is gone and everything it sent has been received
+	pub async fn recv(&mut self) -> Option<Vec<u8>> /* {{{ */ {
+		poll_fn(|ctx| self.poll_recv(ctx)).await
+	} // }}}
+
+	pub fn backlog(&self) -> Backlog /* {{{ */ {
+		Backlog(self.queue.clone())
+	} // }}}
+}
+
+/// A view of how much is queued in one direction of a pipe
+#[derive(Clone, Debug)]
+pub struct Backlog(Arc<Queue>);
+
+impl Backlog {
+	/// Bytes written and not yet read
+	pub fn bytes(&self) -> usize /* {{{ */ {
+		self.0.bytes.load(Ordering::SeqCst)
+	} // }}}
+
+	/// True once the writer would have to wait
+	pub fn is_full(&self) -> bool /* {{{ */ {
+		self.0.is_full()
+	} // }}}
+}
+
+fn channel() -> (PipeSender, PipeReceiver) /* {{{ */ {
+	let (tx, rx) = unbounded();
+	let queue = Arc::new(Queue::new());
+	(PipeSender{tx: tx, queue: queue.clone()}, PipeReceiver{rx: rx, queue: queue})
+} // }}}
+
+/// One end of an in-memory byte pipe; anything written to one end can be read from the other.  Used to adapt
+///    message-oriented transports (such as SSH channels) to the byte stream expected by `Server::serve_session()`.
+///    Each direction holds at most about `PIPE_CAPACITY` bytes; past that, writes wait for the other end to read.
+#[derive(Debug)]
+pub struct Pipe {
+	tx: PipeSender,
+	rx: PipeReceiver,
+	buffer: Vec<u8>,
+	pos: usize
+}
+
+pub fn pipe() -> (Pipe, Pipe) /* {{{ */ {
+	let (left_tx, right_rx) = channel();
+	let (right_tx, left_rx) = channel();
+	(Pipe::new(left_tx, left_rx), Pipe::new(right_tx, right_rx))
+} // }}}
+
+impl Pipe {
+	fn new(tx: PipeSender, rx: PipeReceiver) -> Self /* {{{ */ {
+		Self{
+			tx: tx,
+			rx: rx,
+			buffer: Vec::new(),
+			pos: 0
+		}
+	} // }}}
+
+	/// Splits this end into its raw message channels, for transports that push and pull whole buffers
+	pub fn into_parts(self) -> (PipeSender, PipeReceiver) /* {{{ */ {
+		(self.tx, self.rx)
+	} // }}}
+
+	/// What this end has written that the other hasn't read yet
+	pub fn backlog(&self) -> Backlog /* {{{ */ {
+		self.tx.backlog()
+	} // }}}
+}
+
+impl AsyncRead for Pipe {
+	fn poll_read(self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		while(this.pos >= this.buffer.len()) {
+			match this.rx.poll_recv(ctx) {
+				Ready(Some(v)) => {
+					this.buffer = v;
+					this.pos = 0;
+				},
+				Ready(None) => return Ready(Ok(0)),
+				Pending => return Pending
+			};
+		}
+		let count = buf.len().min(this.buffer.len() - this.pos);
+		buf[..count].copy_from_slice(&this.buffer[this.pos..this.pos + count]);
+		this.pos += count;
+		Ready(Ok(count))
+	}
+}
+
+impl AsyncWrite for Pipe {
+	fn poll_write(self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		if let Pending = self.tx.poll_ready(ctx) {
+			return Pending;
+		}
+		// Large writes are split, so that no one message takes the queue too far past its capacity
+		let count = buf.len().min(PIPE_CAPACITY);
+		match self.tx.send_now(buf[..count].to_vec()) {
+			Ok(_) => Ready(Ok(count)),
+			Err(_) => Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "other end of pipe was dropped")))
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+		self.tx.tx.close_channel();
+		Ready(Ok(()))
+	}
+}
+
+/// Combines separate read and write halves (e.g. stdin and stdout) into a single stream
+#[derive(Debug)]
+pub struct Joined<R, W> {
+	reader: R,
+	writer: W
+}
+
+impl<R, W> Joined<R, W> {
+	pub fn new(reader: R, writer: W) -> Self /* {{{ */ {
+		Self{
+			reader: reader,
+			writer: writer
+		}
+	} // }}}
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Joined<R, W> {
+	fn poll_read(self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.get_mut().reader).poll_read(ctx, buf)
+	}
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Joined<R, W> {
+	fn poll_write(self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.get_mut().writer).poll_write(ctx, buf)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().writer).poll_flush(ctx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().writer).poll_shutdown(ctx)
+	}
+}